@@ -1,8 +1,10 @@
+use std::str::FromStr;
 use vector::{Vector3, Vector2};
 use scene::{Mesh, Intersection, Scene};
 use camera::Ray;
 use config;
-use math::det;
+use config::RenderSettings;
+use math::{det, component};
 
 #[derive(Debug, Clone)]
 pub struct Aabb {
@@ -11,10 +13,60 @@ pub struct Aabb {
 }
 
 impl Aabb {
-    pub fn intersect_aabb(&self, other: &Aabb) -> bool {
-        self.min.x < other.max.x && self.max.x > other.min.x &&
-            self.min.y < other.max.y && self.max.y > other.min.y &&
-            self.min.z < other.max.z && self.max.z > other.min.z
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::new(config::INF, config::INF, config::INF),
+            max: Vector3::new(-config::INF, -config::INF, -config::INF),
+        }
+    }
+
+    pub fn center(&self) -> Vector3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    // レイが AABB に入る距離。始点が内側にある場合は 0
+    pub fn entry_distance(&self, ray: &Ray, dir_inv: &Vector3) -> Option<f64> {
+        let t1 = (self.min.x - ray.origin.x) * dir_inv.x;
+        let t2 = (self.max.x - ray.origin.x) * dir_inv.x;
+        let t3 = (self.min.y - ray.origin.y) * dir_inv.y;
+        let t4 = (self.max.y - ray.origin.y) * dir_inv.y;
+        let t5 = (self.min.z - ray.origin.z) * dir_inv.z;
+        let t6 = (self.max.z - ray.origin.z) * dir_inv.z;
+        let tmin = (t1.min(t2).max(t3.min(t4))).max(t5.min(t6));
+        let tmax = (t1.max(t2).min(t3.max(t4))).min(t5.max(t6));
+
+        if tmin <= tmax && tmax.is_sign_positive() {
+            Some(tmin.max(0.0))
+        } else {
+            None
+        }
+    }
+
+    // レイが AABB の内部を通る区間 [tmin, tmax]（始点より前は含めない）
+    pub fn ray_range(&self, ray: &Ray) -> Option<(f64, f64)> {
+        let dir_inv = Vector3::new(
+            ray.direction.x.recip(),
+            ray.direction.y.recip(),
+            ray.direction.z.recip(),
+        );
+        let tmin = self.entry_distance(ray, &dir_inv)?;
+
+        let t1 = (self.min.x - ray.origin.x) * dir_inv.x;
+        let t2 = (self.max.x - ray.origin.x) * dir_inv.x;
+        let t3 = (self.min.y - ray.origin.y) * dir_inv.y;
+        let t4 = (self.max.y - ray.origin.y) * dir_inv.y;
+        let t5 = (self.min.z - ray.origin.z) * dir_inv.z;
+        let t6 = (self.max.z - ray.origin.z) * dir_inv.z;
+        let tmax = (t1.max(t2).min(t3.max(t4))).min(t5.max(t6));
+        Some((tmin, tmax))
     }
 
     pub fn intersect_ray(&self, ray: &Ray) -> (bool, f64) {
@@ -64,203 +116,448 @@ impl Aabb {
     }
 }
 
-#[derive(Debug)]
-pub struct BvhNode {
-    pub aabb: Aabb,
+// BVH の構築方法
+#[derive(Clone, Debug)]
+pub enum BvhBuildMethod {
+    // 最も長い軸で重心をソートして中央で分割する
+    Median,
+    // Binned Surface Area Heuristic
+    Sah,
+}
+
+impl FromStr for BvhBuildMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BvhBuildMethod, String> {
+        match s {
+            "median" => Ok(BvhBuildMethod::Median),
+            "sah" => Ok(BvhBuildMethod::Sah),
+            _ => Err(format!("unknown BVH build method `{}`", s)),
+        }
+    }
+}
+
+// 分割の対象となるプリミティブ（メッシュのポリゴン、またはシーンの要素）
+struct BuildPrimitive {
+    index: usize,
+    aabb: Aabb,
+    centroid: Vector3,
+}
+
+// SAH のビン
+#[derive(Clone)]
+struct Bin {
+    aabb: Aabb,
+    count: usize,
+}
+
+// 構築時の木構造。構築後に Bvh の配列に平坦化する
+struct BuildNode {
+    aabb: Aabb,
 
     // size must be 0 or 2
     // empty means leaf node
-    pub children: Vec<Box<BvhNode>>,
+    children: Vec<BuildNode>,
 
     // has faces means leaf node
-    pub indexes: Vec<usize>,
+    indexes: Vec<usize>,
 }
 
-impl BvhNode {
-    fn empty() -> BvhNode {
-        BvhNode {
-            aabb: Aabb {
-                min: Vector3::new(config::INF, config::INF, config::INF),
-                max: Vector3::new(-config::INF, -config::INF, -config::INF),
-            },
+impl BuildNode {
+    fn empty() -> BuildNode {
+        BuildNode {
+            aabb: Aabb::empty(),
             children: vec![],
             indexes: vec![],
         }
     }
 
-    fn set_aabb_from_mesh(&mut self, mesh: &Mesh, face_indexes: &Vec<usize>) {
-        for face_index in face_indexes {
-            let face = &mesh.faces[*face_index];
-            let v0 = &mesh.vertexes[face.v0];
-            let v1 = &mesh.vertexes[face.v1];
-            let v2 = &mesh.vertexes[face.v2];
-            self.aabb.merge(&Aabb::from_triangle(v0, v1, v2));
+    fn leaf(aabb: Aabb, primitives: &[BuildPrimitive]) -> BuildNode {
+        BuildNode {
+            aabb,
+            children: vec![],
+            indexes: primitives.iter().map(|p| p.index).collect(),
         }
     }
 
-    fn set_aabb_from_scene(&mut self, scene: &Scene, indexes: &Vec<usize>) {
-        for index in indexes {
-            self.aabb.merge(&scene.elements[*index].aabb());
+    fn build(primitives: Vec<BuildPrimitive>, settings: &RenderSettings) -> BuildNode {
+        match settings.bvh_build_method {
+            BvhBuildMethod::Median => BuildNode::build_median(primitives),
+            BvhBuildMethod::Sah => BuildNode::build_sah(primitives, settings),
         }
     }
 
-    fn build_from_mesh_with_indexes(mesh: &Mesh, face_indexes: &mut Vec<usize>) -> BvhNode {
-        let mut node = BvhNode::empty();
-        node.set_aabb_from_mesh(mesh, face_indexes);
+    fn build_median(mut primitives: Vec<BuildPrimitive>) -> BuildNode {
+        let mut node = BuildNode::empty();
+        for p in &primitives {
+            node.aabb.merge(&p.aabb);
+        }
 
-        let mid = face_indexes.len() / 2;
+        let mid = primitives.len() / 2;
         if mid <= 2 {
             // set leaf node
-            node.indexes = face_indexes.clone();
-        } else {
-            // set intermediate node
-            let lx = node.aabb.max.x - node.aabb.min.x;
-            let ly = node.aabb.max.y - node.aabb.min.y;
-            let lz = node.aabb.max.z - node.aabb.min.z;
-
-            if lx > ly && lx > lz {
-                face_indexes.sort_by(|a, b| {
-                    let a_face = &mesh.faces[*a];
-                    let b_face = &mesh.faces[*b];
-                    let a_sum = mesh.vertexes[a_face.v0].x + mesh.vertexes[a_face.v1].x + mesh.vertexes[a_face.v2].x;
-                    let b_sum = mesh.vertexes[b_face.v0].x + mesh.vertexes[b_face.v1].x + mesh.vertexes[b_face.v2].x;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
-            } else if ly > lx && ly > lz {
-                face_indexes.sort_by(|a, b| {
-                    let a_face = &mesh.faces[*a];
-                    let b_face = &mesh.faces[*b];
-                    let a_sum = mesh.vertexes[a_face.v0].y + mesh.vertexes[a_face.v1].y + mesh.vertexes[a_face.v2].y;
-                    let b_sum = mesh.vertexes[b_face.v0].y + mesh.vertexes[b_face.v1].y + mesh.vertexes[b_face.v2].y;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
-            } else {
-                face_indexes.sort_by(|a, b| {
-                    let a_face = &mesh.faces[*a];
-                    let b_face = &mesh.faces[*b];
-                    let a_sum = mesh.vertexes[a_face.v0].z + mesh.vertexes[a_face.v1].z + mesh.vertexes[a_face.v2].z;
-                    let b_sum = mesh.vertexes[b_face.v0].z + mesh.vertexes[b_face.v1].z + mesh.vertexes[b_face.v2].z;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
-            }
-
-            let mut left_face_indexes = face_indexes.split_off(mid);
-            node.children.push(Box::new(BvhNode::build_from_mesh_with_indexes(mesh, face_indexes)));
-            node.children.push(Box::new(BvhNode::build_from_mesh_with_indexes(mesh, &mut left_face_indexes)));
+            return BuildNode::leaf(node.aabb, &primitives);
         }
 
+        // set intermediate node
+        let lx = node.aabb.max.x - node.aabb.min.x;
+        let ly = node.aabb.max.y - node.aabb.min.y;
+        let lz = node.aabb.max.z - node.aabb.min.z;
+        let axis = if lx > ly && lx > lz { 0 } else if ly > lx && ly > lz { 1 } else { 2 };
+        primitives.sort_by(|a, b| {
+            component(&a.centroid, axis).partial_cmp(&component(&b.centroid, axis)).unwrap()
+        });
+
+        let right = primitives.split_off(mid);
+        node.children.push(BuildNode::build_median(primitives));
+        node.children.push(BuildNode::build_median(right));
         node
     }
 
-    fn build_from_scene_with_indexes(scene: &Scene, indexes: &mut Vec<usize>) -> BvhNode {
-        let mut node = BvhNode::empty();
-        node.set_aabb_from_scene(scene, indexes);
+    fn build_sah(mut primitives: Vec<BuildPrimitive>, settings: &RenderSettings) -> BuildNode {
+        let mut node = BuildNode::empty();
+        let mut centroid_aabb = Aabb::empty();
+        for p in &primitives {
+            node.aabb.merge(&p.aabb);
+            centroid_aabb.merge(&Aabb { min: p.centroid, max: p.centroid });
+        }
 
-        let mid = indexes.len() / 2;
-        if mid <= 2 {
-            // set leaf node
-            node.indexes = indexes.clone();
-        } else {
-            // set intermediate node
-            let lx = node.aabb.max.x - node.aabb.min.x;
-            let ly = node.aabb.max.y - node.aabb.min.y;
-            let lz = node.aabb.max.z - node.aabb.min.z;
-
-            if lx > ly && lx > lz {
-                indexes.sort_by(|a, b| {
-                    let a_aabb = scene.elements[*a].aabb();
-                    let b_aabb = scene.elements[*b].aabb();
-                    let a_sum = a_aabb.min.x + a_aabb.max.x;
-                    let b_sum = b_aabb.min.x + b_aabb.max.x;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
-            } else if ly > lx && ly > lz {
-                indexes.sort_by(|a, b| {
-                    let a_aabb = scene.elements[*a].aabb();
-                    let b_aabb = scene.elements[*b].aabb();
-                    let a_sum = a_aabb.min.y + a_aabb.max.y;
-                    let b_sum = b_aabb.min.y + b_aabb.max.y;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
-            } else {
-                indexes.sort_by(|a, b| {
-                    let a_aabb = scene.elements[*a].aabb();
-                    let b_aabb = scene.elements[*b].aabb();
-                    let a_sum = a_aabb.min.z + a_aabb.max.z;
-                    let b_sum = b_aabb.min.z + b_aabb.max.z;
-                    a_sum.partial_cmp(&b_sum).unwrap()
-                });
+        let count = primitives.len();
+        if count <= 1 {
+            return BuildNode::leaf(node.aabb, &primitives);
+        }
+
+        // 各軸について重心をビンに振り分け、ビンの境界で分割したときのコストを求める
+        let bin_count = settings.bvh_bin_count.max(2);
+        let area = node.aabb.surface_area();
+        let area_inv = if area > 0.0 { area.recip() } else { 0.0 };
+        let mut best: Option<(f64, usize, usize)> = None;
+        for axis in 0..3 {
+            let min = component(&centroid_aabb.min, axis);
+            let extent = component(&centroid_aabb.max, axis) - min;
+            if extent <= 0.0 {
+                continue;
             }
 
-            let mut left_face_indexes = indexes.split_off(mid);
-            node.children.push(Box::new(BvhNode::build_from_scene_with_indexes(scene, indexes)));
-            node.children.push(Box::new(BvhNode::build_from_scene_with_indexes(scene, &mut left_face_indexes)));
+            let mut bins = vec![Bin { aabb: Aabb::empty(), count: 0 }; bin_count];
+            for p in &primitives {
+                let bin = &mut bins[bin_index(&p.centroid, axis, min, extent, bin_count)];
+                bin.aabb.merge(&p.aabb);
+                bin.count += 1;
+            }
+
+            // 右側から累積した表面積と個数
+            let mut right_costs = vec![0.0; bin_count];
+            let mut right = Bin { aabb: Aabb::empty(), count: 0 };
+            for i in (1..bin_count).rev() {
+                right.aabb.merge(&bins[i].aabb);
+                right.count += bins[i].count;
+                right_costs[i] = if right.count > 0 { right.aabb.surface_area() * right.count as f64 } else { 0.0 };
+            }
+
+            let mut left = Bin { aabb: Aabb::empty(), count: 0 };
+            for i in 0..(bin_count - 1) {
+                left.aabb.merge(&bins[i].aabb);
+                left.count += bins[i].count;
+                if left.count == 0 || left.count == count {
+                    continue;
+                }
+
+                let left_cost = left.aabb.surface_area() * left.count as f64;
+                let cost = settings.bvh_traversal_cost
+                    + (left_cost + right_costs[i + 1]) * area_inv * settings.bvh_intersection_cost;
+                let better = match best {
+                    Some((best_cost, _, _)) => cost < best_cost,
+                    None => true,
+                };
+                if better {
+                    best = Some((cost, axis, i));
+                }
+            }
         }
 
+        let leaf_cost = count as f64 * settings.bvh_intersection_cost;
+        let max_leaf_size = settings.bvh_max_leaf_size.max(1);
+        let right = match best {
+            Some((cost, _, _)) if cost >= leaf_cost && count <= max_leaf_size => {
+                return BuildNode::leaf(node.aabb, &primitives);
+            }
+            Some((_, axis, split)) => {
+                let min = component(&centroid_aabb.min, axis);
+                let extent = component(&centroid_aabb.max, axis) - min;
+                let (left, right): (Vec<_>, Vec<_>) = primitives.into_iter().partition(|p| {
+                    bin_index(&p.centroid, axis, min, extent, bin_count) <= split
+                });
+                primitives = left;
+                right
+            }
+            None => {
+                // 重心がすべて一致していて分割できない
+                if count <= max_leaf_size {
+                    return BuildNode::leaf(node.aabb, &primitives);
+                }
+                let mid = count / 2;
+                primitives.split_off(mid)
+            }
+        };
+
+        node.children.push(BuildNode::build_sah(primitives, settings));
+        node.children.push(BuildNode::build_sah(right, settings));
         node
     }
+}
 
-    pub fn build_from_mesh(mesh: &Mesh) -> BvhNode {
-        let mut face_indexes: Vec<usize> = (0..mesh.faces.len()).collect();
-        BvhNode::build_from_mesh_with_indexes(mesh, &mut face_indexes)
-    }
+#[derive(Debug)]
+pub enum BvhNodeKind {
+    // Bvh::indexes[offset..offset + count] のプリミティブを持つ
+    Leaf { offset: usize, count: usize },
+    // 左の子は配列上で直後に、右の子は right に配置する
+    Interior { right: usize },
+}
+
+#[derive(Debug)]
+pub struct BvhNode {
+    pub aabb: Aabb,
+    pub kind: BvhNodeKind,
+}
+
+// 深さ優先の順で配列に平坦化した BVH
+#[derive(Debug)]
+pub struct Bvh {
+    pub nodes: Vec<BvhNode>,
+    pub indexes: Vec<usize>,
+    pub max_depth: usize,
+}
+
+// BVH の形状の統計（比較モード用）
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    pub sah_cost: f64,
+}
 
-    pub fn build_from_scene(scene: &Scene) -> BvhNode {
-        let mut indexes: Vec<usize> = (0..scene.elements.len()).collect();
-        BvhNode::build_from_scene_with_indexes(scene, &mut indexes)
+// 走査の統計（比較モード用）
+#[derive(Default)]
+pub struct TraversalStats {
+    pub rays: usize,
+    pub node_visits: usize,
+    pub primitive_tests: usize,
+}
+
+impl Bvh {
+    fn from_build_node(root: BuildNode) -> Bvh {
+        let mut bvh = Bvh { nodes: vec![], indexes: vec![], max_depth: 0 };
+        bvh.flatten(root, 1);
+        bvh
     }
 
-    pub fn intersect_for_mesh(&self, mesh: &Mesh, ray: &Ray, intersection: &mut Intersection) -> bool {
-        if !self.aabb.intersect_ray(ray).0 {
-            return false;
+    fn flatten(&mut self, node: BuildNode, depth: usize) {
+        self.max_depth = self.max_depth.max(depth);
+        let index = self.nodes.len();
+        if node.children.is_empty() {
+            let kind = BvhNodeKind::Leaf { offset: self.indexes.len(), count: node.indexes.len() };
+            self.nodes.push(BvhNode { aabb: node.aabb, kind });
+            self.indexes.extend(node.indexes);
+        } else {
+            self.nodes.push(BvhNode { aabb: node.aabb, kind: BvhNodeKind::Interior { right: 0 } });
+            let mut children = node.children.into_iter();
+            self.flatten(children.next().unwrap(), depth + 1);
+            let right = self.nodes.len();
+            self.nodes[index].kind = BvhNodeKind::Interior { right };
+            self.flatten(children.next().unwrap(), depth + 1);
         }
+    }
+
+    pub fn build_from_mesh(mesh: &Mesh, settings: &RenderSettings) -> Bvh {
+        let primitives = mesh.faces.iter().enumerate().map(|(index, face)| {
+            let v0 = &mesh.vertexes[face.v0];
+            let v1 = &mesh.vertexes[face.v1];
+            let v2 = &mesh.vertexes[face.v2];
+            BuildPrimitive {
+                index,
+                aabb: Aabb::from_triangle(v0, v1, v2),
+                centroid: (*v0 + *v1 + *v2) / 3.0,
+            }
+        }).collect();
+        Bvh::from_build_node(BuildNode::build(primitives, settings))
+    }
 
-        let mut any_hit = false;
-        if self.children.is_empty() {
-            // leaf node
-            for face_index in &self.indexes {
-                let face = &mesh.faces[*face_index];
-                if intersect_polygon(&mesh.vertexes[face.v0], &mesh.vertexes[face.v1], &mesh.vertexes[face.v2], ray, intersection) {
-                    any_hit = true;
+    pub fn build_from_scene(scene: &Scene, settings: &RenderSettings) -> Bvh {
+        let primitives = scene.elements.iter().enumerate().map(|(index, element)| {
+            let aabb = element.aabb();
+            let centroid = aabb.center();
+            BuildPrimitive { index, aabb, centroid }
+        }).collect();
+        Bvh::from_build_node(BuildNode::build(primitives, settings))
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        self.nodes[0].aabb.clone()
+    }
+
+    pub fn stats(&self, settings: &RenderSettings) -> BvhStats {
+        let area = self.nodes[0].aabb.surface_area();
+        let area_inv = if area > 0.0 { area.recip() } else { 0.0 };
+        let mut stats = BvhStats { node_count: self.nodes.len(), leaf_count: 0, max_depth: self.max_depth, sah_cost: 0.0 };
+        for node in &self.nodes {
+            let probability = node.aabb.surface_area() * area_inv;
+            match node.kind {
+                BvhNodeKind::Leaf { count, .. } => {
+                    stats.leaf_count += 1;
+                    stats.sah_cost += probability * count as f64 * settings.bvh_intersection_cost;
+                }
+                BvhNodeKind::Interior { .. } => {
+                    stats.sah_cost += probability * settings.bvh_traversal_cost;
                 }
             }
-        } else {
-            // intermediate node
-            for child in &self.children {
-                if child.intersect_for_mesh(mesh, ray, intersection) {
-                    any_hit = true;
+        }
+        stats
+    }
+
+    // 最も近い交差をしたプリミティブのインデックスを返す
+    // 近い子ノードから順に走査し、intersection.distance より遠いノードは枝刈りする
+    fn traverse<F>(&self, ray: &Ray, intersection: &mut Intersection, stats: &mut TraversalStats, mut intersect_primitive: F) -> Option<usize>
+        where F: FnMut(usize, &mut Intersection) -> bool {
+        let dir_inv = Vector3::new(
+            ray.direction.x.recip(),
+            ray.direction.y.recip(),
+            ray.direction.z.recip(),
+        );
+
+        let mut nearest_index = None;
+        let mut stack: Vec<(usize, f64)> = Vec::with_capacity(self.max_depth + 1);
+
+        stats.node_visits += 1;
+        if let Some(distance) = self.nodes[0].aabb.entry_distance(ray, &dir_inv) {
+            stack.push((0, distance));
+        }
+
+        while let Some((index, distance)) = stack.pop() {
+            if distance > intersection.distance {
+                continue;
+            }
+
+            match self.nodes[index].kind {
+                BvhNodeKind::Leaf { offset, count } => {
+                    stats.primitive_tests += count;
+                    for primitive_index in &self.indexes[offset..offset + count] {
+                        if intersect_primitive(*primitive_index, intersection) {
+                            nearest_index = Some(*primitive_index);
+                        }
+                    }
+                }
+                BvhNodeKind::Interior { right } => {
+                    let left = index + 1;
+                    stats.node_visits += 2;
+                    let left_distance = self.nodes[left].aabb.entry_distance(ray, &dir_inv)
+                        .filter(|d| *d <= intersection.distance);
+                    let right_distance = self.nodes[right].aabb.entry_distance(ray, &dir_inv)
+                        .filter(|d| *d <= intersection.distance);
+
+                    // 近い方を後に積んで先に取り出す
+                    match (left_distance, right_distance) {
+                        (Some(l), Some(r)) => {
+                            if l <= r {
+                                stack.push((right, r));
+                                stack.push((left, l));
+                            } else {
+                                stack.push((left, l));
+                                stack.push((right, r));
+                            }
+                        }
+                        (Some(l), None) => stack.push((left, l)),
+                        (None, Some(r)) => stack.push((right, r)),
+                        (None, None) => {}
+                    }
                 }
             }
         }
 
-        any_hit
+        nearest_index
     }
 
-    pub fn intersect_for_scene(&self, scene: &Scene, ray: &Ray, intersection: &mut Intersection) -> Option<usize> {
-        if !self.aabb.intersect_ray(ray).0 {
-            return None;
+    // max_distance 未満の距離で交差するプリミティブがあるかどうか
+    // 交差が1つ見つかれば打ち切るので、子ノードの順序は考慮しない
+    fn any_hit<F>(&self, ray: &Ray, max_distance: f64, mut occluded_primitive: F) -> bool
+        where F: FnMut(usize) -> bool {
+        let dir_inv = Vector3::new(
+            ray.direction.x.recip(),
+            ray.direction.y.recip(),
+            ray.direction.z.recip(),
+        );
+        let visible = |node: &BvhNode| {
+            match node.aabb.entry_distance(ray, &dir_inv) {
+                Some(distance) => distance < max_distance,
+                None => false,
+            }
+        };
+
+        let mut stack: Vec<usize> = Vec::with_capacity(self.max_depth + 1);
+        if visible(&self.nodes[0]) {
+            stack.push(0);
         }
 
-        let mut nearest_index: Option<usize> = None;
-        if self.children.is_empty() {
-            // leaf node
-            for index in &self.indexes {
-                let e = &scene.elements[*index];
-                if e.intersect(ray, intersection) {
-                    nearest_index = Some(*index);
+        while let Some(index) = stack.pop() {
+            match self.nodes[index].kind {
+                BvhNodeKind::Leaf { offset, count } => {
+                    if self.indexes[offset..offset + count].iter().any(|i| occluded_primitive(*i)) {
+                        return true;
+                    }
                 }
-            }
-        } else {
-            // intermediate node
-            for child in &self.children {
-                if let Some(index) = child.intersect_for_scene(scene, ray, intersection) {
-                    nearest_index = Some(index);
+                BvhNodeKind::Interior { right } => {
+                    if visible(&self.nodes[right]) {
+                        stack.push(right);
+                    }
+                    if visible(&self.nodes[index + 1]) {
+                        stack.push(index + 1);
+                    }
                 }
             }
         }
 
-        nearest_index
+        false
+    }
+
+    pub fn intersect_for_mesh(&self, mesh: &Mesh, ray: &Ray, intersection: &mut Intersection) -> Option<usize> {
+        self.count_traversal_for_mesh(mesh, ray, intersection, &mut TraversalStats::default())
     }
+
+    pub fn intersect_for_scene(&self, scene: &Scene, ray: &Ray, intersection: &mut Intersection) -> Option<usize> {
+        self.count_traversal_for_scene(scene, ray, intersection, &mut TraversalStats::default())
+    }
+
+    pub fn occluded_for_mesh(&self, mesh: &Mesh, ray: &Ray, max_distance: f64) -> bool {
+        self.any_hit(ray, max_distance, |face_index| {
+            let face = &mesh.faces[face_index];
+            let mut intersection = Intersection::empty();
+            intersection.distance = max_distance;
+            intersect_polygon(&mesh.vertexes[face.v0], &mesh.vertexes[face.v1], &mesh.vertexes[face.v2], ray, &mut intersection)
+                && intersection.distance < max_distance
+        })
+    }
+
+    pub fn occluded_for_scene(&self, scene: &Scene, ray: &Ray, max_distance: f64) -> bool {
+        self.any_hit(ray, max_distance, |index| scene.elements[index].occluded(ray, max_distance))
+    }
+
+    // 走査したノードとプリミティブを数えながら交差判定する（比較モード用）
+    pub fn count_traversal_for_mesh(&self, mesh: &Mesh, ray: &Ray, intersection: &mut Intersection, stats: &mut TraversalStats) -> Option<usize> {
+        self.traverse(ray, intersection, stats, |face_index, intersection| {
+            let face = &mesh.faces[face_index];
+            intersect_polygon(&mesh.vertexes[face.v0], &mesh.vertexes[face.v1], &mesh.vertexes[face.v2], ray, intersection)
+        })
+    }
+
+    pub fn count_traversal_for_scene(&self, scene: &Scene, ray: &Ray, intersection: &mut Intersection, stats: &mut TraversalStats) -> Option<usize> {
+        self.traverse(ray, intersection, stats, |index, intersection| {
+            scene.elements[index].intersect(ray, intersection)
+        })
+    }
+}
+
+fn bin_index(centroid: &Vector3, axis: usize, min: f64, extent: f64, bin_count: usize) -> usize {
+    (((component(centroid, axis) - min) / extent * bin_count as f64) as usize).min(bin_count - 1)
 }
 
 pub fn intersect_polygon(v0: &Vector3, v1: &Vector3, v2: &Vector3, ray: &Ray, intersection: &mut Intersection) -> bool {
@@ -284,6 +581,7 @@ pub fn intersect_polygon(v0: &Vector3, v1: &Vector3, v2: &Vector3, ray: &Ray, in
 
     intersection.position = ray.origin + ray.direction * t;
     intersection.normal = edge1.cross(&edge2).normalize();
+    intersection.geometric_normal = intersection.normal;
     intersection.distance = t;
     intersection.uv = Vector2::new(u, v);
     true
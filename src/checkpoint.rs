@@ -0,0 +1,126 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use vector::Vector3;
+use loader::LoadError;
+
+const MAGIC: &[u8; 8] = b"HNMRCKPT";
+const VERSION: u32 = 2;
+// マジックナンバーから次の途中結果の画像の番号までのバイト数
+const HEADER_SIZE: u64 = 36;
+const PIXEL_SIZE: u64 = 24;
+
+// 途中まで累積したレンダリング結果
+// calc_pixel の乱数のシードはサンプリング回数から決まるので、
+// sampling の次の回から累積を再開すれば中断しなかった場合と同じ結果になる
+pub struct Checkpoint {
+    pub hash: u64,
+    pub width: u32,
+    pub height: u32,
+    pub sampling: u32,
+    // 再開後に途中結果の画像を上書きしないように、次に出力する画像の番号を保存する
+    pub report_image_counter: u32,
+    pub accumulation_buf: Vec<Vector3>,
+}
+
+impl Checkpoint {
+    pub fn load(path: &str) -> Result<Checkpoint, LoadError> {
+        let error = |message: String| LoadError::new(path, 0, message);
+        let f = File::open(path).map_err(|e| error(e.to_string()))?;
+        let file_size = f.metadata().map_err(|e| error(e.to_string()))?.len();
+        let mut r = BufReader::new(f);
+
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).map_err(|e| error(e.to_string()))?;
+        if &magic != MAGIC {
+            return Err(error("not a checkpoint file".to_string()));
+        }
+        let version = read_u32(&mut r).map_err(|e| error(e.to_string()))?;
+        if version != VERSION {
+            return Err(error(format!("unsupported checkpoint version {}", version)));
+        }
+
+        let read_header = |r: &mut BufReader<File>| -> io::Result<(u64, u32, u32, u32, u32)> {
+            Ok((read_u64(r)?, read_u32(r)?, read_u32(r)?, read_u32(r)?, read_u32(r)?))
+        };
+        let (hash, width, height, sampling, report_image_counter) = read_header(&mut r).map_err(|e| error(e.to_string()))?;
+
+        // 壊れたファイルで巨大な領域を確保しないように、ピクセル数をファイルの大きさと照合してから読む
+        let pixel_count = (width as usize).checked_mul(height as usize);
+        let expected_size = pixel_count
+            .and_then(|n| (n as u64).checked_mul(PIXEL_SIZE))
+            .and_then(|n| n.checked_add(HEADER_SIZE));
+        let pixel_count = match (pixel_count, expected_size) {
+            (Some(n), Some(size)) if size == file_size => n,
+            _ => return Err(error(format!("checkpoint size does not match {}x{} pixels", width, height))),
+        };
+
+        let read_pixels = |r: &mut BufReader<File>| -> io::Result<Vec<Vector3>> {
+            let mut accumulation_buf = Vec::with_capacity(pixel_count);
+            for _ in 0..pixel_count {
+                let x = read_f64(r)?;
+                let y = read_f64(r)?;
+                let z = read_f64(r)?;
+                accumulation_buf.push(Vector3::new(x, y, z));
+            }
+            Ok(accumulation_buf)
+        };
+        let accumulation_buf = read_pixels(&mut r).map_err(|e| error(e.to_string()))?;
+        Ok(Checkpoint { hash, width, height, sampling, report_image_counter, accumulation_buf })
+    }
+
+    // 書き込み途中で中断しても前回のチェックポイントが壊れないように、一時ファイルに書いてから置き換える
+    pub fn save(path: &str, hash: u64, width: u32, height: u32, sampling: u32, report_image_counter: u32, accumulation_buf: &[Vector3]) -> io::Result<()> {
+        let tmp_path = format!("{}.tmp", path);
+        {
+            let mut w = BufWriter::new(File::create(&tmp_path)?);
+            w.write_all(MAGIC)?;
+            w.write_all(&VERSION.to_le_bytes())?;
+            w.write_all(&hash.to_le_bytes())?;
+            w.write_all(&width.to_le_bytes())?;
+            w.write_all(&height.to_le_bytes())?;
+            w.write_all(&sampling.to_le_bytes())?;
+            w.write_all(&report_image_counter.to_le_bytes())?;
+            for pixel in accumulation_buf {
+                w.write_all(&pixel.x.to_le_bytes())?;
+                w.write_all(&pixel.y.to_le_bytes())?;
+                w.write_all(&pixel.z.to_le_bytes())?;
+            }
+            w.flush()?;
+        }
+        fs::rename(&tmp_path, path)
+    }
+}
+
+// チェックポイントを再開してよいかを照合するためのハッシュ（FNV-1a）
+// 実行環境によって値が変わらないように std の Hasher は使わない
+pub fn hash(sources: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for source in sources {
+        for byte in source.iter().chain(&[0xff]) {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
@@ -1,5 +1,12 @@
 use std::f64;
+use std::fs::File;
+use std::io::{BufReader, BufRead};
+use std::str::FromStr;
 use tonemap::ToneMappingMode;
+use renderer::MisHeuristic;
+use loader::LoadError;
+use bvh::BvhBuildMethod;
+use light::LightSampling;
 
 pub const PI: f64 = f64::consts::PI;
 pub const PI2: f64 = 2.0 * PI;
@@ -10,16 +17,164 @@ pub const INF: f64 = 1e100;
 
 pub const GAMMA_FACTOR: f64 = 2.2;
 
-pub const SUPERSAMPLING: u32 = 2;
-pub const PATHTRACING_BOUNCE_LIMIT: u32 = 10;
+// 実行時に変更できるレンダリング設定
+// 設定ファイルのキーはフィールド名と同じ
+#[derive(Clone, Debug)]
+pub struct RenderSettings {
+    pub supersampling: u32,
+    pub pathtracing_bounce_limit: u32,
+    pub russian_roulette_depth: u32,
+    pub mis_heuristic: MisHeuristic,
+    pub light_sampling: LightSampling,
+    // パスごとに波長をサンプリングして、誘電体の分散を扱う
+    pub spectral_rendering: bool,
 
-// Tone Mapping
-pub const TONE_MAPPING_MODE: ToneMappingMode = ToneMappingMode::Reinhard;
-pub const TONE_MAPPING_EXPOSURE: f64 = 1.5;
-pub const TONE_MAPPING_WHITE_POINT: f64 = 20.0;
+    // BVH
+    pub bvh_build_method: BvhBuildMethod,
+    pub bvh_max_leaf_size: usize,
+    pub bvh_bin_count: usize,
+    pub bvh_traversal_cost: f64,
+    pub bvh_intersection_cost: f64,
 
-// Denoising - Bilateral Fileter
-pub const BILATERAL_FILTER_ITERATION: u32 = 1;
-pub const BILATERAL_FILTER_DIAMETER: u32 = 3;
-pub const BILATERAL_FILTER_SIGMA_I: f64 = 1.0;// これを無限大にすると Gaussian Blur となる
-pub const BILATERAL_FILTER_SIGMA_S: f64 = 16.0;
+    // Tone Mapping
+    pub tone_mapping_mode: ToneMappingMode,
+    pub tone_mapping_exposure: f64,
+    pub tone_mapping_white_point: f64,
+
+    // Denoising - Bilateral Fileter
+    pub bilateral_filter_iteration: u32,
+    pub bilateral_filter_diameter: u32,
+    pub bilateral_filter_sigma_i: f64,// これを無限大にすると Gaussian Blur となる
+    pub bilateral_filter_sigma_s: f64,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            supersampling: 2,
+            pathtracing_bounce_limit: 64,
+            russian_roulette_depth: 3,
+            mis_heuristic: MisHeuristic::Power,
+            light_sampling: LightSampling::Power,
+            spectral_rendering: false,
+
+            bvh_build_method: BvhBuildMethod::Sah,
+            bvh_max_leaf_size: 4,
+            bvh_bin_count: 16,
+            bvh_traversal_cost: 1.0,
+            bvh_intersection_cost: 1.0,
+
+            tone_mapping_mode: ToneMappingMode::Reinhard,
+            tone_mapping_exposure: 1.5,
+            tone_mapping_white_point: 20.0,
+
+            bilateral_filter_iteration: 1,
+            bilateral_filter_diameter: 3,
+            bilateral_filter_sigma_i: 1.0,
+            bilateral_filter_sigma_s: 16.0,
+        }
+    }
+}
+
+impl RenderSettings {
+    // 1行に1つ `key value` を記述する。# 以降はコメント
+    // 記述されていない項目はデフォルト値のままとなる
+    pub fn load(path: &str) -> Result<RenderSettings, LoadError> {
+        let mut settings = RenderSettings::default();
+        let f = File::open(path).map_err(|e| LoadError::new(path, 0, e.to_string()))?;
+
+        for (i, line) in BufReader::new(f).lines().enumerate() {
+            let line_number = i + 1;
+            let l = line.map_err(|e| LoadError::new(path, line_number, e.to_string()))?;
+            let content = match l.find('#') {
+                Some(pos) => &l[..pos],
+                None => &l[..],
+            };
+            let tokens: Vec<&str> = content.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            if tokens.len() != 2 {
+                return Err(LoadError::new(path, line_number, format!("expected `key value`, found `{}`", tokens.join(" "))));
+            }
+
+            settings.set(tokens[0], tokens[1]).map_err(|message| LoadError::new(path, line_number, message))?;
+        }
+
+        Ok(settings)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            // 0 ではサンプルが無く、バウンスは 1..limit で数えるので 2 未満では何も描かれない
+            "supersampling" => self.supersampling = parse_at_least(key, value, 1)?,
+            "pathtracing_bounce_limit" => self.pathtracing_bounce_limit = parse_at_least(key, value, 2)?,
+            "russian_roulette_depth" => self.russian_roulette_depth = parse_value(key, value)?,
+            "mis_heuristic" => self.mis_heuristic = parse_value(key, value)?,
+            "light_sampling" => self.light_sampling = parse_value(key, value)?,
+            "spectral_rendering" => self.spectral_rendering = parse_value(key, value)?,
+            "bvh_build_method" => self.bvh_build_method = parse_value(key, value)?,
+            "bvh_max_leaf_size" => self.bvh_max_leaf_size = parse_value(key, value)?,
+            "bvh_bin_count" => self.bvh_bin_count = parse_value(key, value)?,
+            "bvh_traversal_cost" => self.bvh_traversal_cost = parse_value(key, value)?,
+            "bvh_intersection_cost" => self.bvh_intersection_cost = parse_value(key, value)?,
+            "tone_mapping_mode" => self.tone_mapping_mode = parse_value(key, value)?,
+            "tone_mapping_exposure" => self.tone_mapping_exposure = parse_value(key, value)?,
+            "tone_mapping_white_point" => self.tone_mapping_white_point = parse_value(key, value)?,
+            "bilateral_filter_iteration" => self.bilateral_filter_iteration = parse_value(key, value)?,
+            "bilateral_filter_diameter" => self.bilateral_filter_diameter = parse_value(key, value)?,
+            "bilateral_filter_sigma_i" => self.bilateral_filter_sigma_i = parse_value(key, value)?,
+            "bilateral_filter_sigma_s" => self.bilateral_filter_sigma_s = parse_value(key, value)?,
+            _ => return Err(format!("unknown setting `{}`", key)),
+        }
+        Ok(())
+    }
+
+    // 設定ファイルのキーの一覧（コマンドライン引数ではアンダースコアをハイフンに置き換えて指定する）
+    pub fn keys() -> &'static [&'static str] {
+        &[
+            "supersampling",
+            "pathtracing_bounce_limit",
+            "russian_roulette_depth",
+            "mis_heuristic",
+            "light_sampling",
+            "spectral_rendering",
+            "bvh_build_method",
+            "bvh_max_leaf_size",
+            "bvh_bin_count",
+            "bvh_traversal_cost",
+            "bvh_intersection_cost",
+            "tone_mapping_mode",
+            "tone_mapping_exposure",
+            "tone_mapping_white_point",
+            "bilateral_filter_iteration",
+            "bilateral_filter_diameter",
+            "bilateral_filter_sigma_i",
+            "bilateral_filter_sigma_s",
+        ]
+    }
+
+    // 累積バッファの値に影響する設定（チェックポイントの照合に使う）
+    // トーンマッピングやフィルタは累積後に適用されるので含めない
+    pub fn sampling_signature(&self) -> String {
+        format!("supersampling={} pathtracing_bounce_limit={} russian_roulette_depth={} mis_heuristic={:?} light_sampling={:?} spectral_rendering={}",
+                self.supersampling, self.pathtracing_bounce_limit, self.russian_roulette_depth, self.mis_heuristic, self.light_sampling,
+                self.spectral_rendering)
+    }
+
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.supersampling * self.supersampling
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse::<T>().map_err(|_| format!("invalid value `{}` for `{}`", value, key))
+}
+
+fn parse_at_least(key: &str, value: &str, min: u32) -> Result<u32, String> {
+    let parsed = parse_value(key, value)?;
+    if parsed < min {
+        return Err(format!("`{}` must be at least {}", key, min));
+    }
+    Ok(parsed)
+}
@@ -0,0 +1,553 @@
+use std::f64;
+use std::io;
+
+use config;
+use vector::Vector3;
+use texture::ImageTexture;
+use math::{clamp, component, set_component, Distribution1D};
+use color::{Color, color_to_luminance};
+use hdr;
+use material::get_tangent_space_basis_gram_schmidtd;
+
+// 交差しなかったレイが到達する無限遠の環境光
+pub trait Environment: Sync {
+    // direction の方向から届く放射輝度
+    fn sample(&self, direction: &Vector3) -> Color;
+
+    // 輝度に比例した確率で方向をサンプリングして、その方向と立体角測度の確率密度を返す
+    fn sample_direction(&self, random: (f64, f64)) -> (Vector3, f64);
+
+    // sample_direction で direction がサンプリングされる立体角測度の確率密度
+    fn pdf(&self, direction: &Vector3) -> f64;
+
+    // 輝度を全立体角で積分した値（光源の放射束の見積もりに使う）
+    fn luminance_integral(&self) -> f64;
+}
+
+// 6 面のキューブマップ
+pub struct Skybox {
+    pub px_texture: ImageTexture,
+    pub nx_texture: ImageTexture,
+    pub py_texture: ImageTexture,
+    pub ny_texture: ImageTexture,
+    pub pz_texture: ImageTexture,
+    pub nz_texture: ImageTexture,
+    pub intensity: Vector3,
+
+    // 方向のサンプリング用に、各面を分割したセルの輝度 × 立体角に比例した分布
+    distribution: Distribution1D,
+}
+
+// 重点サンプリング用の分布の各面の分割数と、セルごとの輝度を求めるサンプル数（1軸あたり）
+const SKYBOX_DISTRIBUTION_RESOLUTION: usize = 64;
+const SKYBOX_DISTRIBUTION_SUBSAMPLES: usize = 4;
+
+impl Skybox {
+    pub fn new(px_path: &str, nx_path: &str, py_path: &str, ny_path: &str, pz_path: &str, nz_path: &str, intensity: &Vector3) -> Skybox {
+        let mut skybox = Skybox {
+            px_texture: ImageTexture::new(px_path),
+            nx_texture: ImageTexture::new(nx_path),
+            py_texture: ImageTexture::new(py_path),
+            ny_texture: ImageTexture::new(ny_path),
+            pz_texture: ImageTexture::new(pz_path),
+            nz_texture: ImageTexture::new(nz_path),
+            intensity: *intensity,
+            distribution: Distribution1D::new(&[]),
+        };
+        skybox.distribution = Distribution1D::new(&skybox.cell_weights());
+        skybox
+    }
+
+    fn cell_weights(&self) -> Vec<f64> {
+        let n = SKYBOX_DISTRIBUTION_RESOLUTION;
+        let m = SKYBOX_DISTRIBUTION_SUBSAMPLES;
+        let coord = |k: f64| k / n as f64 * 2.0 - 1.0;
+
+        let mut weights = Vec::with_capacity(6 * n * n);
+        for face in 0..6 {
+            for j in 0..n {
+                for i in 0..n {
+                    let mut luminance = 0.0;
+                    for sj in 0..m {
+                        for si in 0..m {
+                            let a = coord(i as f64 + (si as f64 + 0.5) / m as f64);
+                            let b = coord(j as f64 + (sj as f64 + 0.5) / m as f64);
+                            luminance += color_to_luminance(&self.sample(&Skybox::face_direction(face, a, b)));
+                        }
+                    }
+                    luminance /= (m * m) as f64;
+
+                    let (a0, a1) = (coord(i as f64), coord(i as f64 + 1.0));
+                    let (b0, b1) = (coord(j as f64), coord(j as f64 + 1.0));
+                    let solid_angle = Skybox::face_solid_angle(a1, b1) - Skybox::face_solid_angle(a0, b1)
+                        - Skybox::face_solid_angle(a1, b0) + Skybox::face_solid_angle(a0, b0);
+                    weights.push(luminance * solid_angle);
+                }
+            }
+        }
+        weights
+    }
+
+    // 面の番号（+x, -x, +y, -y, +z, -z の順）と面上の座標 (a, b) ∈ [-1, 1]^2 から方向を求める
+    // a, b はそれぞれ主軸以外の2軸のうち、インデックスの小さい軸と大きい軸の成分
+    fn face_direction(face: usize, a: f64, b: f64) -> Vector3 {
+        let axis = face / 2;
+        let sign = if face % 2 == 1 { -1.0 } else { 1.0 };
+        let mut direction = Vector3::zero();
+        set_component(&mut direction, axis, sign);
+        let (u_axis, v_axis) = Skybox::face_axes(axis);
+        set_component(&mut direction, u_axis, a);
+        set_component(&mut direction, v_axis, b);
+        direction.normalize()
+    }
+
+    // face_direction の逆変換
+    fn face_coord(direction: &Vector3) -> (usize, f64, f64) {
+        let abs = Vector3::new(direction.x.abs(), direction.y.abs(), direction.z.abs());
+        let axis = if abs.x >= abs.y && abs.x >= abs.z { 0 } else if abs.y >= abs.z { 1 } else { 2 };
+        let major = component(direction, axis);
+        let face = axis * 2 + if major.is_sign_positive() { 0 } else { 1 };
+        let (u_axis, v_axis) = Skybox::face_axes(axis);
+        let scale = major.abs().recip();
+        (face, component(direction, u_axis) * scale, component(direction, v_axis) * scale)
+    }
+
+    // 主軸が axis の面の座標 (a, b) に対応する軸
+    fn face_axes(axis: usize) -> (usize, usize) {
+        match axis {
+            0 => (1, 2),
+            1 => (0, 2),
+            _ => (0, 1),
+        }
+    }
+
+    // 距離 1 の面上の矩形 [0, a] × [0, b] が原点に張る立体角（符号付き）
+    fn face_solid_angle(a: f64, b: f64) -> f64 {
+        (a * b).atan2((a * a + b * b + 1.0).sqrt())
+    }
+}
+
+impl Environment for Skybox {
+    fn sample(&self, direction: &Vector3) -> Color {
+        let abs_x = direction.x.abs();
+        let abs_y = direction.y.abs();
+        let abs_z = direction.z.abs();
+
+        if abs_x > abs_y && abs_x > abs_z {
+            if direction.x.is_sign_positive() {
+                self.intensity * self.px_texture.sample_bilinear_0center(-direction.z / direction.x, direction.y / direction.x)
+            } else {
+                self.intensity * self.nx_texture.sample_bilinear_0center(-direction.z / direction.x, -direction.y / direction.x)
+            }
+        } else if abs_y > abs_x && abs_y > abs_z {
+            if direction.y.is_sign_positive() {
+                self.intensity * self.py_texture.sample_bilinear_0center(direction.x / direction.y, -direction.z / direction.y)
+            } else {
+                self.intensity * self.ny_texture.sample_bilinear_0center(-direction.x / direction.y, -direction.z / direction.y)
+            }
+        } else {
+            if direction.z.is_sign_positive() {
+                self.intensity * self.pz_texture.sample_bilinear_0center(direction.x / direction.z, direction.y / direction.z)
+            } else {
+                self.intensity * self.nz_texture.sample_bilinear_0center(direction.x / direction.z, -direction.y / direction.z)
+            }
+        }
+    }
+
+    fn sample_direction(&self, random: (f64, f64)) -> (Vector3, f64) {
+        let (index, u) = self.distribution.sample(random.0);
+        let n = SKYBOX_DISTRIBUTION_RESOLUTION;
+        let face = index / (n * n);
+        let i = index % n;
+        let j = index / n % n;
+        let a = (i as f64 + u) / n as f64 * 2.0 - 1.0;
+        let b = (j as f64 + random.1) / n as f64 * 2.0 - 1.0;
+        let direction = Skybox::face_direction(face, a, b);
+        (direction, self.pdf(&direction))
+    }
+
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        if self.distribution.total() == 0.0 {
+            return 0.0;
+        }
+
+        let (face, a, b) = Skybox::face_coord(direction);
+        let n = SKYBOX_DISTRIBUTION_RESOLUTION;
+        let i = (((a + 1.0) * 0.5 * n as f64) as usize).min(n - 1);
+        let j = (((b + 1.0) * 0.5 * n as f64) as usize).min(n - 1);
+        let probability = self.distribution.probability((face * n + j) * n + i);
+
+        // 面上の面積測度から立体角測度への変換
+        // 距離 1 の面上の点 (a, b) では dω = dA / r^3 （r = sqrt(1 + a^2 + b^2)）
+        let cell_area = (2.0 / n as f64) * (2.0 / n as f64);
+        let r = (1.0 + a * a + b * b).sqrt();
+        probability / cell_area * r * r * r
+    }
+
+    fn luminance_integral(&self) -> f64 {
+        self.distribution.total()
+    }
+}
+
+// 緯度経度（equirectangular）形式の HDR 画像
+// 回転前は画像の中央が -z 方向、上端が +y 方向になる
+pub struct EquirectangularMap {
+    width: usize,
+    height: usize,
+    // 左上から右下への行優先の並びのリニアな色
+    pixels: Vec<Color>,
+    pub intensity: Vector3,
+    // y 軸まわりの回転（ラジアン）
+    pub rotation: f64,
+
+    // 方向のサンプリング用に、画像を分割したセルの輝度 × 立体角に比例した分布
+    distribution: Distribution1D,
+    grid_width: usize,
+    grid_height: usize,
+}
+
+// 重点サンプリング用の分布の横方向の最大の分割数（縦方向はその半分）
+const EQUIRECTANGULAR_DISTRIBUTION_MAX_RESOLUTION: usize = 1024;
+
+impl EquirectangularMap {
+    pub fn load(path: &str, intensity: &Vector3, rotation: f64) -> io::Result<EquirectangularMap> {
+        let (pixels, width, height) = hdr::load(path)?;
+        let width = width as usize;
+        let height = height as usize;
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty image"));
+        }
+        Ok(EquirectangularMap::new(pixels, width, height, intensity, rotation))
+    }
+
+    // pixels は左上から右下への行優先の並びのリニアな色
+    pub fn new(pixels: Vec<Color>, width: usize, height: usize, intensity: &Vector3, rotation: f64) -> EquirectangularMap {
+        let grid_width = width.min(EQUIRECTANGULAR_DISTRIBUTION_MAX_RESOLUTION);
+        let grid_height = height.min(EQUIRECTANGULAR_DISTRIBUTION_MAX_RESOLUTION / 2);
+
+        // 小さな太陽などを取りこぼさないように、セルの輝度はセルに含まれる全ピクセルの平均とする
+        let mut luminance = vec![0.0; grid_width * grid_height];
+        let mut count = vec![0; grid_width * grid_height];
+        for y in 0..height {
+            let gy = y * grid_height / height;
+            for x in 0..width {
+                let cell = gy * grid_width + x * grid_width / width;
+                luminance[cell] += color_to_luminance(&(pixels[y * width + x] * *intensity));
+                count[cell] += 1;
+            }
+        }
+
+        let mut weights = Vec::with_capacity(grid_width * grid_height);
+        for gy in 0..grid_height {
+            let theta0 = config::PI * gy as f64 / grid_height as f64;
+            let theta1 = config::PI * (gy + 1) as f64 / grid_height as f64;
+            let solid_angle = config::PI2 / grid_width as f64 * (theta0.cos() - theta1.cos());
+            for gx in 0..grid_width {
+                let cell = gy * grid_width + gx;
+                weights.push(luminance[cell] / count[cell] as f64 * solid_angle);
+            }
+        }
+
+        EquirectangularMap {
+            width,
+            height,
+            pixels,
+            intensity: *intensity,
+            rotation,
+            distribution: Distribution1D::new(&weights),
+            grid_width,
+            grid_height,
+        }
+    }
+
+    // 方向から画像上の座標 (u, v) ∈ [0, 1]^2 を求める（v = 0 が上端）
+    fn direction_to_uv(&self, direction: &Vector3) -> (f64, f64) {
+        equirectangular_uv(&rotate_y(direction, -self.rotation))
+    }
+
+    fn uv_to_direction(&self, u: f64, v: f64) -> Vector3 {
+        rotate_y(&equirectangular_direction(u, v), self.rotation)
+    }
+
+    // 横方向は周期的に、縦方向は端の画素で補間する
+    fn pixel(&self, x: i64, y: i64) -> Color {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.max(0).min(self.height as i64 - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Environment for EquirectangularMap {
+    fn sample(&self, direction: &Vector3) -> Color {
+        let (u, v) = self.direction_to_uv(direction);
+        let x = u * self.width as f64 - 0.5;
+        let y = v * self.height as f64 - 0.5;
+        let x0 = x.floor();
+        let y0 = y.floor();
+        let tx = x - x0;
+        let ty = y - y0;
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let color = self.pixel(x0, y0) * (1.0 - tx) * (1.0 - ty)
+            + self.pixel(x0 + 1, y0) * tx * (1.0 - ty)
+            + self.pixel(x0, y0 + 1) * (1.0 - tx) * ty
+            + self.pixel(x0 + 1, y0 + 1) * tx * ty;
+        self.intensity * color
+    }
+
+    fn sample_direction(&self, random: (f64, f64)) -> (Vector3, f64) {
+        let (index, u) = self.distribution.sample(random.0);
+        let u = ((index % self.grid_width) as f64 + u) / self.grid_width as f64;
+        let v = ((index / self.grid_width) as f64 + random.1) / self.grid_height as f64;
+        let direction = self.uv_to_direction(u, v);
+        (direction, self.pdf(&direction))
+    }
+
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        if self.distribution.total() == 0.0 {
+            return 0.0;
+        }
+
+        let (u, v) = self.direction_to_uv(direction);
+        let sin_theta = (v * config::PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let gx = ((u * self.grid_width as f64) as usize).min(self.grid_width - 1);
+        let gy = ((v * self.grid_height as f64) as usize).min(self.grid_height - 1);
+        let probability = self.distribution.probability(gy * self.grid_width + gx);
+
+        // (u, v) の面積測度から立体角測度への変換（dω = 2π^2 sinθ du dv）
+        probability * (self.grid_width * self.grid_height) as f64 / (2.0 * config::PI * config::PI * sin_theta)
+    }
+
+    fn luminance_integral(&self) -> f64 {
+        self.distribution.total()
+    }
+}
+
+// Preetham et al. の昼光の空のモデルと太陽
+// A. J. Preetham, P. Shirley, B. Smits, "A Practical Analytic Model for Daylight", SIGGRAPH 1999
+// 地平線より下は ground_albedo で拡散反射する無限に広い地面として、空と太陽に照らされた明るさにする
+pub struct PhysicalSky {
+    // 太陽を除いた空と地面を緯度経度形式に焼き込んだもの
+    sky: EquirectangularMap,
+    sun_direction: Vector3,
+    sun_cos_theta_max: f64,
+    sun_radiance: Color,
+    // sample_direction で太陽を選ぶ確率
+    sun_probability: f64,
+}
+
+const PHYSICAL_SKY_WIDTH: usize = 512;
+const PHYSICAL_SKY_HEIGHT: usize = 256;
+
+// 1 kcd/m^2 あたりの放射輝度
+// 直射日光（約 100 klx）を受ける白い拡散面の放射輝度がおよそ 1 になるようにする
+const PHYSICAL_SKY_LUMINANCE_SCALE: f64 = 0.03;
+
+// 太陽の視半径（ラジアン）と大気圏外での照度（klx）
+const SUN_ANGULAR_RADIUS: f64 = 0.004_65;
+const SUN_ILLUMINANCE: f64 = 128.0;
+
+impl PhysicalSky {
+    // turbidity は大気の濁り（2 で快晴、10 で霞がかった空）
+    // sun_intensity は太陽の明るさの倍率で、0 にすると空だけになる
+    pub fn new(sun_direction: &Vector3, turbidity: f64, ground_albedo: &Color, intensity: &Vector3, sun_intensity: f64) -> PhysicalSky {
+        let sun_direction = sun_direction.normalize();
+        // 太陽が地平線より下にある場合は、地平線上の太陽として空を計算し、太陽自体は描かない
+        let sun_visible = sun_direction.y > 0.0;
+        let sky_sun_direction = if sun_visible {
+            sun_direction
+        } else {
+            Vector3::new(sun_direction.x, 0.0, sun_direction.z).normalize()
+        };
+        let preetham = Preetham::new(&sky_sun_direction, turbidity);
+
+        let sun_solid_angle = config::PI2 * (1.0 - SUN_ANGULAR_RADIUS.cos());
+        let sun_radiance = if sun_visible {
+            sun_transmittance(sun_direction.y.acos(), turbidity) * (SUN_ILLUMINANCE / sun_solid_angle * PHYSICAL_SKY_LUMINANCE_SCALE * sun_intensity)
+        } else {
+            Color::zero()
+        };
+
+        // 上半分に空を焼き込みながら、水平な地面が空から受ける放射照度を求める
+        let (width, height) = (PHYSICAL_SKY_WIDTH, PHYSICAL_SKY_HEIGHT);
+        let mut pixels = vec![Color::zero(); width * height];
+        let mut irradiance = sun_radiance * sun_solid_angle * sun_direction.y.max(0.0);
+        for y in 0..height / 2 {
+            let theta0 = config::PI * y as f64 / height as f64;
+            let theta1 = config::PI * (y + 1) as f64 / height as f64;
+            let solid_angle = config::PI2 / width as f64 * (theta0.cos() - theta1.cos());
+            for x in 0..width {
+                let direction = equirectangular_direction((x as f64 + 0.5) / width as f64, (y as f64 + 0.5) / height as f64);
+                let radiance = preetham.radiance(&direction);
+                irradiance += radiance * solid_angle * direction.y;
+                pixels[y * width + x] = radiance;
+            }
+        }
+        let ground = *ground_albedo * irradiance / config::PI;
+        for pixel in &mut pixels[width * height / 2..] {
+            *pixel = ground;
+        }
+
+        let sky = EquirectangularMap::new(pixels, width, height, intensity, 0.0);
+        let sun_radiance = sun_radiance * *intensity;
+        let sun_power = color_to_luminance(&sun_radiance) * sun_solid_angle;
+        let total_power = sky.luminance_integral() + sun_power;
+        PhysicalSky {
+            sky,
+            sun_direction,
+            sun_cos_theta_max: SUN_ANGULAR_RADIUS.cos(),
+            sun_radiance,
+            sun_probability: if total_power > 0.0 { sun_power / total_power } else { 0.0 },
+        }
+    }
+
+    fn sun_solid_angle(&self) -> f64 {
+        config::PI2 * (1.0 - self.sun_cos_theta_max)
+    }
+}
+
+impl Environment for PhysicalSky {
+    fn sample(&self, direction: &Vector3) -> Color {
+        let sky = self.sky.sample(direction);
+        if direction.dot(&self.sun_direction) >= self.sun_cos_theta_max {
+            sky + self.sun_radiance
+        } else {
+            sky
+        }
+    }
+
+    // 太陽の視直径の円錐内か、空の輝度に比例した分布のどちらかからサンプリングする
+    fn sample_direction(&self, random: (f64, f64)) -> (Vector3, f64) {
+        let p = self.sun_probability;
+        let direction = if random.0 < p {
+            let u = (random.0 / p).min(1.0 - f64::EPSILON);
+            let cos_theta = 1.0 - u * (1.0 - self.sun_cos_theta_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+            let phi = config::PI2 * random.1;
+            let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(&self.sun_direction);
+            (tangent * phi.cos() + binormal * phi.sin()) * sin_theta + self.sun_direction * cos_theta
+        } else {
+            let u = ((random.0 - p) / (1.0 - p)).min(1.0 - f64::EPSILON);
+            self.sky.sample_direction((u, random.1)).0
+        };
+        (direction, self.pdf(&direction))
+    }
+
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        let sun_pdf = if direction.dot(&self.sun_direction) >= self.sun_cos_theta_max {
+            self.sun_solid_angle().recip()
+        } else {
+            0.0
+        };
+        self.sun_probability * sun_pdf + (1.0 - self.sun_probability) * self.sky.pdf(direction)
+    }
+
+    fn luminance_integral(&self) -> f64 {
+        self.sky.luminance_integral() + color_to_luminance(&self.sun_radiance) * self.sun_solid_angle()
+    }
+}
+
+// Perez の輝度分布の係数と天頂の値から、輝度 Y と色度 x, y を求める
+struct Preetham {
+    sun_direction: Vector3,
+    // Y, x, y それぞれの Perez の係数 A〜E と、天頂での値を F(0, θs) で割ったもの
+    coefficients: [[f64; 5]; 3],
+    zenith: [f64; 3],
+}
+
+impl Preetham {
+    fn new(sun_direction: &Vector3, turbidity: f64) -> Preetham {
+        let t = turbidity;
+        let theta_s = clamp(sun_direction.y, 0.0, 1.0).acos();
+        let coefficients = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+        ];
+
+        // 天頂の輝度（kcd/m^2）と色度
+        let chi = (4.0 / 9.0 - t / 120.0) * (config::PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let theta = [theta_s * theta_s * theta_s, theta_s * theta_s, theta_s, 1.0];
+        let polynomial = |m: [[f64; 4]; 3]| {
+            let row = |r: [f64; 4]| r.iter().zip(&theta).map(|(a, b)| a * b).sum::<f64>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = polynomial([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = polynomial([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let mut zenith = [zenith_luminance, zenith_x, zenith_y];
+        for (value, c) in zenith.iter_mut().zip(&coefficients) {
+            *value /= perez(0.0, theta_s, c);
+        }
+        Preetham { sun_direction: *sun_direction, coefficients, zenith }
+    }
+
+    // 地平線より上の direction の放射輝度
+    fn radiance(&self, direction: &Vector3) -> Color {
+        let theta = clamp(direction.y, 0.0, 1.0).acos();
+        let gamma = clamp(direction.dot(&self.sun_direction), -1.0, 1.0).acos();
+        let luminance = self.zenith[0] * perez(theta, gamma, &self.coefficients[0]);
+        let x = self.zenith[1] * perez(theta, gamma, &self.coefficients[1]);
+        let y = self.zenith[2] * perez(theta, gamma, &self.coefficients[2]);
+
+        // xyY から XYZ を経由してリニアな sRGB に変換する
+        let big_x = x / y * luminance;
+        let big_z = (1.0 - x - y) / y * luminance;
+        let rgb = Color::new(
+            3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z,
+            -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z,
+            0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z,
+        );
+        Color::new(rgb.x.max(0.0), rgb.y.max(0.0), rgb.z.max(0.0)) * PHYSICAL_SKY_LUMINANCE_SCALE
+    }
+}
+
+// 天頂角 theta、太陽との角度 gamma の方向の Perez の輝度分布
+fn perez(theta: f64, gamma: f64, c: &[f64; 5]) -> f64 {
+    let cos_gamma = gamma.cos();
+    (1.0 + c[0] * (c[1] / theta.cos().max(config::EPS)).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+// 大気を通過した太陽光の透過率（Rayleigh 散乱とエアロゾルによる減衰のみを考える）
+// 大気の厚さは Kasten の近似式、RGB の波長はそれぞれ 650nm, 550nm, 450nm とする
+fn sun_transmittance(theta_s: f64, turbidity: f64) -> Color {
+    let air_mass = (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253)).recip();
+    let beta = 0.04608 * turbidity - 0.04586;
+    let transmittance = |wavelength: f64| {
+        let rayleigh = 0.008735 * wavelength.powf(-4.08);
+        let aerosol = beta * wavelength.powf(-1.3);
+        (-air_mass * (rayleigh + aerosol)).exp()
+    };
+    Color::new(transmittance(0.65), transmittance(0.55), transmittance(0.45))
+}
+
+// 回転しない場合の方向と緯度経度形式の画像上の座標の変換
+fn equirectangular_uv(direction: &Vector3) -> (f64, f64) {
+    let u = 0.5 + direction.x.atan2(-direction.z) / config::PI2;
+    let v = clamp(direction.y, -1.0, 1.0).acos() / config::PI;
+    (u, v)
+}
+
+fn equirectangular_direction(u: f64, v: f64) -> Vector3 {
+    let phi = (u - 0.5) * config::PI2;
+    let theta = v * config::PI;
+    Vector3::new(theta.sin() * phi.sin(), theta.cos(), -theta.sin() * phi.cos())
+}
+
+// y 軸まわりに angle だけ回転する
+fn rotate_y(v: &Vector3, angle: f64) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    Vector3::new(v.x * cos + v.z * sin, v.y, -v.x * sin + v.z * cos)
+}
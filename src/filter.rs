@@ -1,6 +1,7 @@
 extern crate image;
 
 use config;
+use config::RenderSettings;
 use math;
 use vector::Vector3;
 
@@ -22,11 +23,11 @@ fn index_to_xy(index: usize, width: u32) -> (u32, u32) {
     (index as u32 % width, index as u32 / width)
 }
 
-pub fn execute(pixel: &Vector3, current: usize, img: &Vec<Vector3>, width: u32, height: u32) -> Vector3 {
+pub fn execute(pixel: &Vector3, current: usize, img: &Vec<Vector3>, width: u32, height: u32, settings: &RenderSettings) -> Vector3 {
     bilateral(pixel, current, img, width, height,
-              config::BILATERAL_FILTER_DIAMETER,
-              config::BILATERAL_FILTER_SIGMA_I,
-              config::BILATERAL_FILTER_SIGMA_S)
+              settings.bilateral_filter_diameter,
+              settings.bilateral_filter_sigma_i,
+              settings.bilateral_filter_sigma_s)
 }
 
 fn bilateral(pixel: &Vector3, current: usize, img: &Vec<Vector3>, width: u32, height: u32, diameter: u32, sigma_i: f64, sigma_s: f64) -> Vector3 {
@@ -0,0 +1,427 @@
+extern crate image;
+extern crate inflate;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use image::Rgb;
+use image::hdr::{HDREncoder, HDRDecoder};
+
+use color::Color;
+
+pub enum HdrFormat {
+    Pfm,
+    Radiance,
+    Exr,
+}
+
+impl HdrFormat {
+    // フォーマットは拡張子で判定する
+    pub fn from_path(path: &str) -> Result<HdrFormat, String> {
+        let extension = Path::new(path).extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("pfm") => Ok(HdrFormat::Pfm),
+            Some("hdr") => Ok(HdrFormat::Radiance),
+            Some("exr") => Ok(HdrFormat::Exr),
+            _ => Err(format!("unsupported HDR format `{}` (pfm, hdr, exr)", path)),
+        }
+    }
+}
+
+// トーンマッピング前のリニアな色を浮動小数点の画像として保存する
+// pixels は左上から右下への行優先の並び
+pub fn save(path: &str, pixels: &[Color], width: u32, height: u32) -> io::Result<()> {
+    let format = HdrFormat::from_path(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let f = File::create(path)?;
+    let mut w = BufWriter::new(f);
+
+    match format {
+        HdrFormat::Pfm => write_pfm(&mut w, pixels, width, height)?,
+        HdrFormat::Radiance => write_radiance_hdr(&mut w, pixels, width, height)?,
+        HdrFormat::Exr => write_exr(&mut w, pixels, width, height)?,
+    }
+    w.flush()
+}
+
+// 浮動小数点の画像を読み込んで、左上から右下への行優先の並びのリニアな色と幅・高さを返す
+pub fn load(path: &str) -> io::Result<(Vec<Color>, u32, u32)> {
+    let format = HdrFormat::from_path(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut r = BufReader::new(File::open(path)?);
+
+    match format {
+        HdrFormat::Pfm => read_pfm(&mut r),
+        HdrFormat::Radiance => read_radiance_hdr(r),
+        HdrFormat::Exr => read_exr(&mut r),
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Portable Float Map
+// スケールが負の値のときはリトルエンディアン。行は下から上へ並べる
+fn write_pfm<W: Write>(w: &mut W, pixels: &[Color], width: u32, height: u32) -> io::Result<()> {
+    write!(w, "PF\n{} {}\n-1.0\n", width, height)?;
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let pixel = pixels[(y * width + x) as usize];
+            write_f32(w, pixel.x as f32)?;
+            write_f32(w, pixel.y as f32)?;
+            write_f32(w, pixel.z as f32)?;
+        }
+    }
+    Ok(())
+}
+
+// ヘッダは空白区切りの3つのトークンで、その後の1文字の空白の次から画素が並ぶ
+// グレースケール（Pf）にも対応する
+fn read_pfm<R: Read>(r: &mut R) -> io::Result<(Vec<Color>, u32, u32)> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while tokens.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let begin = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if begin == pos {
+            return Err(invalid_data("truncated PFM header".to_string()));
+        }
+        tokens.push(String::from_utf8_lossy(&data[begin..pos]).into_owned());
+    }
+    pos += 1;
+
+    let channels = match tokens[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid_data("not a PFM file".to_string())),
+    };
+    let invalid_header = |token: &str| invalid_data(format!("invalid PFM header `{}`", token));
+    let parse_size = |token: &str| match token.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(invalid_header(token)),
+    };
+    let width = parse_size(&tokens[1])?;
+    let height = parse_size(&tokens[2])?;
+    let little_endian = tokens[3].parse::<f64>().map_err(|_| invalid_header(&tokens[3]))? < 0.0;
+
+    // 巨大なヘッダでも桁あふれしないように、画素の大きさを確かめてから確保する
+    let end = width.checked_mul(height)
+        .and_then(|n| n.checked_mul(channels * 4))
+        .and_then(|n| n.checked_add(pos));
+    match end {
+        Some(end) if end <= data.len() => {}
+        _ => return Err(invalid_data("truncated PFM data".to_string())),
+    }
+    let value = |index: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&data[pos + index * 4..pos + index * 4 + 4]);
+        f64::from(if little_endian { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) })
+    };
+
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let index = (y * width + x) * channels;
+            pixels.push(if channels == 3 {
+                Color::new(value(index), value(index + 1), value(index + 2))
+            } else {
+                Color::from_one(value(index))
+            });
+        }
+    }
+    Ok((pixels, width as u32, height as u32))
+}
+
+// Radiance HDR (RGBE)
+fn write_radiance_hdr<W: Write>(w: &mut W, pixels: &[Color], width: u32, height: u32) -> io::Result<()> {
+    let data: Vec<_> = pixels.iter().map(|pixel| {
+        Rgb([pixel.x as f32, pixel.y as f32, pixel.z as f32])
+    }).collect();
+    HDREncoder::new(w).encode(&data, width as usize, height as usize)
+}
+
+fn read_radiance_hdr<R: io::BufRead>(r: R) -> io::Result<(Vec<Color>, u32, u32)> {
+    let decoder = HDRDecoder::new(r).map_err(|e| invalid_data(e.to_string()))?;
+    let metadata = decoder.metadata();
+    let data = decoder.read_image_hdr().map_err(|e| invalid_data(e.to_string()))?;
+    let pixels = data.iter().map(|pixel| {
+        Color::new(f64::from(pixel[0]), f64::from(pixel[1]), f64::from(pixel[2]))
+    }).collect();
+    Ok((pixels, metadata.width, metadata.height))
+}
+
+// OpenEXR（非圧縮・スキャンライン・32bit float）
+// http://www.openexr.com/openexrfilelayout.pdf
+fn write_exr<W: Write>(w: &mut W, pixels: &[Color], width: u32, height: u32) -> io::Result<()> {
+    // magic number と version（single-part scanline）
+    w.write_all(&[0x76, 0x2f, 0x31, 0x01])?;
+    write_i32(w, 2)?;
+
+    // チャンネルは名前の昇順に並べる必要がある
+    let mut channels = Vec::new();
+    for name in &["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&2i32.to_le_bytes());// pixel type: FLOAT
+        channels.extend_from_slice(&[0, 0, 0, 0]);// pLinear, reserved
+        channels.extend_from_slice(&1i32.to_le_bytes());// x sampling
+        channels.extend_from_slice(&1i32.to_le_bytes());// y sampling
+    }
+    channels.push(0);
+
+    let mut window = Vec::new();
+    for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    let mut screen_window_center = Vec::new();
+    screen_window_center.extend_from_slice(&0.0f32.to_le_bytes());
+    screen_window_center.extend_from_slice(&0.0f32.to_le_bytes());
+
+    let mut header = Vec::new();
+    write_exr_attribute(&mut header, "channels", "chlist", &channels)?;
+    write_exr_attribute(&mut header, "compression", "compression", &[0])?;// NO_COMPRESSION
+    write_exr_attribute(&mut header, "dataWindow", "box2i", &window)?;
+    write_exr_attribute(&mut header, "displayWindow", "box2i", &window)?;
+    write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0])?;// INCREASING_Y
+    write_exr_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes())?;
+    write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &screen_window_center)?;
+    write_exr_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes())?;
+    header.push(0);
+    w.write_all(&header)?;
+
+    // オフセットテーブル（非圧縮なので1ブロック1スキャンライン）
+    let line_size = 4 * 3 * width as u64;
+    let block_size = 4 + 4 + line_size;
+    let table_begin = 4 + 4 + header.len() as u64;
+    let blocks_begin = table_begin + 8 * height as u64;
+    for y in 0..height as u64 {
+        w.write_all(&(blocks_begin + y * block_size).to_le_bytes())?;
+    }
+
+    for y in 0..height {
+        write_i32(w, y as i32)?;
+        write_i32(w, line_size as i32)?;
+        let line = &pixels[(y * width) as usize..((y + 1) * width) as usize];
+        for pixel in line { write_f32(w, pixel.z as f32)?; }
+        for pixel in line { write_f32(w, pixel.y as f32)?; }
+        for pixel in line { write_f32(w, pixel.x as f32)?; }
+    }
+    Ok(())
+}
+
+fn write_exr_attribute<W: Write>(w: &mut W, name: &str, type_name: &str, value: &[u8]) -> io::Result<()> {
+    w.write_all(name.as_bytes())?;
+    w.write_all(&[0])?;
+    w.write_all(type_name.as_bytes())?;
+    w.write_all(&[0])?;
+    write_i32(w, value.len() as i32)?;
+    w.write_all(value)
+}
+
+fn write_f32<W: Write>(w: &mut W, v: f32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_i32<W: Write>(w: &mut W, v: i32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+// OpenEXR（single-part スキャンライン。圧縮は NONE / ZIPS / ZIP、チャンネルは R G B または Y）
+fn read_exr<R: Read>(r: &mut R) -> io::Result<(Vec<Color>, u32, u32)> {
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let mut r = ExrReader { data: &data, pos: 0 };
+
+    if r.take(4)? != [0x76, 0x2f, 0x31, 0x01] {
+        return Err(invalid_data("not an OpenEXR file".to_string()));
+    }
+    let version = r.read_i32()?;
+    // tiled, deep, multi-part のフラグ
+    if version & 0xff != 2 || version & (0x200 | 0x800 | 0x1000) != 0 {
+        return Err(invalid_data("only single-part scanline OpenEXR is supported".to_string()));
+    }
+
+    let mut channels = Vec::new();
+    let mut compression = None;
+    let mut window = None;
+    loop {
+        let name = r.read_string()?;
+        if name.is_empty() {
+            break;
+        }
+        let _type_name = r.read_string()?;
+        let size = r.read_i32()? as usize;
+        let mut value = ExrReader { data: r.take(size)?, pos: 0 };
+        match name.as_str() {
+            "channels" => loop {
+                let channel_name = value.read_string()?;
+                if channel_name.is_empty() {
+                    break;
+                }
+                let pixel_type = value.read_i32()?;
+                value.take(4)?;// pLinear, reserved
+                if value.read_i32()? != 1 || value.read_i32()? != 1 {
+                    return Err(invalid_data("subsampled OpenEXR channels are not supported".to_string()));
+                }
+                channels.push((channel_name, pixel_type));
+            },
+            "compression" => compression = Some(value.take(1)?[0]),
+            "dataWindow" => window = Some((value.read_i32()?, value.read_i32()?, value.read_i32()?, value.read_i32()?)),
+            _ => {}
+        }
+    }
+
+    let (xmin, ymin, xmax, ymax) = window.ok_or_else(|| invalid_data("missing OpenEXR dataWindow".to_string()))?;
+    if xmax < xmin || ymax < ymin {
+        return Err(invalid_data("empty OpenEXR dataWindow".to_string()));
+    }
+    // i32 の範囲いっぱいの dataWindow でも桁あふれしないように i64 で求める
+    let width = (i64::from(xmax) - i64::from(xmin) + 1) as usize;
+    let height = (i64::from(ymax) - i64::from(ymin) + 1) as usize;
+    let lines_per_block = match compression {
+        Some(0) | Some(2) => 1,// NO_COMPRESSION, ZIPS_COMPRESSION
+        Some(3) => 16,// ZIP_COMPRESSION
+        _ => return Err(invalid_data("unsupported OpenEXR compression (none, zips, zip)".to_string())),
+    };
+
+    let find = |name: &str| channels.iter().position(|c| c.0 == name);
+    let rgb = match (find("R"), find("G"), find("B"), find("Y")) {
+        (Some(r), Some(g), Some(b), _) => [r, g, b],
+        (_, _, _, Some(y)) => [y, y, y],
+        _ => return Err(invalid_data("OpenEXR has no R G B or Y channels".to_string())),
+    };
+    // UINT, FLOAT は 4 バイト、HALF は 2 バイト
+    let channel_sizes: Vec<usize> = channels.iter().map(|c| if c.1 == 1 { 2 } else { 4 }).collect();
+    let pixel_size = channel_sizes.iter().sum::<usize>();
+
+    // 画素を確保する前に、dataWindow がファイルの大きさに収まるか確かめる
+    // 展開後の大きさの上限は、非圧縮ならファイルの大きさ、ZIP なら deflate の最大の圧縮率（約 1032 倍）から求める
+    let max_unpacked_size = if compression == Some(0) { data.len() } else { data.len().saturating_mul(1032) };
+    match width.checked_mul(height).and_then(|n| n.checked_mul(pixel_size)) {
+        Some(size) if size <= max_unpacked_size => {}
+        _ => return Err(invalid_data("OpenEXR dataWindow is too large for the file".to_string())),
+    }
+    let line_size = width * pixel_size;
+
+    let block_count = height.div_ceil(lines_per_block);
+    let mut offsets = Vec::with_capacity(block_count);
+    for _ in 0..block_count {
+        offsets.push(r.read_u64()? as usize);
+    }
+
+    let mut pixels = vec![Color::zero(); width * height];
+    for offset in offsets {
+        let mut block = ExrReader { data: &data, pos: offset };
+        let y = i64::from(block.read_i32()?) - i64::from(ymin);
+        if y < 0 || y >= height as i64 {
+            return Err(invalid_data("OpenEXR block is outside dataWindow".to_string()));
+        }
+        let y = y as usize;
+        let size = block.read_i32()? as usize;
+        let lines = lines_per_block.min(height.saturating_sub(y));
+        let packed = block.take(size)?;
+        // 圧縮しても小さくならなかったブロックはそのまま格納されている
+        let unpacked = if size < lines * line_size { unzip_exr_block(packed)? } else { packed.to_vec() };
+        if unpacked.len() < lines * line_size {
+            return Err(invalid_data("truncated OpenEXR block".to_string()));
+        }
+
+        for line in 0..lines {
+            let mut pos = line * line_size;
+            let mut values = vec![vec![0.0; width]; channels.len()];
+            for (c, channel) in channels.iter().enumerate() {
+                for value in &mut values[c] {
+                    let bytes = &unpacked[pos..pos + channel_sizes[c]];
+                    *value = match channel.1 {
+                        0 => f64::from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                        1 => f64::from(half_to_f32(u16::from_le_bytes([bytes[0], bytes[1]]))),
+                        _ => f64::from(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+                    };
+                    pos += channel_sizes[c];
+                }
+            }
+            for x in 0..width {
+                pixels[(y + line) * width + x] = Color::new(values[rgb[0]][x], values[rgb[1]][x], values[rgb[2]][x]);
+            }
+        }
+    }
+    Ok((pixels, width as u32, height as u32))
+}
+
+// ZIP 圧縮を展開して、バイトの予測符号化と並べ替えを元に戻す
+fn unzip_exr_block(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut t = inflate::inflate_bytes_zlib(data).map_err(invalid_data)?;
+    for i in 1..t.len() {
+        t[i] = t[i - 1].wrapping_add(t[i]).wrapping_sub(128);
+    }
+
+    // 偶数番目のバイトが前半に、奇数番目のバイトが後半に分けられている
+    let half = t.len().div_ceil(2);
+    let mut out = Vec::with_capacity(t.len());
+    for i in 0..half {
+        out.push(t[i]);
+        if half + i < t.len() {
+            out.push(t[half + i]);
+        }
+    }
+    Ok(out)
+}
+
+// IEEE 754 の半精度浮動小数点数
+fn half_to_f32(h: u16) -> f32 {
+    let sign = if h & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = i32::from((h >> 10) & 0x1f);
+    let mantissa = f32::from(h & 0x3ff);
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        31 => if mantissa == 0.0 { sign * f32::INFINITY } else { f32::NAN },
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+struct ExrReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ExrReader<'a> {
+    fn take(&mut self, size: usize) -> io::Result<&'a [u8]> {
+        let end = match self.pos.checked_add(size) {
+            Some(end) if end <= self.data.len() => end,
+            _ => return Err(invalid_data("unexpected end of OpenEXR file".to_string())),
+        };
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_i32(&mut self) -> io::Result<i32> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // null 終端の文字列
+    fn read_string(&mut self) -> io::Result<String> {
+        let length = self.data.get(self.pos..).and_then(|rest| rest.iter().position(|&b| b == 0))
+            .ok_or_else(|| invalid_data("unexpected end of OpenEXR file".to_string()))?;
+        let bytes = self.take(length + 1)?;
+        Ok(String::from_utf8_lossy(&bytes[..length]).into_owned())
+    }
+}
@@ -0,0 +1,466 @@
+use std::str::FromStr;
+
+use config;
+use vector::Vector3;
+use scene::{SceneTrait, Intersectable};
+use environment::Environment;
+use bvh::Aabb;
+use math::{Distribution1D, clamp};
+use color::{Color, color_to_luminance};
+
+// NEE で光源を選ぶ方法
+#[derive(Clone, Debug)]
+pub enum LightSampling {
+    // すべての光源をサンプリングする
+    All,
+    // 推定した放射束に比例した確率で 1 つ選ぶ
+    Power,
+    // 光源の BVH をたどって、近くて明るい光源ほど高い確率で 1 つ選ぶ
+    Bvh,
+}
+
+impl FromStr for LightSampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LightSampling, String> {
+        match s {
+            "all" => Ok(LightSampling::All),
+            "power" => Ok(LightSampling::Power),
+            "bvh" => Ok(LightSampling::Bvh),
+            _ => Err(format!("unknown light sampling `{}` (all, power, bvh)", s)),
+        }
+    }
+}
+
+// origin から光源をサンプリングした結果
+pub struct LightSample {
+    pub direction: Vector3,
+    // 遮蔽を判定する距離
+    pub distance: f64,
+    pub emission: Color,
+    // 立体角測度の確率密度
+    // 点光源などのデルタ分布の光源では無限大とし、emission には origin での放射照度（放射強度 / 距離^2）を入れる
+    pub pdf: f64,
+}
+
+// 位置や向きだけを持ち、形状を持たない光源
+// BSDF サンプリングで到達することはないので、NEE でだけ扱う
+#[derive(Clone, Debug)]
+pub enum PunctualLight {
+    // 全方向に同じ放射強度 intensity で放射する点光源
+    Point {
+        position: Vector3,
+        intensity: Color,
+    },
+    // direction を中心に、角度の余弦が cos_inner 以上では intensity、cos_outer 以下では 0 で、その間を滑らかに減衰させるスポットライト
+    Spot {
+        position: Vector3,
+        direction: Vector3,
+        intensity: Color,
+        cos_inner: f64,
+        cos_outer: f64,
+    },
+    // direction の向きに進む平行光。irradiance は光に垂直な面での放射照度
+    Directional {
+        direction: Vector3,
+        irradiance: Color,
+    },
+}
+
+impl PunctualLight {
+    fn sample(&self, origin: &Vector3) -> Option<LightSample> {
+        let (position, intensity) = match *self {
+            PunctualLight::Point { position, intensity } => (position, intensity),
+            PunctualLight::Spot { position, direction, intensity, cos_inner, cos_outer } => {
+                let cos_theta = (*origin - position).normalize().dot(&direction);
+                (position, intensity * PunctualLight::spot_falloff(cos_theta, cos_inner, cos_outer))
+            }
+            PunctualLight::Directional { direction, irradiance } => {
+                return Some(LightSample { direction: -direction, distance: config::INF, emission: irradiance, pdf: f64::INFINITY });
+            }
+        };
+
+        if intensity == Color::zero() {
+            return None;
+        }
+        let shadow_vec = position - *origin;
+        let distance = shadow_vec.length();
+        if distance == 0.0 {
+            return None;
+        }
+        Some(LightSample {
+            direction: shadow_vec / distance,
+            distance,
+            emission: intensity / (distance * distance),
+            pdf: f64::INFINITY,
+        })
+    }
+
+    fn spot_falloff(cos_theta: f64, cos_inner: f64, cos_outer: f64) -> f64 {
+        if cos_inner <= cos_outer {
+            return if cos_theta >= cos_outer { 1.0 } else { 0.0 };
+        }
+        let t = clamp((cos_theta - cos_outer) / (cos_inner - cos_outer), 0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    // 光源の BVH に入れる位置。平行光は位置を持たない
+    fn position(&self) -> Option<Vector3> {
+        match *self {
+            PunctualLight::Point { position, .. } | PunctualLight::Spot { position, .. } => Some(position),
+            PunctualLight::Directional { .. } => None,
+        }
+    }
+
+    // 放射束の見積もり（共通の係数 π は省く）
+    // スポットライトの減衰する範囲は放射強度の半分として扱う
+    fn power(&self, scene_radius: f64) -> f64 {
+        match *self {
+            PunctualLight::Point { intensity, .. } => color_to_luminance(&intensity) * 4.0,
+            PunctualLight::Spot { intensity, cos_inner, cos_outer, .. } => {
+                let cos_inner = cos_inner.max(cos_outer);
+                color_to_luminance(&intensity) * 2.0 * ((1.0 - cos_inner) + (cos_inner - cos_outer) * 0.5)
+            }
+            PunctualLight::Directional { irradiance, .. } => color_to_luminance(&irradiance) * scene_radius * scene_radius,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Light<'a> {
+    // Scene::elements の中の光源
+    Element(&'a Intersectable),
+    // 無限遠の環境光
+    Environment(&'a Environment),
+    // 点光源・スポットライト・平行光
+    Punctual(&'a PunctualLight),
+}
+
+impl<'a> Light<'a> {
+    // origin から見た光源上の点をサンプリングする。サンプリングできない場合は None
+    pub fn sample(&self, origin: &Vector3, random: (f64, f64)) -> Option<LightSample> {
+        match *self {
+            Light::Element(element) => {
+                let surface = element.sample_on_surface(origin, random);
+                // origin から光源の面が見えない場合はサンプリングできない
+                if surface.pdf == 0.0 {
+                    return None;
+                }
+                let shadow_vec = surface.position - *origin;
+                let shadow_distance = shadow_vec.length();
+                Some(LightSample {
+                    direction: shadow_vec / shadow_distance,
+                    // 光源自身との交差を除くため、サンプリングした点の手前までを判定する
+                    distance: shadow_distance - config::OFFSET,
+                    emission: surface.emission,
+                    pdf: surface.pdf,
+                })
+            }
+            Light::Environment(environment) => {
+                let (direction, pdf) = environment.sample_direction(random);
+                if pdf == 0.0 {
+                    return None;
+                }
+                Some(LightSample { direction, distance: config::INF, emission: environment.sample(&direction), pdf })
+            }
+            Light::Punctual(light) => light.sample(origin),
+        }
+    }
+
+    // 光源を選ぶ確率に使う放射束の見積もり（共通の係数 π は省く）
+    // 面光源は輝度の平均 × 表面積、環境光はシーンを囲む球の断面積 × 輝度の立体角での積分とする
+    fn power(&self, scene_radius: f64) -> f64 {
+        match *self {
+            Light::Element(element) => color_to_luminance(&element.material().emission.average()) * element.surface_area(),
+            Light::Environment(environment) => environment.luminance_integral() * scene_radius * scene_radius,
+            Light::Punctual(light) => light.power(scene_radius),
+        }
+    }
+
+    // 環境光と平行光は無限遠にある
+    fn is_infinite(&self) -> bool {
+        match *self {
+            Light::Element(_) => false,
+            Light::Environment(_) => true,
+            Light::Punctual(light) => light.position().is_none(),
+        }
+    }
+
+    // 光源の BVH に入れる範囲。無限遠にある光源は None
+    fn aabb(&self) -> Option<Aabb> {
+        match *self {
+            Light::Element(element) => Some(element.aabb()),
+            Light::Environment(_) => None,
+            Light::Punctual(light) => light.position().map(|position| Aabb { min: position, max: position }),
+        }
+    }
+}
+
+pub struct LightSampler<'a> {
+    method: LightSampling,
+    lights: Vec<Light<'a>>,
+    // Scene::elements のインデックスから lights のインデックスへの対応
+    light_indexes: Vec<Option<usize>>,
+    // 環境光の lights 上のインデックス
+    environment: Option<usize>,
+    distribution: Distribution1D,
+    // 環境光と平行光は BVH に含めず、BVH をたどる前にこの確率で選ぶ
+    infinite_probability: f64,
+    // BVH に含めない光源の lights 上のインデックスと、その中から選ぶ分布
+    infinite_lights: Vec<usize>,
+    infinite_distribution: Distribution1D,
+    bvh: Vec<LightNode>,
+    // lights のインデックスから、その光源を持つ bvh の葉、または infinite_lights へのインデックス
+    leaves: Vec<usize>,
+}
+
+impl<'a> LightSampler<'a> {
+    pub fn new(scene: &'a SceneTrait, method: &LightSampling) -> LightSampler<'a> {
+        let mut light_indexes = Vec::new();
+        let mut lights = Vec::new();
+        for (element_index, element) in scene.emissions() {
+            if light_indexes.len() <= element_index {
+                light_indexes.resize(element_index + 1, None);
+            }
+            light_indexes[element_index] = Some(lights.len());
+            lights.push(Light::Element(element.as_ref()));
+        }
+
+        let environment = if scene.environment().luminance_integral() > 0.0 {
+            lights.push(Light::Environment(scene.environment()));
+            Some(lights.len() - 1)
+        } else {
+            None
+        };
+        for light in scene.punctual_lights() {
+            lights.push(Light::Punctual(light));
+        }
+
+        let aabb = scene.aabb();
+        let scene_radius = if aabb.min.x <= aabb.max.x { (aabb.max - aabb.min).length() * 0.5 } else { 0.0 };
+        let mut powers: Vec<_> = lights.iter().map(|light| light.power(scene_radius)).collect();
+        if !powers.iter().any(|&p| p > 0.0) {
+            powers = vec![1.0; lights.len()];
+        }
+
+        let mut bvh = Vec::new();
+        let mut leaves = vec![0; lights.len()];
+        let mut infinite_lights = Vec::new();
+        let mut infinite_powers = Vec::new();
+        let mut infinite_probability = 0.0;
+        if let LightSampling::Bvh = *method {
+            let mut primitives = Vec::new();
+            for (index, (light, &power)) in lights.iter().zip(&powers).enumerate() {
+                match light.aabb() {
+                    Some(aabb) => primitives.push(LightPrimitive { light: index, aabb, power }),
+                    None => {
+                        leaves[index] = infinite_lights.len();
+                        infinite_lights.push(index);
+                        infinite_powers.push(power);
+                    }
+                }
+            }
+            let bvh_power: f64 = primitives.iter().map(|primitive| primitive.power).sum();
+            if !primitives.is_empty() {
+                LightNode::build(primitives, None, &mut bvh, &mut leaves);
+            }
+            if !infinite_lights.is_empty() {
+                let infinite_power: f64 = infinite_powers.iter().sum();
+                infinite_probability = if bvh.is_empty() { 1.0 } else { infinite_power / (infinite_power + bvh_power) };
+                if !infinite_powers.iter().any(|&p| p > 0.0) {
+                    infinite_powers = vec![1.0; infinite_lights.len()];
+                }
+            }
+        }
+
+        LightSampler {
+            method: method.clone(),
+            lights,
+            light_indexes,
+            environment,
+            distribution: Distribution1D::new(&powers),
+            infinite_probability,
+            infinite_lights,
+            infinite_distribution: Distribution1D::new(&infinite_powers),
+            bvh,
+            leaves,
+        }
+    }
+
+    // origin から NEE を行う光源を選んで、光源・選ばれた確率・引き伸ばした乱数を f に渡す
+    // All の場合はすべての光源を確率 1 で渡す
+    pub fn select<F>(&self, origin: &Vector3, u: f64, mut f: F) where F: FnMut(Light, f64, f64) {
+        if self.lights.is_empty() {
+            return;
+        }
+
+        match self.method {
+            LightSampling::All => {
+                for light in &self.lights {
+                    f(*light, 1.0, u);
+                }
+            }
+            LightSampling::Power => {
+                let (index, u) = self.distribution.sample(u);
+                f(self.lights[index], self.distribution.probability(index), u);
+            }
+            LightSampling::Bvh => {
+                let mut u = u;
+                let mut probability = 1.0;
+                if !self.infinite_lights.is_empty() {
+                    let p = self.infinite_probability;
+                    if u < p {
+                        let (index, u) = self.infinite_distribution.sample((u / p).min(1.0 - f64::EPSILON));
+                        f(self.lights[self.infinite_lights[index]], p * self.infinite_distribution.probability(index), u);
+                        return;
+                    }
+                    u = ((u - p) / (1.0 - p)).min(1.0 - f64::EPSILON);
+                    probability = 1.0 - p;
+                }
+
+                let mut index = 0;
+                loop {
+                    match self.bvh[index].kind {
+                        LightNodeKind::Leaf(light) => {
+                            f(self.lights[light], probability, u);
+                            return;
+                        }
+                        LightNodeKind::Interior(right) => {
+                            let p_left = self.left_probability(index, right, origin);
+                            if u < p_left {
+                                u = (u / p_left).min(1.0 - f64::EPSILON);
+                                probability *= p_left;
+                                index += 1;
+                            } else {
+                                u = ((u - p_left) / (1.0 - p_left)).min(1.0 - f64::EPSILON);
+                                probability *= 1.0 - p_left;
+                                index = right;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // origin から NEE を行うときに element_index の要素が光源として選ばれる確率
+    pub fn probability(&self, element_index: usize, origin: &Vector3) -> f64 {
+        match self.light_indexes.get(element_index) {
+            Some(&Some(light)) => self.light_probability(light, origin),
+            _ => 0.0,
+        }
+    }
+
+    // NEE を行うときに環境光が選ばれる確率
+    pub fn environment_probability(&self, origin: &Vector3) -> f64 {
+        match self.environment {
+            Some(light) => self.light_probability(light, origin),
+            None => 0.0,
+        }
+    }
+
+    fn light_probability(&self, light: usize, origin: &Vector3) -> f64 {
+        match self.method {
+            LightSampling::All => 1.0,
+            LightSampling::Power => self.distribution.probability(light),
+            LightSampling::Bvh => {
+                if self.lights[light].is_infinite() {
+                    return self.infinite_probability * self.infinite_distribution.probability(self.leaves[light]);
+                }
+
+                // 葉から根に向かって、各分岐で選ばれる確率を掛けていく
+                let mut probability = 1.0 - self.infinite_probability;
+                let mut index = self.leaves[light];
+                while let Some(parent) = self.bvh[index].parent {
+                    if let LightNodeKind::Interior(right) = self.bvh[parent].kind {
+                        let p_left = self.left_probability(parent, right, origin);
+                        probability *= if index == right { 1.0 - p_left } else { p_left };
+                    }
+                    index = parent;
+                }
+                probability
+            }
+        }
+    }
+
+    fn left_probability(&self, index: usize, right: usize, origin: &Vector3) -> f64 {
+        let left = self.bvh[index + 1].importance(origin);
+        let right = self.bvh[right].importance(origin);
+        if left + right > 0.0 {
+            left / (left + right)
+        } else {
+            0.5
+        }
+    }
+}
+
+struct LightPrimitive {
+    light: usize,
+    aabb: Aabb,
+    power: f64,
+}
+
+enum LightNodeKind {
+    Leaf(usize),
+    // 左の子は直後のノード。右の子のインデックスを持つ
+    Interior(usize),
+}
+
+struct LightNode {
+    aabb: Aabb,
+    power: f64,
+    parent: Option<usize>,
+    kind: LightNodeKind,
+}
+
+impl LightNode {
+    // 重心の範囲が最も広い軸の中央値で分割する
+    fn build(mut primitives: Vec<LightPrimitive>, parent: Option<usize>, nodes: &mut Vec<LightNode>, leaves: &mut Vec<usize>) -> usize {
+        let index = nodes.len();
+        let mut aabb = Aabb::empty();
+        let mut power = 0.0;
+        for primitive in &primitives {
+            aabb.merge(&primitive.aabb);
+            power += primitive.power;
+        }
+
+        if primitives.len() == 1 {
+            let light = primitives[0].light;
+            leaves[light] = index;
+            nodes.push(LightNode { aabb, power, parent, kind: LightNodeKind::Leaf(light) });
+            return index;
+        }
+
+        let mut centroid = Aabb::empty();
+        for primitive in &primitives {
+            let center = primitive.aabb.center();
+            centroid.merge(&Aabb { min: center, max: center });
+        }
+        let d = centroid.max - centroid.min;
+        let axis = if d.x >= d.y && d.x >= d.z { 0 } else if d.y >= d.z { 1 } else { 2 };
+        let key = |primitive: &LightPrimitive| {
+            let center = primitive.aabb.center();
+            match axis {
+                0 => center.x,
+                1 => center.y,
+                _ => center.z,
+            }
+        };
+        primitives.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        let right_primitives = primitives.split_off(primitives.len() / 2);
+
+        nodes.push(LightNode { aabb, power, parent, kind: LightNodeKind::Leaf(0) });
+        LightNode::build(primitives, Some(index), nodes, leaves);
+        let right = LightNode::build(right_primitives, Some(index), nodes, leaves);
+        nodes[index].kind = LightNodeKind::Interior(right);
+        index
+    }
+
+    // 放射束を中心までの距離の二乗で割った値を選ばれやすさとする
+    // 内側や近くにある場合に発散しないよう、距離は AABB の対角線の半分より小さくしない
+    fn importance(&self, origin: &Vector3) -> f64 {
+        let half_diagonal = (self.aabb.max - self.aabb.min).norm() * 0.25;
+        let distance = (self.aabb.center() - *origin).norm();
+        self.power / distance.max(half_diagonal)
+    }
+}
@@ -1,60 +1,605 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufReader, BufRead};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
 
-use vector::Vector3;
+use vector::{Vector3, Vector2};
 use matrix::Matrix44;
 use scene::{Mesh, Face};
-use material::Material;
+use material::{Material, SurfaceType};
+use texture::Texture;
+use color::Color;
+use principled::PrincipledTextures;
+use medium::Medium;
+
+// ファイル名と行番号つきの読み込みエラー
+// line が 0 の場合はファイル全体に関するエラーとする
+#[derive(Debug)]
+pub struct LoadError {
+    pub path: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl LoadError {
+    pub fn new(path: &str, line: usize, message: String) -> LoadError {
+        LoadError {
+            path: path.to_string(),
+            line,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.path, self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.path, self.line, self.message)
+        }
+    }
+}
+
+// メッシュの頂点法線の扱い
+pub enum NormalMode {
+    // ファイルの頂点法線（vn）を使い、無ければ面の法線を使う
+    File,
+    // ファイルの頂点法線を使い、無ければ角度で重み付けした頂点法線を生成する
+    Smooth,
+    // ファイルの頂点法線を無視して面の法線を使う
+    Flat,
+}
+
+impl FromStr for NormalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NormalMode, String> {
+        match s {
+            "file" => Ok(NormalMode::File),
+            "smooth" => Ok(NormalMode::Smooth),
+            "flat" => Ok(NormalMode::Flat),
+            _ => Err(format!("unknown normal mode `{}` (file, smooth, flat)", s)),
+        }
+    }
+}
+
+// OBJ のマテリアルの読み込み方
+pub enum MtlSource {
+    // 読み込まずに、メッシュ全体にシーンファイルで指定したマテリアルを使う
+    None,
+    // OBJ の mtllib で指定された MTL ファイルを使う
+    Mtllib,
+    // 指定した MTL ファイルを使う
+    Path(String),
+}
 
 pub struct ObjLoader;
 
+// f 行の頂点（v, v/vt, v//vn, v/vt/vn）のインデックス
+struct FaceVertex {
+    v: usize,
+    vt: Option<usize>,
+    vn: Option<usize>,
+}
+
+// o / g / usemtl で区切られたポリゴンのまとまり
+struct FaceGroup {
+    object: Option<String>,
+    groups: Vec<String>,
+    // usemtl のマテリアル名と行番号
+    material: Option<(String, usize)>,
+    faces: Vec<Face>,
+}
+
+impl FaceGroup {
+    fn matches(&self, name: &str) -> bool {
+        self.object.as_deref() == Some(name) || self.groups.iter().any(|g| g == name)
+    }
+}
+
 impl ObjLoader {
-    pub fn load(path: &str, matrix: Matrix44, material: Material) -> Mesh {
-        let mut mesh = Mesh {
-            vertexes: vec![],
+    // selected_groups が空でなければ、その名前の o / g に含まれるポリゴンだけを読み込む
+    // MTL を読み込む場合は usemtl のマテリアルごとにメッシュを分ける
+    // usemtl の無いポリゴンには material を使う
+    pub fn load(path: &str, matrix: Matrix44, material: Material, normal_mode: &NormalMode, selected_groups: &[String], mtl: &MtlSource) -> Result<Vec<Mesh>, LoadError> {
+        let mut vertexes = vec![];
+        let mut normals = vec![];
+        let mut uvs = vec![];
+
+        // 法線は逆行列の転置で変換する
+        let normal_matrix = matrix.inverse().transpose();
+
+        let mut groups = vec![FaceGroup {
+            object: None,
+            groups: vec![],
+            material: None,
             faces: vec![],
-            material: material,
-        };
+        }];
+        let mut mtllibs = vec![];
 
-        let f = File::open(path).unwrap();
+        let f = File::open(path).map_err(|e| LoadError::new(path, 0, e.to_string()))?;
         let file = BufReader::new(&f);
-        for (_, line) in file.lines().enumerate() {
-            let l = line.unwrap();
-            let split_line: Vec<&str> = l.split(" ").collect();
-            match split_line[0] {
+        for (index, line) in file.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| LoadError::new(path, line_number, message);
+            let l = line.map_err(|e| error(e.to_string()))?;
+
+            // # 以降はコメント。区切りはスペースとタブのどちらでもよく、連続してもよい
+            let content = l.split('#').next().unwrap_or("");
+            let tokens: Vec<&str> = content.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            let args = &tokens[1..];
+
+            match tokens[0] {
                 "v" => {
-                    let local_vertex = Vector3::new(
-                        split_line[1].parse::<f64>().unwrap(),
-                        split_line[2].parse::<f64>().unwrap(),
-                        split_line[3].parse::<f64>().unwrap(),
-                    );
+                    let local_vertex = ObjLoader::parse_vector3(args).map_err(error)?;
                     let world_vertex = matrix * local_vertex;
-                    mesh.vertexes.push(world_vertex);
+                    vertexes.push(world_vertex);
+                }
+                "vn" => {
+                    let local_normal = ObjLoader::parse_vector3(args).map_err(error)?;
+                    let world_normal = normal_matrix * local_normal;
+                    normals.push(world_normal.normalize());
+                }
+                "vt" => {
+                    // v は省略可能。ImageTexture と同じく v は画像の下端が 0 なのでそのまま使う
+                    if args.is_empty() {
+                        return Err(error("`vt` requires at least 1 value".to_string()));
+                    }
+                    let u = ObjLoader::parse_f64(args[0]).map_err(&error)?;
+                    let v = match args.get(1) {
+                        Some(arg) => ObjLoader::parse_f64(arg).map_err(&error)?,
+                        None => 0.0,
+                    };
+                    uvs.push(Vector2::new(u, v));
                 }
                 "f" => {
-                    let v1: Vec<&str> = split_line[1].split("/").collect();
-                    let v2: Vec<&str> = split_line[2].split("/").collect();
-                    let v3: Vec<&str> = split_line[3].split("/").collect();
-                    mesh.faces.push(Face {
-                        v0: v1[0].parse::<usize>().unwrap() - 1,
-                        v1: v2[0].parse::<usize>().unwrap() - 1,
-                        v2: v3[0].parse::<usize>().unwrap() - 1,
-                    });
-
-                    // 四角形ポリゴンの場合の処理
-                    if split_line.len() == 5 {
-                        let v4: Vec<&str> = split_line[4].split("/").collect();
-                        mesh.faces.push(Face {
-                            v0: v1[0].parse::<usize>().unwrap() - 1,
-                            v1: v3[0].parse::<usize>().unwrap() - 1,
-                            v2: v4[0].parse::<usize>().unwrap() - 1,
-                        });
+                    if args.len() < 3 {
+                        return Err(error(format!("`f` requires at least 3 vertices, found {}", args.len())));
                     }
+                    let mut face_vertexes = Vec::with_capacity(args.len());
+                    for arg in args {
+                        face_vertexes.push(ObjLoader::parse_face_vertex(arg, vertexes.len(), uvs.len(), normals.len()).map_err(&error)?);
+                    }
+
+                    // 多角形は最初の頂点を中心に扇状に三角形分割する
+                    let group = groups.last_mut().unwrap();
+                    for i in 1..face_vertexes.len() - 1 {
+                        group.faces.push(ObjLoader::face(&face_vertexes[0], &face_vertexes[i], &face_vertexes[i + 1]));
+                    }
+                }
+                "o" => {
+                    let object = Some(args.join(" "));
+                    ObjLoader::begin_group(&mut groups, |group| group.object = object);
+                }
+                "g" => {
+                    let names = args.iter().map(|s| s.to_string()).collect();
+                    ObjLoader::begin_group(&mut groups, |group| group.groups = names);
+                }
+                "usemtl" => {
+                    let material = Some((args.join(" "), line_number));
+                    ObjLoader::begin_group(&mut groups, |group| group.material = material);
+                }
+                // ファイル名に空白を含むものは考慮しない
+                "mtllib" => mtllibs.extend(args.iter().map(|s| s.to_string())),
+                // s, l, p などは無視する
+                _ => {}
+            }
+        }
+
+        for name in selected_groups {
+            if !groups.iter().any(|group| !group.faces.is_empty() && group.matches(name)) {
+                return Err(LoadError::new(path, 0, format!("group `{}` not found", name)));
+            }
+        }
+        let mut faces = vec![];
+        let mut face_materials = vec![];
+        for group in groups {
+            if selected_groups.is_empty() || selected_groups.iter().any(|name| group.matches(name)) {
+                face_materials.extend(group.faces.iter().map(|_| group.material.clone()));
+                faces.extend(group.faces);
+            }
+        }
+
+        let mut mesh = Mesh::new(vertexes, normals, uvs, faces, material);
+
+        match *normal_mode {
+            NormalMode::File => {}
+            NormalMode::Smooth => if mesh.normals.is_empty() {
+                mesh.generate_smooth_normals();
+            },
+            NormalMode::Flat => {
+                mesh.normals.clear();
+                for face in &mut mesh.faces {
+                    face.normals = None;
+                }
+            }
+        }
+
+        let libraries = match *mtl {
+            MtlSource::None => return Ok(vec![mesh]),
+            MtlSource::Mtllib => {
+                if mtllibs.is_empty() {
+                    return Err(LoadError::new(path, 0, "no `mtllib` found".to_string()));
+                }
+                // mtllib は OBJ ファイルからの相対パス
+                let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+                mtllibs.iter().map(|lib| dir.join(lib).to_string_lossy().into_owned()).collect()
+            }
+            MtlSource::Path(ref mtl_path) => vec![mtl_path.clone()],
+        };
+        let mut library = HashMap::new();
+        for lib in &libraries {
+            library.extend(MtlLoader::load(lib)?);
+        }
+
+        // 同じマテリアルのポリゴンを、最初に使われた順にまとめる
+        let Mesh { vertexes, normals, uvs, faces, material, .. } = mesh;
+        let mut default_material = Some(material);
+        let mut split: Vec<(Option<String>, Vec<Face>)> = vec![];
+        for (face, face_material) in faces.into_iter().zip(face_materials) {
+            let name = match face_material {
+                Some((name, line)) => {
+                    if !library.contains_key(&name) {
+                        return Err(LoadError::new(path, line, format!("material `{}` not found in {}", name, libraries.join(", "))));
+                    }
+                    Some(name)
+                }
+                None => None,
+            };
+            match split.iter().position(|(n, _)| *n == name) {
+                Some(i) => split[i].1.push(face),
+                None => split.push((name, vec![face])),
+            }
+        }
+
+        // マテリアルが 1 つなら頂点などの配列をそのまま使う
+        if split.len() == 1 {
+            let (name, faces) = split.pop().unwrap();
+            let material = match name {
+                Some(name) => library.remove(&name).unwrap(),
+                None => default_material.take().unwrap(),
+            };
+            return Ok(vec![Mesh::new(vertexes, normals, uvs, faces, material)]);
+        }
+
+        Ok(split.into_iter().map(|(name, faces)| {
+            let material = match name {
+                Some(name) => library.remove(&name).unwrap(),
+                None => default_material.take().unwrap(),
+            };
+            ObjLoader::compact_mesh(&vertexes, &normals, &uvs, faces, material)
+        }).collect())
+    }
+
+    // faces が使う頂点・法線・UV だけを集め、インデックスを付け直したメッシュを作る
+    fn compact_mesh(vertexes: &[Vector3], normals: &[Vector3], uvs: &[Vector2], mut faces: Vec<Face>, material: Material) -> Mesh {
+        fn remap<T: Copy>(index: &mut usize, source: &[T], map: &mut HashMap<usize, usize>, compacted: &mut Vec<T>) {
+            *index = *map.entry(*index).or_insert_with(|| {
+                compacted.push(source[*index]);
+                compacted.len() - 1
+            });
+        }
+
+        let (mut vertex_map, mut normal_map, mut uv_map) = (HashMap::new(), HashMap::new(), HashMap::new());
+        let (mut compact_vertexes, mut compact_normals, mut compact_uvs) = (vec![], vec![], vec![]);
+        for face in &mut faces {
+            remap(&mut face.v0, vertexes, &mut vertex_map, &mut compact_vertexes);
+            remap(&mut face.v1, vertexes, &mut vertex_map, &mut compact_vertexes);
+            remap(&mut face.v2, vertexes, &mut vertex_map, &mut compact_vertexes);
+            if let Some(ref mut indexes) = face.normals {
+                for index in indexes.iter_mut() {
+                    remap(index, normals, &mut normal_map, &mut compact_normals);
+                }
+            }
+            if let Some(ref mut indexes) = face.uvs {
+                for index in indexes.iter_mut() {
+                    remap(index, uvs, &mut uv_map, &mut compact_uvs);
                 }
+            }
+        }
+        Mesh::new(compact_vertexes, compact_normals, compact_uvs, faces, material)
+    }
+
+    // 直前のまとまりの設定を引き継いだ新しいまとまりを始める
+    fn begin_group<F: FnOnce(&mut FaceGroup)>(groups: &mut Vec<FaceGroup>, update: F) {
+        let mut group = {
+            let last = groups.last().unwrap();
+            FaceGroup {
+                object: last.object.clone(),
+                groups: last.groups.clone(),
+                material: last.material.clone(),
+                faces: vec![],
+            }
+        };
+        update(&mut group);
+
+        // ポリゴンを持たないまとまりは置き換える
+        if groups.last().unwrap().faces.is_empty() {
+            groups.pop();
+        }
+        groups.push(group);
+    }
+
+    fn parse_f64(token: &str) -> Result<f64, String> {
+        token.parse::<f64>().map_err(|_| format!("invalid number `{}`", token))
+    }
+
+    // 4 つ目の値（w）は無視する
+    fn parse_vector3(args: &[&str]) -> Result<Vector3, String> {
+        if args.len() < 3 {
+            return Err(format!("expected 3 values, found {}", args.len()));
+        }
+        Ok(Vector3::new(
+            ObjLoader::parse_f64(args[0])?,
+            ObjLoader::parse_f64(args[1])?,
+            ObjLoader::parse_f64(args[2])?,
+        ))
+    }
+
+    // 各 count はそれまでに定義された v, vt, vn の数
+    fn parse_face_vertex(token: &str, vertex_count: usize, uv_count: usize, normal_count: usize) -> Result<FaceVertex, String> {
+        let indexes: Vec<&str> = token.split('/').collect();
+        if indexes.len() > 3 {
+            return Err(format!("invalid face vertex `{}`", token));
+        }
+        let parse_index = |i: usize, kind: &str, count: usize| -> Result<Option<usize>, String> {
+            match indexes.get(i) {
+                Some(s) if !s.is_empty() => ObjLoader::resolve_index(s, kind, count).map(Some),
+                _ => Ok(None),
+            }
+        };
+        match parse_index(0, "v", vertex_count)? {
+            Some(v) => Ok(FaceVertex {
+                v,
+                vt: parse_index(1, "vt", uv_count)?,
+                vn: parse_index(2, "vn", normal_count)?,
+            }),
+            None => Err(format!("invalid face vertex `{}`", token)),
+        }
+    }
+
+    // 1 から始まるインデックスを 0 から始まるものに変換する
+    // 負の値はそれまでに定義された要素の末尾からの相対位置
+    fn resolve_index(token: &str, kind: &str, count: usize) -> Result<usize, String> {
+        let index = token.parse::<i64>().map_err(|_| format!("invalid index `{}`", token))?;
+        let resolved = if index > 0 {
+            index - 1
+        } else {
+            count as i64 + index
+        };
+        if index == 0 || resolved < 0 || resolved >= count as i64 {
+            return Err(format!("{} index {} out of range ({} defined)", kind, index, count));
+        }
+        Ok(resolved as usize)
+    }
+
+    fn face(v0: &FaceVertex, v1: &FaceVertex, v2: &FaceVertex) -> Face {
+        Face {
+            v0: v0.v,
+            v1: v1.v,
+            v2: v2.v,
+            normals: match (v0.vn, v1.vn, v2.vn) {
+                (Some(n0), Some(n1), Some(n2)) => Some([n0, n1, n2]),
+                _ => None,
+            },
+            uvs: match (v0.vt, v1.vt, v2.vt) {
+                (Some(t0), Some(t1), Some(t2)) => Some([t0, t1, t2]),
+                _ => None,
+            },
+        }
+    }
+}
+
+// MTL ファイルの 1 マテリアル分の記述
+struct MtlMaterial {
+    // newmtl の行番号
+    line: usize,
+    kd: Color,
+    ks: Color,
+    ke: Color,
+    tf: Option<Color>,
+    ns: Option<f64>,
+    pr: Option<f64>,
+    ni: f64,
+    d: f64,
+    illum: u32,
+    map_kd: Option<String>,
+    map_roughness: Option<String>,
+}
+
+impl MtlMaterial {
+    fn new(line: usize) -> MtlMaterial {
+        MtlMaterial {
+            line,
+            kd: Color::one(),
+            ks: Color::zero(),
+            ke: Color::zero(),
+            tf: None,
+            ns: None,
+            pr: None,
+            ni: 1.5,
+            d: 1.0,
+            illum: 1,
+            map_kd: None,
+            map_roughness: None,
+        }
+    }
+
+    // illum と d から SurfaceType を選ぶ
+    //   d < 1, illum 4, 6, 7, 9   透過（Refraction / GGXRefraction）
+    //   illum 3, 5, 8             鏡面反射（Specular / GGX、f0 は Ks の最大成分）
+    //   それ以外                  Diffuse（Ks による Phong のハイライトは表現しない）
+    // 粗さが 0 でテクスチャも無い場合は完全鏡面の SurfaceType にする
+    // 透過する場合は境界で色を付けないので、Tf（無ければ Kd）を距離 1 を進んだ光の色として吸収係数に変換する
+    fn to_material(&self) -> Result<Material, String> {
+        // Pr が無ければ Ns を Blinn-Phong と GGX の対応 alpha = sqrt(2 / (Ns + 2)) で変換する
+        let roughness = match (self.pr, self.ns) {
+            (Some(pr), _) => pr,
+            (None, Some(ns)) => (2.0 / (ns.max(0.0) + 2.0)).sqrt(),
+            (None, None) => 0.0,
+        };
+        let smooth = roughness == 0.0 && self.map_roughness.is_none();
+
+        let refraction = self.d < 1.0 || [4, 6, 7, 9].contains(&self.illum);
+        let surface = match self.illum {
+            _ if refraction => self.refraction_surface(smooth),
+            3 | 5 | 8 => if smooth {
+                SurfaceType::Specular
+            } else {
+                SurfaceType::GGX { f0: self.ks.x.max(self.ks.y).max(self.ks.z) }
+            },
+            _ => SurfaceType::Diffuse,
+        };
+
+        let (albedo, medium) = if refraction {
+            if self.map_kd.is_some() {
+                return Err("`map_Kd` is not supported on transparent materials".to_string());
+            }
+            let (key, color) = match self.tf {
+                Some(tf) => ("Tf", tf),
+                None => ("Kd", self.kd),
+            };
+            let absorption = Medium::absorption_from_color(&color, 1.0)
+                .ok_or_else(|| format!("`{}` of a transparent material must be in (0, 1]", key))?;
+            let medium = if absorption == Color::zero() {
+                None
+            } else {
+                let mut medium = Medium::new();
+                medium.absorption = absorption;
+                Some(medium)
+            };
+            (Texture::from_color(Color::one()), medium)
+        } else {
+            let albedo = match self.map_kd {
+                Some(ref path) => Texture::new(path, self.kd),
+                None => Texture::from_color(self.kd),
+            };
+            (albedo, None)
+        };
+
+        Ok(Material {
+            surface,
+            albedo,
+            emission: Texture::from_color(self.ke),
+            roughness: match self.map_roughness {
+                Some(ref path) => Texture::from_path(path),
+                None => Texture::from_color(Color::from_one(roughness)),
+            },
+            principled: PrincipledTextures::new(),
+            medium,
+            dispersion: None,
+        })
+    }
+
+    fn refraction_surface(&self, smooth: bool) -> SurfaceType {
+        if smooth {
+            SurfaceType::Refraction { refractive_index: self.ni }
+        } else {
+            SurfaceType::GGXRefraction { refractive_index: self.ni }
+        }
+    }
+}
+
+pub struct MtlLoader;
+
+impl MtlLoader {
+    // 対応するキー: Kd, Ks, Ke, Tf, Ns, Pr, Ni, d, Tr, illum, map_Kd, map_Ns, map_Pr
+    // map_Ns と map_Pr はどちらも粗さのテクスチャとして扱う
+    pub fn load(path: &str) -> Result<HashMap<String, Material>, LoadError> {
+        let mut materials: Vec<(String, MtlMaterial)> = vec![];
+
+        // テクスチャは MTL ファイルからの相対パス
+        let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+
+        let f = File::open(path).map_err(|e| LoadError::new(path, 0, e.to_string()))?;
+        let file = BufReader::new(&f);
+        for (index, line) in file.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| LoadError::new(path, line_number, message);
+            let l = line.map_err(|e| error(e.to_string()))?;
+
+            let content = l.split('#').next().unwrap_or("");
+            let tokens: Vec<&str> = content.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            let key = tokens[0];
+            let args = &tokens[1..];
+
+            if key == "newmtl" {
+                if args.is_empty() {
+                    return Err(error("`newmtl` requires a name".to_string()));
+                }
+                materials.push((args.join(" "), MtlMaterial::new(line_number)));
+                continue;
+            }
+
+            let material = match materials.last_mut() {
+                Some(&mut (_, ref mut material)) => material,
+                None => return Err(error(format!("`{}` before `newmtl`", key))),
+            };
+            let single = || -> Result<f64, String> {
+                if args.len() != 1 {
+                    return Err(format!("`{}` requires 1 value, found {}", key, args.len()));
+                }
+                ObjLoader::parse_f64(args[0])
+            };
+            // オプション（-s, -o など）は無視してファイル名だけを使う
+            let texture_path = || -> Result<String, String> {
+                let file_name = args.last().ok_or_else(|| format!("`{}` requires a file name", key))?;
+                let texture_path = dir.join(file_name);
+                if !texture_path.is_file() {
+                    return Err(format!("file not found `{}`", texture_path.display()));
+                }
+                Ok(texture_path.to_string_lossy().into_owned())
+            };
+
+            match key {
+                "Kd" => material.kd = MtlLoader::parse_color(key, args).map_err(error)?,
+                "Ks" => material.ks = MtlLoader::parse_color(key, args).map_err(error)?,
+                "Ke" => material.ke = MtlLoader::parse_color(key, args).map_err(error)?,
+                "Tf" => material.tf = Some(MtlLoader::parse_color(key, args).map_err(error)?),
+                "Ns" => material.ns = Some(single().map_err(error)?),
+                "Pr" => material.pr = Some(single().map_err(error)?),
+                "Ni" => material.ni = single().map_err(error)?,
+                "d" => material.d = single().map_err(error)?,
+                "Tr" => material.d = 1.0 - single().map_err(error)?,
+                "illum" => {
+                    material.illum = match args {
+                        [arg] => arg.parse::<u32>().map_err(|_| error(format!("invalid illumination model `{}`", arg)))?,
+                        _ => return Err(error(format!("`illum` requires 1 value, found {}", args.len()))),
+                    };
+                }
+                "map_Kd" => material.map_kd = Some(texture_path().map_err(error)?),
+                "map_Ns" | "map_Pr" => material.map_roughness = Some(texture_path().map_err(error)?),
+                // Ka, map_Bump などは無視する
                 _ => {}
             }
         }
 
-        mesh
+        materials.into_iter().map(|(name, material)| {
+            let converted = material.to_material()
+                .map_err(|message| LoadError::new(path, material.line, format!("material `{}`: {}", name, message)))?;
+            Ok((name, converted))
+        }).collect()
+    }
+
+    // 1つの値なら全成分に同じ値を、3つの値ならそれぞれの成分とする
+    fn parse_color(key: &str, args: &[&str]) -> Result<Color, String> {
+        match args.len() {
+            1 => Ok(Color::from_one(ObjLoader::parse_f64(args[0])?)),
+            3 => Ok(Color::new(
+                ObjLoader::parse_f64(args[0])?,
+                ObjLoader::parse_f64(args[1])?,
+                ObjLoader::parse_f64(args[2])?,
+            )),
+            _ => Err(format!("`{}` requires 1 or 3 values, found {}", key, args.len())),
+        }
     }
 }
@@ -10,8 +10,8 @@ use std::fs::File;
 use std::path::Path;
 use std::fs;
 use std::io::{BufWriter, Write};
+use std::process;
 use num::Float;
-use self::rand::{Rng, SeedableRng, StdRng};
 use getopts::Options;
 use std::env;
 
@@ -22,27 +22,35 @@ mod scene;
 mod camera;
 mod renderer;
 mod material;
+mod principled;
 mod color;
 mod texture;
 mod math;
 mod loader;
+mod scene_loader;
 mod bvh;
 mod tonemap;
 mod filter;
-
-use vector::Vector3;
-use matrix::Matrix44;
-use scene::{Scene, BvhScene, Sphere, Cuboid, BvhMesh, Skybox};
-use bvh::Aabb;
-use camera::{Camera, LensShape};
-use material::{Material, SurfaceType};
-use texture::Texture;
+mod hdr;
+mod checkpoint;
+mod light;
+mod environment;
+mod spectrum;
+mod medium;
+mod volume;
+
+use scene::{Scene, BvhScene, Intersection};
+use camera::{Camera, Ray};
+use vector::Vector2;
+use bvh::{Bvh, BvhBuildMethod, TraversalStats};
+use config::RenderSettings;
+use hdr::HdrFormat;
+use checkpoint::Checkpoint;
 
 #[allow(unused_imports)]
 use renderer::{Renderer, DebugRenderer, DebugRenderMode, PathTracingRenderer};
 
-use color::{Color, hsv_to_rgb};
-use loader::ObjLoader;
+use scene_loader::SceneLoader;
 
 fn tee(f: &mut BufWriter<File>, message: &String) {
     println!("{}", message);
@@ -50,1172 +58,78 @@ fn tee(f: &mut BufWriter<File>, message: &String) {
     let _ = f.write(b"\n");
 }
 
-#[allow(dead_code)]
-fn init_scene_simple() -> (Camera, Scene) {
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.0, 9.0), // eye
-        Vector3::new(0.0, 1.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        10.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2 * 0.0,// aperture
-        8.8,// focus_distance
-    );
-
-    let radius = 0.6;
-
-    let scene = Scene {
-        elements: vec![
-            Box::new(Sphere {
-                center: Vector3::new(0.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.99)),
-                },
-            }),
-
-            // 光源
-            Box::new(Sphere {
-                center: Vector3::new(3.0, 2.0 + radius, -2.0),
-                radius: radius * 0.2,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::new(200.0, 10.0, 10.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-
-            // 光源
-            Box::new(Sphere {
-                center: Vector3::new(-3.0, 2.0 + radius, -2.0),
-                radius: radius * 0.2,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::new(10.0, 200.0, 10.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    //albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    //roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/LancellottiChapel/posx.jpg",
-            "textures/cube/LancellottiChapel/negx.jpg",
-            "textures/cube/LancellottiChapel/posy.jpg",
-            "textures/cube/LancellottiChapel/negy.jpg",
-            "textures/cube/LancellottiChapel/posz.jpg",
-            "textures/cube/LancellottiChapel/negz.jpg",
-            &Vector3::zero(),
-        ),
-    };
-
-    (camera, scene)
-}
-
-#[allow(dead_code)]
-fn init_scene_material_examples() -> (Camera, Scene) {
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.0, 9.0), // eye
-        Vector3::new(0.0, 1.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        10.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2, // * 0.0,// aperture
-        8.8,// focus_distance
-    );
-
-    let radius = 0.4;
-
-    let scene = Scene {
-        elements: vec![
-            // 球体
-            Box::new(Sphere {
-                center: Vector3::new(-2.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(-1.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(0.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Specular,
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(1.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(2.0, radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::GGXRefraction { refractive_index: 1.5 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-
-            // 光源
-            Box::new(Sphere {
-                center: Vector3::new(0.0, 2.0 + radius, -2.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::from_one(20.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    //albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    //roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::one(
-            "textures/cube/LancellottiChapel/posx.jpg",
-            "textures/cube/LancellottiChapel/negx.jpg",
-            "textures/cube/LancellottiChapel/posy.jpg",
-            "textures/cube/LancellottiChapel/negy.jpg",
-            "textures/cube/LancellottiChapel/posz.jpg",
-            "textures/cube/LancellottiChapel/negz.jpg",
-        ),
-    };
-
-    (camera, scene)
+// 出力する画像の解像度と、トーンマッピング前の画像の出力先
+struct OutputOptions {
+    width: u32,
+    height: u32,
+    hdr_output: Option<String>,
 }
 
-#[allow(dead_code)]
-fn init_scene_rtcamp5() -> (Camera, Scene) {
-    let seed: &[_] = &[870, 2000, 304, 2];
-    let mut rng: StdRng = SeedableRng::from_seed(seed);
-
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.5, 9.0), // eye
-        Vector3::new(0.0, 1.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        17.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.15, // * 0.0,// aperture
-        8.5,// focus_distance
-    );
-
-    let mut scene = Scene {
-        elements: vec![
-            // うさぎ右
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/bunny/bunny_face1000.obj",
-                Matrix44::scale_linear(1.5) * Matrix44::translate(1.2, 0.0, 0.0) * Matrix44::rotate_y(0.2),
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },
-            ))),
-            // うさぎ左
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/bunny/bunny_face1000_flip.obj",
-                Matrix44::scale(1.5, 1.5, 1.5) * Matrix44::translate(-1.2, 0.0, 0.0) * Matrix44::rotate_y(-0.2),
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 0.04, 0.04)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },
-            ))),
-            // 背後にある地図ガラス
-            /*Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-4.0, 0.0, -3.6),
-                    max: Vector3::new(4.0, 3.0, -3.5),
-                },
-                material: Material {
-                    surface: SurfaceType::GGXReflection { refractive_index: 1.2 },
-                    albedo: Texture::white(),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(3.0, 3.0, 1.1)),
-                    roughness: Texture::from_color(Color::from_one(0.3)),
-                }
-            }),*/
-
-            // 固定のダイヤモンド
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/dia/dia.obj",
-                Matrix44::translate(3.1, 0.0, 0.8) * Matrix44::scale_linear(1.0) * Matrix44::rotate_y(-0.5) * Matrix44::rotate_x(40.35.to_radians()),
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::black(),
-                },
-            ))),
-            // 地球のテクスチャを光源にした球体
-            Box::new(Sphere {
-                center: Vector3::new(0.0, 0.5, -0.5),
-                radius: 0.5,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::white(),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(5.0, 5.0, 2.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            // 地球のテクスチャをラフネスにした球体
-            Box::new(Sphere {
-                center: Vector3::new(-3.5, 0.5, 0.0),
-                radius: 0.5,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 1.0, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_path("textures/2d/earth_inverse_2048.jpg"),
-                },
-            }),
-            // カラフルな球体
-            Box::new(Sphere {
-                center: Vector3::new(0.5018854352719382, 0.3899602675366644, 1.8484239850862165),
-                radius: 0.3899602675366644,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.2, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(-0.5748933256792994, 0.2951263257801348, 2.266298272012876),
-                radius: 0.2951263257801348,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.4, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(-0.9865234498515534, 0.3386858117447873, 2.9809338871934585),
-                radius: 0.3386858117447873,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.6, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.02)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(0.6946459502665004, 0.2764689077971783, 2.7455446851003025),
-                radius: 0.2764689077971783,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.05, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.0)),
-                },
-            }),
-            /*Box::new(Sphere {
-                center: Vector3::new( 1.4192264328563055, 0.3, 1.6181489825435929),
-                radius:  0.3,
-                material: Material {
-                    surface: SurfaceType::GGX{ f0: 0.8 }{ metalness: 1.0 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.7, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),*/
-            Box::new(Sphere {
-                center: Vector3::new(3.7027464198816952, 0.3917608374245498, -0.40505849281451556),
-                radius: 0.3917608374245498,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new(0.8, 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },
-            }),
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    //albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    //roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::one(
-            "textures/cube/LancellottiChapel/posx.jpg",
-            "textures/cube/LancellottiChapel/negx.jpg",
-            "textures/cube/LancellottiChapel/posy.jpg",
-            "textures/cube/LancellottiChapel/negy.jpg",
-            "textures/cube/LancellottiChapel/posz.jpg",
-            "textures/cube/LancellottiChapel/negz.jpg",
-        ),
-    };
-
-    // 金属の球体
-    let mut count = 0;
-    while count < 0 {
-        let px = rng.gen_range(-2.5, 3.5);
-        let py = 0.0;//rng.gen_range(0.0, 3.0);
-        let pz = rng.gen_range(-2.0, 3.0);
-        let r = rng.gen_range(0.2, 0.4);
-
-        if scene.add_with_check_collisions(Box::new(Sphere {
-            center: Vector3::new(px, r + py, pz),
-            radius: r,
-            material: Material {
-                surface: SurfaceType::GGX { f0: 0.8 },
-                albedo: Texture::from_color(hsv_to_rgb(Color::new(0.2 + 0.1 * count as f64, 1.0, 1.0))),
-                emission: Texture::black(),
-                roughness: Texture::from_color(Color::from_one(rng.gen_range(0.0, 0.2))),
-            },
-        })) {
-            println!("{}, {}, {} : {}", px, r, pz, 0.2 + 0.1 * count as f64);
-            count += 1;
-        }
-    }
-
-    // 床に落ちているダイヤモンド
-    count = 0;
-    while count < 12 {
-        let px = rng.gen_range(-4.5, 4.5);
-        let py = 0.0;
-        let pz = rng.gen_range(-2.5, 4.5);
-        let s = rng.gen_range(0.7, 1.1);
-        let ry = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-
-        if scene.add_with_check_collisions(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-            "models/dia/dia.obj",
-            Matrix44::translate(px, py, pz) * Matrix44::scale_linear(s) * Matrix44::rotate_y(ry) * Matrix44::rotate_x(40.35.to_radians()),
-            Material {
-                surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                albedo: Texture::white(),
-                emission: Texture::black(),
-                roughness: Texture::black(),
-            },
-        )))) {
-            count += 1;
-        }
-    }
-
-    // 空中浮遊しているダイヤモンド
-    count = 0;
-    while count < 30 {
-        let px = rng.gen_range(-4.5, 4.5);
-        let py = rng.gen_range(0.0, 4.0);
-        let pz = rng.gen_range(-4.5, 3.5);
-        let s = rng.gen_range(0.6, 1.1);
-        let ry = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-        let rx = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-
-        if scene.add_with_check_collisions(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-            "models/dia/dia.obj",
-            Matrix44::translate(px, py, pz) * Matrix44::scale_linear(s) * Matrix44::rotate_y(ry) * Matrix44::rotate_x(rx),
-            Material {
-                surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                albedo: Texture::white(),
-                emission: Texture::black(),
-                roughness: Texture::black(),
-            },
-        )))) {
-            count += 1;
+fn render<R: Renderer>(renderer: &mut R, camera: &Camera, scene: Scene, settings: &RenderSettings, output: &OutputOptions) -> u32 {
+    let mut imgbuf = image::ImageBuffer::new(output.width, output.height);
+    let mut hdrbuf = Vec::new();
+    let sampled = renderer.render(&BvhScene::from_scene(scene, settings), camera, settings, &mut imgbuf, &mut hdrbuf);
+    let _ = image::ImageRgb8(imgbuf).save("result.png");
+    if let Some(ref path) = output.hdr_output {
+        match hdr::save(path, &hdrbuf, output.width, output.height) {
+            Ok(_) => println!("output HDR image: {}", path),
+            Err(e) => eprintln!("{}: {}", path, e),
         }
     }
-
-    (camera, scene)
+    sampled
 }
 
-#[allow(dead_code)]
-fn init_scene_tbf3() -> (Camera, Scene) {
-    let seed: &[_] = &[870, 2000, 304, 1];
-    let mut rng: StdRng = SeedableRng::from_seed(seed);
-
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.5, 9.0), // eye
-        //Vector3::new(0.0, 15.5, 1.0), // eye
-        Vector3::new(0.0, 1.5, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        19.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.18, // * 0.0,// aperture
-        7.0,// focus_distance
-    );
-
-    let mut scene = Scene {
-        elements: vec![
-            // KLab logo
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/klab_logo/klab_logo_triangle.obj",
-                Matrix44::scale_linear(0.4) * Matrix44::translate(0.0, 3.1782, 2.0) * Matrix44::rotate_y(-0.5),
-                /*Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },*/
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(0.4, 0.4, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            ))),
-            // 背後にある地図ガラス
-            /*Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-4.0, 0.0, -5.1),
-                    max: Vector3::new(4.0, 4.0, -5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::GGXRefraction { refractive_index: 1.2 },
-                    albedo: Texture::white(),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(3.0, 3.0, 1.1)),
-                    roughness: Texture::from_color(Color::from_one(0.3)),
-                }
-            }),*/
-            // 固定のダイヤモンド（右）
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/dia/dia.obj",
-                Matrix44::translate(1.3, 0.0, 2.2) * Matrix44::scale_linear(1.0) * Matrix44::rotate_y(-0.4) * Matrix44::rotate_x(40.35.to_radians()),
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::black(),
-                },
-            ))),
-
-            // 固定のダイヤモンド（中央）
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/dia/dia.obj",
-                Matrix44::translate(-0.1, 0.0, 2.4) * Matrix44::scale_linear(1.0) * Matrix44::rotate_y(-1.4) * Matrix44::rotate_x(40.35.to_radians()),
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::black(),
-                },
-            ))),
-
-            // 光源の球体（手前）
-            Box::new(Sphere {
-                center: Vector3::new(-1.0, 0.4, 4.0),
-                radius: 0.4,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::one()),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(3.0, 3.0, 1.1)),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),
-
-            // 光源の球体（奥）
-            Box::new(Sphere {
-                center: Vector3::new(-3.0, 0.4, -3.5),
-                radius: 0.4,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(0.5, 1.0, 1.0)),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(1.0, 3.0, 3.5)),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),
-
-            // 光源の球体（奥）
-            Box::new(Sphere {
-                center: Vector3::new(4.0, 0.2, -4.5),
-                radius: 0.2,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(0.3, 0.7, 1.0)),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(3.0, 3.0, 1.1)),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),
-            Box::new(Sphere {
-                center: Vector3::new(3.0, 0.2, -4.2),
-                radius: 0.2,
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 0.7, 0.9)),
-                    emission: Texture::new("textures/2d/earth_inverse_2048.jpg", Color::new(2.0, 3.0, 1.0)),
-                    roughness: Texture::from_color(Color::from_one(0.01)),
-                },
-            }),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    //albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    //roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/LancellottiChapel/posx.jpg",
-            "textures/cube/LancellottiChapel/negx.jpg",
-            "textures/cube/LancellottiChapel/posy.jpg",
-            "textures/cube/LancellottiChapel/negy.jpg",
-            "textures/cube/LancellottiChapel/posz.jpg",
-            "textures/cube/LancellottiChapel/negz.jpg",
-            &Vector3::new(2.0, 2.0, 3.0),
-        ),
-    };
-
-    // 金属の球体
-    let mut count = 0;
-    #[allow(unused_parens)]
-        while count < 8 {
-        let px = rng.gen_range(-3.0, 3.0);
-        let py = 0.0;//rng.gen_range(0.0, 3.0);
-        let pz = rng.gen_range(-5.0, 5.0);
-        let r = rng.gen_range(0.2, 0.4);
-
-        if scene.add_with_check_collisions(Box::new(Sphere {
-            center: Vector3::new(px, r + py, pz),
-            radius: r,
-            material: Material {
-                surface: SurfaceType::GGX { f0: 0.8 },
-                albedo: Texture::from_color(hsv_to_rgb(Color::new(0.2 + 0.1 * count as f64, 1.0, 1.0))),
-                emission: Texture::black(),
-                roughness: Texture::from_color(Color::from_one(rng.gen_range(0.0, 0.2))),
-            },
-        })) {
-            println!("{}, {}, {} : {}", px, r, pz, 0.2 + 0.1 * count as f64);
-            count += 1;
-        }
-    }
-
-    // 床に落ちているダイヤモンド
-    count = 0;
-    while count < 20 {
-        let px = rng.gen_range(-4.0, 4.0);
-        let py = 0.0;
-        let pz = rng.gen_range(-5.0, 5.0);
-        let s = rng.gen_range(0.7, 1.1);
-        let ry = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-
-        if scene.add_with_check_collisions(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-            "models/dia/dia.obj",
-            Matrix44::translate(px, py, pz) * Matrix44::scale_linear(s) * Matrix44::rotate_y(ry) * Matrix44::rotate_x(40.35.to_radians()),
-            Material {
-                surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                albedo: Texture::white(),
-                emission: Texture::black(),
-                roughness: Texture::black(),
-            },
-        )))) {
-            count += 1;
+// 中央分割と SAH で構築した BVH の統計を、シーンとメッシュごとに比較する
+// 走査の統計はピクセルごとに1本のカメラレイで計測する
+fn compare_bvh(camera: &Camera, scene: &Scene, settings: &RenderSettings, width: u32, height: u32) {
+    let resolution = Vector2::new(width as f64, height as f64);
+    let mut rays = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let frag_coord = Vector2::new(x as f64 + 0.5, (height - y) as f64 - 0.5);
+            let normalized_coord = (frag_coord * 2.0 - resolution) / resolution.x.min(resolution.y);
+            rays.push(camera.ray(&normalized_coord));
         }
     }
 
-    // 空中浮遊しているダイヤモンド
-    count = 0;
-    while count < 0 {
-        let px = rng.gen_range(-4.5, 4.5);
-        let py = rng.gen_range(0.0, 7.0);
-        let pz = rng.gen_range(-4.5, 3.5);
-        let s = rng.gen_range(0.6, 1.1);
-        let ry = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-        let rx = rng.gen_range(-180.0.to_radians(), 180.0.to_radians());
-
-        if scene.add_with_check_collisions(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-            "models/dia/dia.obj",
-            Matrix44::translate(px, py, pz) * Matrix44::scale_linear(s) * Matrix44::rotate_y(ry) * Matrix44::rotate_x(rx),
-            Material {
-                surface: SurfaceType::Refraction { refractive_index: 2.42 },
-                albedo: Texture::white(),
-                emission: Texture::black(),
-                roughness: Texture::black(),
-            },
-        )))) {
-            count += 1;
+    println!("scene: {} elements", scene.elements.len());
+    print_bvh_comparison(settings, &rays, |s| Bvh::build_from_scene(scene, s), |bvh, ray, stats| {
+        bvh.count_traversal_for_scene(scene, ray, &mut Intersection::empty(), stats);
+    });
+
+    for (i, element) in scene.elements.iter().enumerate() {
+        if let Some(mesh) = element.mesh() {
+            println!("mesh #{}: {} faces", i, mesh.faces.len());
+            print_bvh_comparison(settings, &rays, |s| Bvh::build_from_mesh(mesh, s), |bvh, ray, stats| {
+                bvh.count_traversal_for_mesh(mesh, ray, &mut Intersection::empty(), stats);
+            });
         }
     }
-
-    (camera, scene)
-}
-
-#[allow(dead_code)]
-fn init_scene_rtcamp6_v1() -> (Camera, Scene) {
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.0, 10.0), // eye
-        Vector3::new(0.0, 1.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        10.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2 * 0.0,// aperture
-        8.8,// focus_distance
-    );
-
-    let radius = 0.6;
-
-    let scene = Scene {
-        elements: vec![
-            Box::new(Sphere {
-                center: Vector3::new(0.0, 3.1782 * 0.4, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::white(),
-                    emission: Texture::from_color(Color::from_one(10.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),
-
-            // Mesh
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/houdini_boss.obj",
-                Matrix44::scale_linear(0.4) * Matrix44::translate(0.0, 3.1782, 2.0) * Matrix44::rotate_y(-0.5),
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },
-                /*Material {
-                    surface: SurfaceType::GGX{ f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(0.4, 0.4, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },*/
-            ))),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    //albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    //roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/LancellottiChapel/posx.jpg",
-            "textures/cube/LancellottiChapel/negx.jpg",
-            "textures/cube/LancellottiChapel/posy.jpg",
-            "textures/cube/LancellottiChapel/negy.jpg",
-            "textures/cube/LancellottiChapel/posz.jpg",
-            "textures/cube/LancellottiChapel/negz.jpg",
-            &Vector3::from_one(0.5),
-        ),
-    };
-
-    (camera, scene)
 }
 
-#[allow(dead_code)]
-fn init_scene_rtcamp6_v2() -> (Camera, Scene) {
-    let seed: &[_] = &[870, 2000, 304, 2];
-    let mut rng: StdRng = SeedableRng::from_seed(seed);
-
-    let camera = Camera::new(
-        Vector3::new(-5.0, -1.0, 0.0), // eye
-        Vector3::new(0.0, 0.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        10.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2 * 0.0,// aperture
-        8.8,// focus_distance
-    );
-
-    let mut scene = Scene {
-        elements: vec![
-            /*Box::new(Sphere {
-                center: Vector3::new(0.0, 0.0, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::white(),
-                    emission: Texture::from_color(Color::from_one(10.0)),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            }),*/
-
-            // 床
-            /*Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    surface: SurfaceType::GGX{ f0: 0.9 },
-                    //albedo:  Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    //albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    //roughness: Texture::white(),
-                    roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    //roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                }
-            }),*/
-        ],
-        skybox: Skybox::new(
-            "textures/cube/Ryfjallet/posx.jpg",
-            "textures/cube/Ryfjallet/negx.jpg",
-            "textures/cube/Ryfjallet/posy.jpg",
-            "textures/cube/Ryfjallet/negy.jpg",
-            "textures/cube/Ryfjallet/posz.jpg",
-            "textures/cube/Ryfjallet/negz.jpg",
-            &Vector3::from_one(0.5),
-        ),
-    };
-
-    // 空中浮遊しているSphere
-    let mut count = 0;
-    while count < 100 {
-        let px = rng.gen_range(-0.5, 2.0);
-        let py = rng.gen_range(-2.0, 2.0);
-        let pz = rng.gen_range(-2.0, 2.0);
-        let s = 0.1;
-
-        if scene.add_with_check_collisions(Box::new(Sphere {
-            center: Vector3::new(px, py, pz),
-            radius: s,
-            material: Material {
-                surface: SurfaceType::GGX { f0: 0.9 },
-                albedo: Texture::from_color(color::hsv_to_rgb(Color::new(rng.gen_range(0.0, 1.0), 1.0, 1.0))),
-                emission: Texture::black(),
-                roughness: Texture::from_color(Color::from_one(rng.gen_range(0.0, 1.0))),
-            },
-        },
-        )) {
-            count += 1;
+fn print_bvh_comparison<B, C>(settings: &RenderSettings, rays: &[Ray], build: B, count_traversal: C)
+    where B: Fn(&RenderSettings) -> Bvh, C: Fn(&Bvh, &Ray, &mut TraversalStats) {
+    for method in &[BvhBuildMethod::Median, BvhBuildMethod::Sah] {
+        let mut method_settings = settings.clone();
+        method_settings.bvh_build_method = method.clone();
+
+        let begin = time::now();
+        let bvh = build(&method_settings);
+        let build_sec = (time::now() - begin).num_milliseconds() as f64 * 0.001;
+
+        let stats = bvh.stats(settings);
+        let mut traversal = TraversalStats::default();
+        for ray in rays {
+            traversal.rays += 1;
+            count_traversal(&bvh, ray, &mut traversal);
         }
-    }
-
-    let mut count = 0;
-    while count < 5 {
-        let px = rng.gen_range(-0.2, 0.5);
-        let py = rng.gen_range(-1.0, 1.0);
-        let pz = rng.gen_range(-1.0, 1.0);
-        let s = 0.1;
+        let rays_inv = (traversal.rays.max(1) as f64).recip();
 
-        if scene.add_with_check_collisions(Box::new(Sphere {
-            center: Vector3::new(px, py, pz),
-            radius: s,
-            material: Material {
-                surface: SurfaceType::Diffuse,
-                albedo: Texture::black(),
-                emission: Texture::from_color(color::hsv_to_rgb(Color::new(rng.gen_range(0.0, 1.0), 1.0, 1.0)) * 10.0),
-                roughness: Texture::from_color(Color::from_one(rng.gen_range(0.0, 1.0))),
-            },
-        },
-        )) {
-            count += 1;
-        }
+        println!("  {:<6} build {:.3} sec, nodes {} (leaves {}), depth {}, SAH cost {:.3}, per ray: {:.2} nodes, {:.2} primitives",
+                 format!("{:?}", method).to_lowercase(), build_sec,
+                 stats.node_count, stats.leaf_count, stats.max_depth, stats.sah_cost,
+                 traversal.node_visits as f64 * rays_inv, traversal.primitive_tests as f64 * rays_inv);
     }
-
-    scene.add(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-        "models/fractal_dodecahedron.obj",
-        Matrix44::scale_linear(1.0) * Matrix44::translate(0.0, 0.0, 0.0) * Matrix44::rotate_y(0.0),
-        Material {
-            surface: SurfaceType::Refraction { refractive_index: 1.5 },
-            albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-            emission: Texture::black(),
-            roughness: Texture::from_color(Color::from_one(0.1)),
-        },
-        /*Material {
-            surface: SurfaceType::GGX { f0: 0.8 },
-            albedo: Texture::from_color(Color::new(0.4, 0.4, 1.0)),
-            emission: Texture::black(),
-            roughness: Texture::from_color(Color::from_one(0.05)),
-        },*/
-    ))));
-
-    (camera, scene)
-}
-
-#[allow(dead_code)]
-fn init_scene_rtcamp6_v3() -> (Camera, Scene) {
-    let camera = Camera::new(
-        Vector3::new(0.0, 2.0, 6.0), // eye
-        Vector3::new(0.0, 1.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        20.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2,// aperture
-        4.9,// focus_distance
-    );
-
-    let radius = 0.2;
-
-    let scene = Scene {
-        elements: vec![
-            Box::new(Sphere {
-                center: Vector3::new(-0.3, 0.5 + radius, 0.0),
-                radius: radius,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::from_one(10.0)),
-                    roughness: Texture::black(),
-                },
-            }),
-
-            // camera light
-            Box::new(Sphere {
-                center: camera.eye - camera.forward,
-                radius: 0.001,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::from_one(1000.0)),
-                    roughness: Texture::black(),
-                },
-            }),
-
-            // Mesh
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/bunny/bunny_wired_300.obj",
-                Matrix44::scale_linear(1.5) * Matrix44::translate(0.0, 0.0, 0.0) * Matrix44::rotate_y(0.3),
-                /*Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },*/
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 0.01, 0.01)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            ))),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-5.0, -1.0, -5.0),
-                    max: Vector3::new(5.0, 0.0, 5.0),
-                },
-                material: Material {
-                    //surface: SurfaceType::GGX{ f0: 0.99 },
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::white(),
-                    //albedo: Texture::from_path("textures/2d/stone03.jpg"),
-                    //albedo: Texture::from_path("textures/2d/checkered_diagonal_10_0.5_1.0_512.png"),
-                    //albedo: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_c_diffuse.tiff"),
-                    emission: Texture::black(),
-                    roughness: Texture::white(),
-                    //roughness: Texture::from_path("textures/2d/checkered_diagonal_10_0.1_0.6_512.png"),
-                    //roughness: Texture::from_path("textures/2d/MarbleFloorTiles2/TexturesCom_MarbleFloorTiles2_1024_roughness.png"),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/Powerlines/posx.jpg",
-            "textures/cube/Powerlines/negx.jpg",
-            "textures/cube/Powerlines/posy.jpg",
-            "textures/cube/Powerlines/negy.jpg",
-            "textures/cube/Powerlines/posz.jpg",
-            "textures/cube/Powerlines/negz.jpg",
-            &Vector3::from_one(1.0),
-        ),
-    };
-
-    (camera, scene)
-}
-
-#[allow(dead_code)]
-fn init_scene_rtcamp6_v3_1() -> (Camera, Scene) {
-    let scene_scale = 1.0;
-    let theta = config::PI2 * 0.03;
-    let r = 6.5 * scene_scale;
-    let camera = Camera::new(
-        Vector3::new(r * theta.sin(), 2.0 * scene_scale, r * theta.cos()), // eye
-        Vector3::new(0.0, 1.0 * scene_scale, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        20.0, // fov
-
-        LensShape::Circle, // lens shape
-
-        // Submit版
-        0.03, 5.0 * scene_scale,
-
-        // 強いDOF用
-        //0.3, 5.7 * scene_scale,
-    );
-
-    let radius = 0.2;
-    let floor_s = 9.0 * scene_scale;
-
-    let mut scene = Scene {
-        elements: vec![
-            // 光源
-            Box::new(Sphere {
-                center: Vector3::new(-0.3, 0.5 + radius, 0.0) * scene_scale,
-                radius: radius * scene_scale,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::new(30.0, 20.0, 4.0)),
-                    roughness: Texture::black(),
-                },
-            }),
-
-            // Mesh
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/bunny/bunny_wired_300.obj",
-                Matrix44::scale_linear(1.5 * scene_scale) * Matrix44::translate(0.0, 0.0, 0.0) * Matrix44::rotate_y(0.3),
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 0.01, 0.01)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            ))),
-
-            // 鏡
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/box.obj",
-                Matrix44::translate(1.0 * scene_scale, 0.0, -3.0 * scene_scale) * Matrix44::rotate_y(-config::PI / 8.0) * Matrix44::scale(4.0 * 0.9 * scene_scale, 3.0 * 0.9 * scene_scale, 0.1 * 0.9 * scene_scale),
-                Material {
-                    surface: SurfaceType::Specular,
-                    albedo: Texture::white(),
-                    emission: Texture::black(),
-                    roughness: Texture::black(),
-                },
-            ))),
-
-            // 額縁
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/picture_frame.obj",
-                Matrix44::translate(1.0 * scene_scale, 0.0, -3.0 * scene_scale) * Matrix44::rotate_y(-config::PI / 8.0) * Matrix44::scale(4.0 * scene_scale, 3.0 * scene_scale, scene_scale),
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.9 },
-                    albedo: Texture::from_color(Color::new(0.33, 0.27, 0.22)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.3)),
-                },
-            ))),
-
-            // 床
-            Box::new(Cuboid {
-                aabb: Aabb {
-                    min: Vector3::new(-floor_s, -1.0, -floor_s),
-                    max: Vector3::new(floor_s, 0.0, floor_s),
-                },
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::from_path("textures/2d/magic-circle3.png"),
-                    emission: Texture::black(),
-                    roughness: Texture::white(),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/Powerlines/posx.jpg",
-            "textures/cube/Powerlines/negx.jpg",
-            "textures/cube/Powerlines/posy.jpg",
-            "textures/cube/Powerlines/negy.jpg",
-            "textures/cube/Powerlines/posz.jpg",
-            "textures/cube/Powerlines/negz.jpg",
-            &Vector3::from_one(1.0),
-        ),
-    };
-
-    let mut i = 0;
-    let count = 6;
-    while i < count {
-        let r = 2.2 * scene_scale;
-        let dr = i as f64 / count as f64;
-        let theta = config::PI2 * dr;
-        let px = r * theta.sin();
-        let py = 0.0;
-        let pz = r * theta.cos();
-        let s = scene_scale;
-        let offset = 0.45;
-
-        scene.add(Box::new(BvhMesh::from_mesh(ObjLoader::load(
-            "models/armadilo_1000.obj",
-            Matrix44::translate(px, py, pz) * Matrix44::rotate_y(theta) * Matrix44::scale_linear(s),
-            if i % 2 == 0 {
-                Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new((offset + dr).fract(), 0.2, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                }
-            } else {
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(hsv_to_rgb(Color::new((offset + dr).fract(), 1.0, 1.0))),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05 * i as f64)),
-                }
-            },
-        ))));
-
-        i += 1;
-    }
-
-    (camera, scene)
-}
-
-#[allow(dead_code)]
-fn init_scene_rtcamp6_v4() -> (Camera, Scene) {
-    let camera = Camera::new(
-        Vector3::new(0.0, 1.0, 6.0), // eye
-        Vector3::new(0.0, 0.0, 0.0), // target
-        Vector3::new(0.0, 1.0, 0.0).normalize(), // y_up
-        30.0, // fov
-
-        LensShape::Circle, // lens shape
-        0.2 * 0.0,// aperture
-        4.9,// focus_distance
-    );
-
-    let scene = Scene {
-        elements: vec![
-            // Mesh
-            Box::new(BvhMesh::from_mesh(ObjLoader::load(
-                "models/fractal_icosahedron.obj",
-                Matrix44::scale_linear(1.0) * Matrix44::translate(0.0, 0.0, 0.0) * Matrix44::rotate_y(0.3),
-                /*Material {
-                    surface: SurfaceType::Refraction { refractive_index: 1.5 },
-                    albedo: Texture::from_color(Color::new(0.7, 0.7, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.1)),
-                },*/
-                Material {
-                    surface: SurfaceType::GGX { f0: 0.8 },
-                    albedo: Texture::from_color(Color::new(1.0, 1.0, 1.0)),
-                    emission: Texture::black(),
-                    roughness: Texture::from_color(Color::from_one(0.05)),
-                },
-            ))),
-
-            // camera light
-            Box::new(Sphere {
-                center: camera.eye - camera.forward,
-                radius: 0.001,
-                material: Material {
-                    surface: SurfaceType::Diffuse,
-                    albedo: Texture::black(),
-                    emission: Texture::from_color(Color::from_one(1000.0)),
-                    roughness: Texture::black(),
-                },
-            }),
-        ],
-        skybox: Skybox::new(
-            "textures/cube/Ryfjallet/posx.jpg",
-            "textures/cube/Ryfjallet/negx.jpg",
-            "textures/cube/Ryfjallet/posy.jpg",
-            "textures/cube/Ryfjallet/negy.jpg",
-            "textures/cube/Ryfjallet/posz.jpg",
-            "textures/cube/Ryfjallet/negz.jpg",
-            &Vector3::from_one(1.0),
-        ),
-    };
-
-    (camera, scene)
-}
-
-fn render<R: Renderer>(renderer: &mut R, width: u32, height: u32, camera: &Camera, scene: Scene) -> u32 {
-    let mut imgbuf = image::ImageBuffer::new(width, height);
-    let sampled = renderer.render(&BvhScene::from_scene(scene), camera, &mut imgbuf);
-    let _ = image::ImageRgb8(imgbuf).save("result.png");
-    sampled
 }
 
 fn print_usage(program: &str, opts: Options) {
@@ -1230,11 +144,35 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("", "help", "print this help menu");
     opts.optflag("d", "debug", "use debug mode");
+    opts.optflag("", "bvh-compare", "print statistics of median and SAH BVHs and exit");
     opts.optopt("w", "width", "output resolution width", "WIDTH");
     opts.optopt("h", "height", "output resolution height", "HEIGHT");
     opts.optopt("s", "sampling", "sampling limit", "SAMPLING");
     opts.optopt("t", "time", "time limit sec", "TIME");
     opts.optopt("i", "interval", "report interval sec", "INTERVAL");
+    opts.optopt("", "scene", "scene description file", "FILE");
+    opts.optopt("", "settings", "render settings file", "FILE");
+    opts.optopt("", "checkpoint", "save a checkpoint at every report interval", "FILE");
+    opts.optflag("", "resume", "resume rendering from the checkpoint");
+    opts.optopt("", "hdr-output", "also save the linear image before tone mapping (.pfm, .hdr, .exr)", "FILE");
+    opts.optopt("", "supersampling", "supersampling count per axis", "N");
+    opts.optopt("", "pathtracing-bounce-limit", "hard limit of path tracing bounces", "N");
+    opts.optopt("", "russian-roulette-depth", "bounces before Russian roulette may end a path", "N");
+    opts.optopt("", "mis-heuristic", "MIS heuristic (balance, power)", "HEURISTIC");
+    opts.optopt("", "light-sampling", "how NEE picks lights (all, power, bvh)", "METHOD");
+    opts.optopt("", "spectral-rendering", "sample a wavelength per path for dispersion (true, false)", "BOOL");
+    opts.optopt("", "bvh-build-method", "BVH build method (median, sah)", "METHOD");
+    opts.optopt("", "bvh-max-leaf-size", "max primitives in a BVH leaf for SAH", "N");
+    opts.optopt("", "bvh-bin-count", "number of bins for SAH", "N");
+    opts.optopt("", "bvh-traversal-cost", "SAH cost of traversing a BVH node", "COST");
+    opts.optopt("", "bvh-intersection-cost", "SAH cost of intersecting a primitive", "COST");
+    opts.optopt("", "tone-mapping-mode", "tone mapping mode (none, reinhard)", "MODE");
+    opts.optopt("", "tone-mapping-exposure", "tone mapping exposure", "EXPOSURE");
+    opts.optopt("", "tone-mapping-white-point", "tone mapping white point", "WHITE");
+    opts.optopt("", "bilateral-filter-iteration", "bilateral filter iteration count", "N");
+    opts.optopt("", "bilateral-filter-diameter", "bilateral filter diameter", "N");
+    opts.optopt("", "bilateral-filter-sigma-i", "bilateral filter sigma of intensity", "SIGMA");
+    opts.optopt("", "bilateral-filter-sigma-s", "bilateral filter sigma of space", "SIGMA");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
@@ -1255,36 +193,111 @@ fn main() {
     let time_limit_sec = matches.opt_get_default("t", 123.0).unwrap();// 123秒以内に終了
     let report_interval_sec = matches.opt_get_default("i", 15.0).unwrap();// 15秒ごとに途中結果を出力
 
+    let scene_path = matches.opt_str("scene").unwrap_or_else(|| "scenes/rtcamp6_v3_1.scene".to_string());
+    let checkpoint_path = matches.opt_str("checkpoint");
+    let resume = matches.opt_present("resume");
+    if resume && checkpoint_path.is_none() {
+        eprintln!("--resume requires --checkpoint");
+        process::exit(1);
+    }
+    let hdr_output = matches.opt_str("hdr-output");
+    if let Some(ref path) = hdr_output {
+        if let Err(message) = HdrFormat::from_path(path) {
+            eprintln!("{}", message);
+            process::exit(1);
+        }
+    }
+
+    // 設定ファイルの値をコマンドライン引数で上書きする
+    let mut settings = match matches.opt_str("settings") {
+        Some(path) => match RenderSettings::load(&path) {
+            Ok(settings) => settings,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        None => RenderSettings::default(),
+    };
+    for key in RenderSettings::keys() {
+        if let Some(value) = matches.opt_str(&key.replace('_', "-")) {
+            if let Err(message) = settings.set(key, &value) {
+                eprintln!("{}", message);
+                process::exit(1);
+            }
+        }
+    }
+
+    let output = OutputOptions { width, height, hdr_output };
+
     let mut f = BufWriter::new(fs::File::create("result.txt").unwrap());
     let total_begin = time::now();
     {
         tee(&mut f, &format!("num threads: {}.", rayon::current_num_threads()));
         tee(&mut f, &format!("resolution: {}x{}.", width, height));
-        tee(&mut f, &format!("max sampling: {}x{} spp.", sampling, config::SUPERSAMPLING * config::SUPERSAMPLING));
+        tee(&mut f, &format!("max sampling: {}x{} spp.", sampling, settings.samples_per_pixel()));
         tee(&mut f, &format!("time limit: {:.2} sec.", time_limit_sec));
         tee(&mut f, &format!("report interval: {:.2} sec.", report_interval_sec));
+        tee(&mut f, &format!("scene: {}.", scene_path));
 
         let init_scene_begin = time::now();
 
-        //let (camera, scene) = init_scene_rtcamp5();
-        //let (camera, scene) = init_scene_material_examples();
-        //let (camera, scene) = init_scene_tbf3();
-        //let (camera, scene) = init_scene_simple();
-        let (camera, scene) = init_scene_rtcamp6_v3_1();
+        let (camera, scene) = match SceneLoader::load(&scene_path, &settings) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        };
 
         let init_scene_end = time::now();
         let init_scene_sec = (init_scene_end - init_scene_begin).num_milliseconds() as f64 * 0.001;
         tee(&mut f, &format!("init scene: {:.2} sec.", init_scene_sec));
 
+        if matches.opt_present("bvh-compare") {
+            compare_bvh(&camera, &scene, &settings, width, height);
+            return;
+        }
+
         let sampled = if debug_mode {
             let mut debug_renderer = DebugRenderer { mode: DebugRenderMode::FocalPlane };
-            render(&mut debug_renderer, width, height, &camera, scene)
+            render(&mut debug_renderer, &camera, scene, &settings, &output)
         } else {
             let mut pathtracing_renderer = PathTracingRenderer::new(sampling, time_limit_sec, report_interval_sec);
-            render(&mut pathtracing_renderer, width, height, &camera, scene)
+            if let Some(ref path) = checkpoint_path {
+                // シーンファイルと累積に影響する設定が一致する場合のみ再開できる
+                let scene_source = fs::read(&scene_path).unwrap_or_default();
+                let hash = checkpoint::hash(&[&scene_source, settings.sampling_signature().as_bytes()]);
+                pathtracing_renderer.enable_checkpoint(path, hash);
+
+                if resume {
+                    let checkpoint = match Checkpoint::load(path) {
+                        Ok(checkpoint) => checkpoint,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            process::exit(1);
+                        }
+                    };
+                    if checkpoint.width != width || checkpoint.height != height {
+                        eprintln!("{}: checkpoint resolution {}x{} does not match {}x{}", path, checkpoint.width, checkpoint.height, width, height);
+                        process::exit(1);
+                    }
+                    if checkpoint.hash != hash {
+                        eprintln!("{}: checkpoint does not match the scene or settings", path);
+                        process::exit(1);
+                    }
+                    if checkpoint.sampling >= sampling {
+                        eprintln!("{}: checkpoint already has {} samplings (limit {})", path, checkpoint.sampling, sampling);
+                        process::exit(1);
+                    }
+                    tee(&mut f, &format!("resume: {}x{} spp from {}.", checkpoint.sampling, settings.samples_per_pixel(), path));
+                    pathtracing_renderer.resume(checkpoint);
+                }
+            }
+            render(&mut pathtracing_renderer, &camera, scene, &settings, &output)
         };
 
-        tee(&mut f, &format!("sampled: {}x{} spp.", sampled, config::SUPERSAMPLING * config::SUPERSAMPLING));
+        tee(&mut f, &format!("sampled: {}x{} spp.", sampled, settings.samples_per_pixel()));
     }
     let total_end = time::now();
 
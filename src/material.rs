@@ -2,8 +2,12 @@ use texture::Texture;
 use color::Color;
 use vector::Vector3;
 use camera::Ray;
+use vector::Vector2;
 use config;
-use math::saturate;
+use math::{saturate, clamp};
+use principled::{PrincipledTextures, PrincipledParameters};
+use spectrum::{Dispersion, rgb_to_spectrum, interpolate_rgb_samples};
+use medium::Medium;
 
 #[derive(Clone, Debug)]
 pub enum SurfaceType {
@@ -12,14 +16,77 @@ pub enum SurfaceType {
     Refraction { refractive_index: f64 },
     GGX { f0: f64 },
     GGXRefraction { refractive_index: f64 },
+    // 複素屈折率 eta + ik をチャンネルごとに持つ金属。GGX の法線分布を使う
+    Conductor { eta: Color, k: Color },
+    // Disney の Principled BSDF。パラメータは Material::principled に持つ
+    Principled,
+    // 屈折せずに光をそのまま通す面。関与媒質の境界に使う
+    Transparent,
 }
 
+// 代表的な金属の複素屈折率（RGB それぞれ 650nm, 550nm, 450nm 付近の値）
+// 名前と (eta, k) の組
+pub const CONDUCTOR_PRESETS: &[(&str, [f64; 3], [f64; 3])] = &[
+    ("Au", [0.143, 0.374, 1.442], [3.983, 2.385, 1.603]),
+    ("Ag", [0.155, 0.117, 0.138], [4.828, 3.122, 2.147]),
+    ("Cu", [0.200, 0.924, 1.102], [3.912, 2.452, 2.142]),
+    ("Al", [1.657, 0.880, 0.521], [9.224, 6.270, 4.837]),
+];
+
 #[derive(Debug)]
 pub struct Material {
     pub surface: SurfaceType,
     pub albedo: Texture,
     pub emission: Texture,
     pub roughness: Texture,
+    pub principled: PrincipledTextures,
+    // 光が屈折して入る物体の内部の関与媒質。散乱しない場合は Beer-Lambert の法則で減衰させるだけになる
+    pub medium: Option<Medium>,
+    // 分光レンダリングで使う屈折率の波長依存性。RGB でのレンダリングでは surface の屈折率を使う
+    pub dispersion: Option<Dispersion>,
+}
+
+impl Material {
+    // uv の位置のマテリアルを求める
+    pub fn sample(&self, uv: Vector2) -> PointMaterial {
+        match self.surface {
+            SurfaceType::Principled => {
+                // base color は BSDF の中で扱うので albedo は 1 とする
+                PointMaterial {
+                    surface: self.surface.clone(),
+                    albedo: Color::one(),
+                    emission: self.emission.sample(uv),
+                    roughness: self.roughness.sample(uv).x,
+                    principled: self.principled.sample(uv, self.albedo.sample(uv)),
+                    medium: self.medium.clone(),
+                    dispersion: self.dispersion,
+                }
+            }
+            SurfaceType::Refraction { refractive_index: _ } | SurfaceType::GGXRefraction { refractive_index: _ } => {
+                // 誘電体は境界を通るたびに色を付けず、内部の色は medium の吸収で表す
+                PointMaterial {
+                    surface: self.surface.clone(),
+                    albedo: Color::one(),
+                    emission: self.emission.sample(uv),
+                    roughness: self.roughness.sample(uv).x,
+                    principled: PrincipledParameters::default(),
+                    medium: self.medium.clone(),
+                    dispersion: self.dispersion,
+                }
+            }
+            _ => {
+                PointMaterial {
+                    surface: self.surface.clone(),
+                    albedo: self.albedo.sample(uv),
+                    emission: self.emission.sample(uv),
+                    roughness: self.roughness.sample(uv).x,
+                    principled: PrincipledParameters::default(),
+                    medium: self.medium.clone(),
+                    dispersion: self.dispersion,
+                }
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -28,6 +95,9 @@ pub struct PointMaterial {
     pub albedo: Color,
     pub emission: Color,
     pub roughness: f64,
+    pub principled: PrincipledParameters,
+    pub medium: Option<Medium>,
+    pub dispersion: Option<Dispersion>,
 }
 
 pub struct SampleResult {
@@ -35,27 +105,74 @@ pub struct SampleResult {
 
     // reflectance = bsdf * cos(normal, light) / pdf
     // 重点的サンプリングを行うと、bsdf * cos を pdf が打ち消すケースが多いので、このような定義とした
-    pub reflectance: f64,
+    // 金属のフレネル反射率は波長によって異なるので色とする
+    pub reflectance: Color,
 }
 
 impl PointMaterial {
+    // 分光レンダリングのために、色を波長 lambda でのスペクトルの値（すべてのチャンネルが同じ値）に置き換える
+    pub fn set_wavelength(&mut self, lambda: f64) {
+        let to_spectrum = |color: &Color| Color::from_one(rgb_to_spectrum(color, lambda));
+        self.albedo = to_spectrum(&self.albedo);
+        self.emission = to_spectrum(&self.emission);
+        self.medium = self.medium.as_ref().map(|medium| medium.at_wavelength(lambda));
+        self.principled.base_color = to_spectrum(&self.principled.base_color);
+
+        self.surface = match (&self.surface, self.dispersion) {
+            (&SurfaceType::Refraction { refractive_index: _ }, Some(dispersion)) => {
+                SurfaceType::Refraction { refractive_index: dispersion.refractive_index(lambda) }
+            }
+            (&SurfaceType::GGXRefraction { refractive_index: _ }, Some(dispersion)) => {
+                SurfaceType::GGXRefraction { refractive_index: dispersion.refractive_index(lambda) }
+            }
+            (&SurfaceType::Conductor { eta, k }, _) => SurfaceType::Conductor {
+                eta: Color::from_one(interpolate_rgb_samples(&eta, lambda)),
+                k: Color::from_one(interpolate_rgb_samples(&k, lambda)),
+            },
+            (surface, _) => surface.clone(),
+        };
+    }
+
     pub fn nee_available(&self) -> bool {
         match self.surface {
             SurfaceType::Diffuse => true,
             SurfaceType::GGX { f0: _ } => true,
+            SurfaceType::Conductor { eta: _, k: _ } => true,
+            SurfaceType::GGXRefraction { refractive_index: _ } => true,
+            SurfaceType::Principled => true,
 
             SurfaceType::Specular => false,
             SurfaceType::Refraction { refractive_index: _ } => false,
-            SurfaceType::GGXRefraction { refractive_index: _ } => false,
+            SurfaceType::Transparent => false,
+        }
+    }
+
+    // 光が屈折して物体の内部に入るか
+    pub fn has_interior(&self) -> bool {
+        match self.surface {
+            SurfaceType::Refraction { refractive_index: _ } => true,
+            SurfaceType::GGXRefraction { refractive_index: _ } => true,
+            SurfaceType::Principled => self.principled.is_transmissive(),
+            SurfaceType::Transparent => true,
+            _ => false,
         }
     }
 
-    pub fn bsdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> f64 {
+    // 光源が表面の裏側にあっても寄与するか
+    pub fn transmissive(&self) -> bool {
         match self.surface {
-            SurfaceType::Diffuse => config::PI.recip(),
+            SurfaceType::GGXRefraction { refractive_index: _ } => true,
+            SurfaceType::Principled => self.principled.is_transmissive(),
+            _ => false,
+        }
+    }
+
+    pub fn bsdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> Color {
+        match self.surface {
+            SurfaceType::Diffuse => Color::from_one(config::PI.recip()),
             SurfaceType::Specular => unimplemented!(),
             SurfaceType::Refraction { refractive_index: _ } => unimplemented!(),
-            SurfaceType::GGX { f0 } => {
+            SurfaceType::GGX { f0: _ } | SurfaceType::Conductor { eta: _, k: _ } => {
                 // https://schuttejoe.github.io/post/ggximportancesamplingpart1/
                 // i: view, g: light, m: half
 
@@ -65,7 +182,7 @@ impl PointMaterial {
 
                 let l_dot_n = light.dot(normal);
                 if l_dot_n.is_sign_negative() {
-                    return 0.0;
+                    return Color::zero();
                 }
 
                 let v_dot_n = view.dot(normal);
@@ -73,18 +190,47 @@ impl PointMaterial {
                 let h_dot_n = half.dot(normal);
 
                 // D: Microfacet Distribution Functions GGX(Trowbridge-Reitz model)
-                let tmp = 1.0 - (1.0 - alpha2) * h_dot_n * h_dot_n;
-                let d = alpha2 / (config::PI * tmp * tmp);
+                let d = d_ggx(h_dot_n, alpha2);
 
                 // G: Masking-Shadowing Fucntion
                 let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
 
                 // F: Fresnel term
-                let f = f_schlick_f64(v_dot_h, f0);
+                let f = self.fresnel(v_dot_h);
 
-                d * g * f / (4.0 * l_dot_n * v_dot_n)
+                f * (d * g) / (4.0 * l_dot_n * v_dot_n)
             }
-            SurfaceType::GGXRefraction { refractive_index: _ } => unimplemented!()
+            SurfaceType::GGXRefraction { refractive_index } => {
+                let alpha2 = roughness_to_alpha2(self.roughness);
+                Color::from_one(rough_dielectric_bsdf(view, normal, light, refractive_index, alpha2))
+            }
+            SurfaceType::Principled => self.principled.bsdf(self.roughness, view, normal, light),
+            // 光をそのまま通すだけで、散乱する成分を持たない
+            SurfaceType::Transparent => Color::zero(),
+        }
+    }
+
+    // sample で light の方向がサンプリングされる立体角測度の確率密度
+    pub fn pdf(&self, view: &Vector3, normal: &Vector3, light: &Vector3) -> f64 {
+        match self.surface {
+            SurfaceType::Diffuse => light.dot(normal).max(0.0) * config::PI.recip(),
+            SurfaceType::Specular => unimplemented!(),
+            SurfaceType::Refraction { refractive_index: _ } => unimplemented!(),
+            SurfaceType::GGX { f0: _ } | SurfaceType::Conductor { eta: _, k: _ } => {
+                let alpha2 = roughness_to_alpha2(self.roughness);
+                let half = (*light + *view).normalize();
+                let v_dot_h = view.dot(&half);
+                let h_dot_n = half.dot(normal);
+
+                // ハーフベクトルの確率密度 D * cos(half, normal) を反射方向の確率密度に変換する
+                d_ggx(h_dot_n, alpha2) * h_dot_n / (4.0 * v_dot_h.abs())
+            }
+            SurfaceType::GGXRefraction { refractive_index } => {
+                let alpha2 = roughness_to_alpha2(self.roughness);
+                rough_dielectric_pdf(view, normal, light, refractive_index, alpha2)
+            }
+            SurfaceType::Principled => self.principled.pdf(self.roughness, view, normal, light),
+            SurfaceType::Transparent => 0.0,
         }
     }
 
@@ -98,7 +244,7 @@ impl PointMaterial {
                         origin: *position + *normal * config::OFFSET,
                         direction: importance_sample_diffuse(random, normal),
                     },
-                    reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                    reflectance: Color::one(),// bsdf * cos と pdf が打ち消し合う
                 })
             }
             SurfaceType::Specular => {
@@ -107,13 +253,13 @@ impl PointMaterial {
                         origin: *position + *normal * config::OFFSET,
                         direction: ray.reflect(normal),
                     },
-                    reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                    reflectance: Color::one(),// bsdf * cos と pdf が打ち消し合う
                 })
             }
             SurfaceType::Refraction { refractive_index } => {
                 sample_refraction(random, position, &ray, normal, refractive_index)
             }
-            SurfaceType::GGX { f0 } => {
+            SurfaceType::GGX { f0: _ } | SurfaceType::Conductor { eta: _, k: _ } => {
                 let alpha2 = roughness_to_alpha2(self.roughness);
                 let half = importance_sample_ggx_half(random, normal, alpha2);
                 let next_direction = ray.reflect(&half);
@@ -131,7 +277,7 @@ impl PointMaterial {
                     let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
 
                     // F: Fresnel term
-                    let f = f_schlick_f64(v_dot_h, f0);
+                    let f = self.fresnel(v_dot_h);
 
                     Some(SampleResult {
                         ray: Ray {
@@ -144,9 +290,52 @@ impl PointMaterial {
             }
             SurfaceType::GGXRefraction { refractive_index } => {
                 let alpha2 = roughness_to_alpha2(self.roughness);
-                let half = importance_sample_ggx_half(random, normal, alpha2);
-                sample_refraction(random, position, &ray, &half, refractive_index)
+                let light = sample_rough_dielectric(random, view, normal, refractive_index, alpha2)?;
+                self.sample_result(position, view, normal, &light)
             }
+            SurfaceType::Principled => {
+                let light = self.principled.sample_direction(self.roughness, random, view, normal)?;
+                self.sample_result(position, view, normal, &light)
+            }
+            SurfaceType::Transparent => {
+                // 面の反対側にレイの原点を移動する
+                let offset = if ray.dot(normal) < 0.0 { *normal * -config::OFFSET } else { *normal * config::OFFSET };
+                Some(SampleResult {
+                    ray: Ray {
+                        origin: *position + offset,
+                        direction: ray,
+                    },
+                    reflectance: Color::one(),
+                })
+            }
+        }
+    }
+
+    // サンプリングした light の方向から、bsdf と pdf を評価して SampleResult を作る
+    fn sample_result(&self, position: &Vector3, view: &Vector3, normal: &Vector3, light: &Vector3) -> Option<SampleResult> {
+        let pdf = self.pdf(view, normal, light);
+        if pdf <= 0.0 || !pdf.is_finite() {
+            return None;
+        }
+
+        // 光の方向の側にレイの原点を移動する
+        let l_dot_n = light.dot(normal);
+        let offset = if l_dot_n > 0.0 { *normal * config::OFFSET } else { *normal * -config::OFFSET };
+        Some(SampleResult {
+            ray: Ray {
+                origin: *position + offset,
+                direction: *light,
+            },
+            reflectance: self.bsdf(view, normal, light) * (l_dot_n.abs() / pdf),
+        })
+    }
+
+    // マイクロファセットの BRDF のフレネル項
+    fn fresnel(&self, v_dot_h: f64) -> Color {
+        match self.surface {
+            SurfaceType::GGX { f0 } => Color::from_one(f_schlick_f64(v_dot_h, f0)),
+            SurfaceType::Conductor { eta, k } => f_conductor(v_dot_h, &eta, &k),
+            _ => unreachable!(),
         }
     }
 }
@@ -164,7 +353,7 @@ fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, nor
                 origin: *position + config::OFFSET * oriented_normal,
                 direction: reflect_direction,
             },
-            reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+            reflectance: Color::one(),// bsdf * cos と pdf が打ち消し合う
         })
     } else {
         // フレネル反射率rの計算
@@ -183,7 +372,7 @@ fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, nor
                     origin: *position + config::OFFSET * oriented_normal,
                     direction: reflect_direction,
                 },
-                reflectance: 1.0,// bsdf * cos と pdf が打ち消し合う
+                reflectance: Color::one(),// bsdf * cos と pdf が打ち消し合う
             })
         } else {
             // 屈折
@@ -192,14 +381,14 @@ fn sample_refraction(random: (f64, f64), position: &Vector3, view: &Vector3, nor
                     origin: *position - config::OFFSET * oriented_normal,// 物体内部にレイの原点を移動する
                     direction: refract_direction,
                 },
-                reflectance: nnt * nnt,// 立体角の変化に伴う放射輝度の補正
+                reflectance: Color::from_one(nnt * nnt),// 立体角の変化に伴う放射輝度の補正
             })
         }
     }
 }
 
 // 法線を基準とした空間の基底ベクトルを計算
-fn get_tangent_space_basis_gram_schmidtd(normal: &Vector3) -> (Vector3, Vector3) {
+pub fn get_tangent_space_basis_gram_schmidtd(normal: &Vector3) -> (Vector3, Vector3) {
     let up = if normal.x.abs() > config::EPS {
         Vector3::new(0.0, 1.0, 0.0)
     } else {
@@ -224,7 +413,7 @@ fn get_tangent_space_basis_revised_onb(normal: &Vector3) -> (Vector3, Vector3) {
 
 // 完全拡散反射のcos項による重点サンプリング
 // https://github.com/githole/edupt/blob/master/radiance.h
-fn importance_sample_diffuse(random: (f64, f64), normal: &Vector3) -> Vector3 {
+pub fn importance_sample_diffuse(random: (f64, f64), normal: &Vector3) -> Vector3 {
     let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(normal);
 
     // θ,φは極座標系の偏角。cosθにより重点サンプリングをしたい
@@ -247,17 +436,18 @@ fn importance_sample_diffuse(random: (f64, f64), normal: &Vector3) -> Vector3 {
     (tangent * phi.cos() + binormal * phi.sin()) * random.1.sqrt() + *normal * (1.0 - random.1).sqrt()
 }
 
-fn roughness_to_alpha2(roughness: f64) -> f64 {
+pub fn roughness_to_alpha2(roughness: f64) -> f64 {
     // UE4の結果に近づけたいなら、alpha = roughness にする
     // alpha = roughness * roughness の実装をよく見かける
-    let alpha = roughness;
+    // alpha = 0 では D が評価できないので、鏡面とほぼ同じになる値で下限を設ける
+    let alpha = roughness.max(1e-3);
     alpha * alpha
 }
 
 // Unreal Engine 4 で利用されている ImportanceSampleGGX を移植
 // cos項による重点サンプリングのためのハーフベクトルを計算
 // http://project-asura.com/blog/?p=3124
-fn importance_sample_ggx_half(random: (f64, f64), normal: &Vector3, alpha2: f64) -> Vector3 {
+pub fn importance_sample_ggx_half(random: (f64, f64), normal: &Vector3, alpha2: f64) -> Vector3 {
     let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(normal);
 
     let phi = config::PI2 * random.0;
@@ -268,12 +458,17 @@ fn importance_sample_ggx_half(random: (f64, f64), normal: &Vector3, alpha2: f64)
     tangent * h.x + binormal * h.y + *normal * h.z
 }
 
+pub fn d_ggx(h_dot_n: f64, alpha2: f64) -> f64 {
+    let tmp = 1.0 - (1.0 - alpha2) * h_dot_n * h_dot_n;
+    alpha2 / (config::PI * tmp * tmp)
+}
+
 fn g_smith_joint_lambda(x_dot_n: f64, alpha2: f64) -> f64 {
     let a = (x_dot_n * x_dot_n).recip() - 1.0;
     0.5 * (1.0 + alpha2 * a).sqrt() - 0.5
 }
 
-fn g_smith_joint(l_dot_n: f64, v_dot_n: f64, alpha2: f64) -> f64 {
+pub fn g_smith_joint(l_dot_n: f64, v_dot_n: f64, alpha2: f64) -> f64 {
     let lambda_l = g_smith_joint_lambda(l_dot_n, alpha2);
     let lambda_v = g_smith_joint_lambda(v_dot_n, alpha2);
     (1.0 + lambda_l + lambda_v).recip()
@@ -282,3 +477,164 @@ fn g_smith_joint(l_dot_n: f64, v_dot_n: f64, alpha2: f64) -> f64 {
 fn f_schlick_f64(v_dot_h: f64, f0: f64) -> f64 {
     f0 + (1.0 - f0) * (1.0 - v_dot_h).powi(5)
 }
+
+// 導体のフレネル反射率（S波とP波の平均）をチャンネルごとに求める
+// https://seblagarde.wordpress.com/2013/04/29/memo-on-fresnel-equations/
+fn f_conductor(cos_i: f64, eta: &Color, k: &Color) -> Color {
+    let f = |eta: f64, k: f64| {
+        let cos2 = saturate(cos_i * cos_i);
+        let sin2 = 1.0 - cos2;
+        let eta2 = eta * eta;
+        let k2 = k * k;
+
+        let t0 = eta2 - k2 - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t2 = 2.0 * cos_i.max(0.0) * a;
+        let r_s = (t1 - t2) / (t1 + t2);
+
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let r_p = r_s * (t3 - t4) / (t3 + t4);
+
+        0.5 * (r_p + r_s)
+    };
+    Color::new(f(eta.x, k.x), f(eta.y, k.y), f(eta.z, k.z))
+}
+
+// 誘電体のフレネル反射率（S波とP波の平均）
+// refractive_index は法線の裏側の屈折率で、cos_i が負の場合は裏側からの入射とする
+pub fn f_dielectric(cos_i: f64, refractive_index: f64) -> f64 {
+    let (cos_i, eta) = if cos_i < 0.0 { (-cos_i, refractive_index.recip()) } else { (cos_i, refractive_index) };
+    let cos_i = saturate(cos_i);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // 全反射
+        return 1.0;
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// Walter et al. の粗い誘電体の BSDF
+// B. Walter, S. R. Marschner, H. Li, K. E. Torrance, "Microfacet Models for Refraction through Rough Surfaces", EGSR 2007
+// normal は物体の外側を向き、refractive_index は物体の内側の屈折率とする。view と light は表裏のどちら側にあってもよい
+pub fn rough_dielectric_bsdf(view: &Vector3, normal: &Vector3, light: &Vector3, refractive_index: f64, alpha2: f64) -> f64 {
+    let v_dot_n = view.dot(normal);
+    let l_dot_n = light.dot(normal);
+    let (half, eta) = match rough_dielectric_half(view, normal, light, refractive_index) {
+        Some(v) => v,
+        None => return 0.0,
+    };
+    let v_dot_h = view.dot(&half);
+    let l_dot_h = light.dot(&half);
+
+    let d = d_ggx(half.dot(normal), alpha2);
+    let g = g_smith_joint(l_dot_n.abs(), v_dot_n.abs(), alpha2);
+    let f = f_dielectric(v_dot_h, refractive_index);
+
+    if eta == 1.0 {
+        d * g * f / (4.0 * l_dot_n.abs() * v_dot_n.abs())
+    } else {
+        // 放射輝度は屈折で 1 / eta^2 倍になる
+        let denominator = l_dot_h + v_dot_h / eta;
+        d * (1.0 - f) * g * (l_dot_h * v_dot_h / (denominator * denominator * l_dot_n * v_dot_n)).abs() / (eta * eta)
+    }
+}
+
+// sample_rough_dielectric で light の方向がサンプリングされる立体角測度の確率密度
+pub fn rough_dielectric_pdf(view: &Vector3, normal: &Vector3, light: &Vector3, refractive_index: f64, alpha2: f64) -> f64 {
+    let (half, eta) = match rough_dielectric_half(view, normal, light, refractive_index) {
+        Some(v) => v,
+        None => return 0.0,
+    };
+    let v_dot_h = view.dot(&half);
+    let l_dot_h = light.dot(&half);
+    let h_dot_n = half.dot(normal);
+    let reflect_probability = rough_dielectric_reflect_probability(view, normal, refractive_index);
+
+    // ハーフベクトルの確率密度 D * cos(half, normal) を light の方向の確率密度に変換する
+    let pdf_half = d_ggx(h_dot_n, alpha2) * h_dot_n;
+    if eta == 1.0 {
+        reflect_probability * pdf_half / (4.0 * v_dot_h.abs())
+    } else {
+        let denominator = l_dot_h + v_dot_h / eta;
+        (1.0 - reflect_probability) * pdf_half * l_dot_h.abs() / (denominator * denominator)
+    }
+}
+
+// 反射か屈折を選んでから GGX のハーフベクトルをサンプリングして、光の方向を返す
+// 屈折できないハーフベクトルが選ばれた場合は None
+pub fn sample_rough_dielectric(random: (f64, f64), view: &Vector3, normal: &Vector3, refractive_index: f64, alpha2: f64) -> Option<Vector3> {
+    let reflect_probability = rough_dielectric_reflect_probability(view, normal, refractive_index);
+    let reflect = random.0 < reflect_probability;
+    let u = if reflect {
+        random.0 / reflect_probability
+    } else {
+        (random.0 - reflect_probability) / (1.0 - reflect_probability)
+    };
+    let half = importance_sample_ggx_half((u.min(1.0 - f64::EPSILON), random.1), normal, alpha2);
+
+    let v_dot_h = view.dot(&half);
+    if v_dot_h * view.dot(normal) <= 0.0 {
+        return None;
+    }
+
+    let light = if reflect {
+        (-*view).reflect(&half)
+    } else {
+        // half と同じ側から入射するように向きを揃えて屈折させる
+        let (oriented_half, eta) = if v_dot_h > 0.0 { (half, refractive_index) } else { (-half, refractive_index.recip()) };
+        let refracted = (-*view).refract(&oriented_half, eta.recip());
+        if refracted == Vector3::zero() {
+            return None;
+        }
+        refracted
+    };
+
+    // 反射なら view と同じ側、屈折なら反対側に出ていなければならない
+    if (light.dot(normal) * view.dot(normal) > 0.0) != reflect {
+        return None;
+    }
+    Some(light)
+}
+
+// 反射と屈折のどちらを選ぶかの確率
+// マクロな法線に対するフレネル反射率を使い、どちらも一定の確率で選ばれるように制限する
+fn rough_dielectric_reflect_probability(view: &Vector3, normal: &Vector3, refractive_index: f64) -> f64 {
+    clamp(f_dielectric(view.dot(normal), refractive_index), 0.05, 0.95)
+}
+
+// view と light からマイクロファセットの法線（物体の外側向き）と、view の側から見た light の側の相対屈折率を求める
+// 反射の場合の相対屈折率は 1 とする。寄与しない組み合わせであれば None
+fn rough_dielectric_half(view: &Vector3, normal: &Vector3, light: &Vector3, refractive_index: f64) -> Option<(Vector3, f64)> {
+    let v_dot_n = view.dot(normal);
+    let l_dot_n = light.dot(normal);
+    if v_dot_n == 0.0 || l_dot_n == 0.0 {
+        return None;
+    }
+
+    let eta = if v_dot_n * l_dot_n > 0.0 {
+        1.0
+    } else if v_dot_n > 0.0 {
+        refractive_index
+    } else {
+        refractive_index.recip()
+    };
+    let half = *light * eta + *view;
+    if half == Vector3::zero() {
+        return None;
+    }
+    let half = half.normalize();
+    let half = if half.dot(normal) < 0.0 { -half } else { half };
+
+    // マイクロファセットの裏側から入射・出射する組み合わせは寄与しない
+    if half.dot(view) * v_dot_n <= 0.0 || half.dot(light) * l_dot_n <= 0.0 {
+        return None;
+    }
+    Some((half, eta))
+}
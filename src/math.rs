@@ -34,3 +34,64 @@ pub fn det(a: &Vector3, b: &Vector3, c: &Vector3) -> f64 {
 pub fn mix(x: &Vector3, y: &Vector3, a: f64) -> Vector3 {
     *x * (1.0 - a) + *y * a
 }
+
+// 軸のインデックス（0: x, 1: y, 2: z）で指定した成分
+pub fn component(v: &Vector3, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+pub fn set_component(v: &mut Vector3, axis: usize, value: f64) {
+    match axis {
+        0 => v.x = value,
+        1 => v.y = value,
+        _ => v.z = value,
+    }
+}
+
+// 重みに比例した確率で離散的な要素を選ぶための累積分布
+pub struct Distribution1D {
+    cdf: Vec<f64>,
+    total: f64,
+}
+
+impl Distribution1D {
+    pub fn new(weights: &[f64]) -> Distribution1D {
+        let mut cdf = Vec::with_capacity(weights.len());
+        let mut total = 0.0;
+        for weight in weights {
+            total += weight.max(0.0);
+            cdf.push(total);
+        }
+        if total > 0.0 {
+            for c in &mut cdf {
+                *c /= total;
+            }
+        }
+        Distribution1D { cdf, total }
+    }
+
+    // 重みの合計
+    pub fn total(&self) -> f64 {
+        self.total
+    }
+
+    // [0, 1) の乱数 u から要素を選び、そのインデックスと、選ばれた区間内で [0, 1) に引き伸ばした u を返す
+    // 引き伸ばした u は別のサンプリングに再利用できる
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let index = self.cdf.partition_point(|&c| c <= u).min(self.cdf.len() - 1);
+        let begin = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        let width = self.cdf[index] - begin;
+        let remapped = if width > 0.0 { ((u - begin) / width).min(1.0 - f64::EPSILON) } else { 0.0 };
+        (index, remapped)
+    }
+
+    // index の要素が選ばれる確率
+    pub fn probability(&self, index: usize) -> f64 {
+        let begin = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        self.cdf[index] - begin
+    }
+}
@@ -17,10 +17,6 @@ impl Matrix44 {
         }
     }
 
-    pub fn scale_linear(s: f64) -> Matrix44 {
-        Matrix44::scale(s, s, s)
-    }
-
     #[cfg_attr(rustfmt, rustfmt_skip)]
     pub fn scale(sx: f64, sy: f64, sz: f64) -> Matrix44 {
         Matrix44 {
@@ -77,6 +73,16 @@ impl Matrix44 {
         }
     }
 
+    pub fn transpose(&self) -> Matrix44 {
+        let mut result = Matrix44::identity();
+        for i in 0..4 {
+            for j in 0..4 {
+                result[i][j] = self[j][i];
+            }
+        }
+        result
+    }
+
     pub fn inverse(&self) -> Matrix44 {
         let mut s = Matrix44::identity();
         let mut t = self.clone();
@@ -0,0 +1,255 @@
+extern crate rand;
+
+use std::sync::Arc;
+use self::rand::Rng;
+
+use color::Color;
+use vector::Vector3;
+use camera::Ray;
+use config;
+use material::get_tangent_space_basis_gram_schmidtd;
+use spectrum::rgb_to_spectrum;
+use volume::VoxelGrid;
+
+// 関与媒質（霧・煙・炎など）
+// 係数は単位長さあたりで、RGB のチャンネルごとに持つ
+// density を指定すると係数に位置ごとの密度を掛けた不均質な媒質になる
+#[derive(Clone, Debug)]
+pub struct Medium {
+    pub absorption: Color,
+    pub scattering: Color,
+    // Henyey-Greenstein の位相関数の非対称パラメータ g（-1〜1、正で前方散乱）
+    pub anisotropy: f64,
+    // 放射輝度。吸収係数に比例して放射する
+    pub emission: Color,
+    // 密度のグリッド。範囲外の密度は 0
+    pub density: Option<Arc<VoxelGrid>>,
+    // 放射輝度に掛けるグリッド（炎の温度分布など）。範囲外は 0
+    pub emission_grid: Option<Arc<VoxelGrid>>,
+}
+
+pub enum MediumSample {
+    // 媒質中の distance の位置で散乱した。weight = 透過率 * 散乱係数 / 確率密度
+    Scatter { distance: f64, weight: Color, emission: Color },
+    // 散乱せずに max_distance まで進んだ。weight = 透過率 / 確率
+    // 途中で吸収された場合の weight は 0
+    Pass { weight: Color, emission: Color },
+}
+
+impl Medium {
+    pub fn new() -> Medium {
+        Medium {
+            absorption: Color::zero(),
+            scattering: Color::zero(),
+            anisotropy: 0.0,
+            emission: Color::zero(),
+            density: None,
+            emission_grid: None,
+        }
+    }
+
+    // 係数が一定で放射しない媒質は、透過率を解析的に求められる
+    fn is_homogeneous(&self) -> bool {
+        self.density.is_none() && self.emission == Color::zero()
+    }
+
+    fn density_at(&self, position: &Vector3) -> f64 {
+        match self.density {
+            Some(ref grid) => grid.lookup(position),
+            None => 1.0,
+        }
+    }
+
+    // 消散係数の上限（majorant）。すべてのチャンネルで共通の値とする
+    fn majorant(&self) -> f64 {
+        let extinction = self.extinction();
+        let max_density = match self.density {
+            Some(ref grid) => grid.max_value(),
+            None => 1.0,
+        };
+        extinction.x.max(extinction.y).max(extinction.z) * max_density
+    }
+
+    // レイの上で密度が 0 でない区間
+    fn ray_range(&self, ray: &Ray, max_distance: f64) -> Option<(f64, f64)> {
+        match self.density {
+            Some(ref grid) => grid.aabb.ray_range(ray)
+                .map(|(tmin, tmax)| (tmin, tmax.min(max_distance)))
+                .filter(|&(tmin, tmax)| tmin < tmax),
+            None => Some((0.0, max_distance)),
+        }
+    }
+
+    // 距離 distance を進んだ白色光が color になる吸収係数
+    // color の成分が (0, 1] の範囲外なら None
+    pub fn absorption_from_color(color: &Color, distance: f64) -> Option<Color> {
+        let f = |c: f64| if c > 0.0 && c <= 1.0 { Some(-c.ln() / distance) } else { None };
+        match (f(color.x), f(color.y), f(color.z)) {
+            (Some(r), Some(g), Some(b)) => Some(Color::new(r, g, b)),
+            _ => None,
+        }
+    }
+
+    pub fn extinction(&self) -> Color {
+        self.absorption + self.scattering
+    }
+
+    fn transmittance(&self, distance: f64) -> Color {
+        (-self.extinction() * distance).exp()
+    }
+
+    // 分光レンダリングのために、係数を波長 lambda での値に置き換える
+    pub fn at_wavelength(&self, lambda: f64) -> Medium {
+        let to_spectrum = |color: &Color| Color::from_one(rgb_to_spectrum(color, lambda));
+        Medium {
+            absorption: to_spectrum(&self.absorption),
+            scattering: to_spectrum(&self.scattering),
+            anisotropy: self.anisotropy,
+            emission: to_spectrum(&self.emission),
+            density: self.density.clone(),
+            emission_grid: self.emission_grid.clone(),
+        }
+    }
+
+    // ray を max_distance まで進む間の透過率
+    // 不均質な媒質では ratio tracking で推定する
+    pub fn estimate_transmittance<R: Rng>(&self, ray: &Ray, max_distance: f64, rng: &mut R) -> Color {
+        if self.is_homogeneous() {
+            return self.transmittance(max_distance);
+        }
+
+        let (mut t, tmax) = match self.ray_range(ray, max_distance) {
+            Some(range) => range,
+            None => return Color::one(),
+        };
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return Color::one();
+        }
+
+        let extinction = self.extinction();
+        let mut transmittance = Color::one();
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if t >= tmax {
+                return transmittance;
+            }
+            let density = self.density_at(&(ray.origin + ray.direction * t));
+            transmittance *= Color::one() - extinction * (density / majorant);
+            if transmittance == Color::zero() {
+                return transmittance;
+            }
+        }
+    }
+
+    // 散乱する距離をサンプリングする
+    // 不均質な媒質や放射する媒質では delta tracking を行い、
+    // 衝突ごとに放射を足し合わせ、吸収・散乱・null 衝突をチャンネルの平均の確率で選ぶ
+    pub fn sample_distance<R: Rng>(&self, ray: &Ray, max_distance: f64, rng: &mut R) -> MediumSample {
+        if self.is_homogeneous() {
+            return self.sample_homogeneous_distance(rng.gen(), max_distance);
+        }
+
+        let (mut t, tmax) = match self.ray_range(ray, max_distance) {
+            Some(range) => range,
+            None => return MediumSample::Pass { weight: Color::one(), emission: Color::zero() },
+        };
+        let majorant = self.majorant();
+        if majorant <= 0.0 {
+            return MediumSample::Pass { weight: Color::one(), emission: Color::zero() };
+        }
+
+        let mut weight = Color::one();
+        let mut emission = Color::zero();
+        loop {
+            t -= (1.0 - rng.gen::<f64>()).ln() / majorant;
+            if t >= tmax {
+                return MediumSample::Pass { weight, emission };
+            }
+
+            let position = ray.origin + ray.direction * t;
+            let density = self.density_at(&position);
+            let absorption = self.absorption * density;
+            let scattering = self.scattering * density;
+
+            if self.emission != Color::zero() {
+                let scale = match self.emission_grid {
+                    Some(ref grid) => grid.lookup(&position),
+                    None => 1.0,
+                };
+                emission += weight * absorption * self.emission * (scale / majorant);
+            }
+
+            let null = Color::from_one(majorant) - absorption - scattering;
+            let p_absorb = absorption.dot(&Color::one()) / (3.0 * majorant);
+            let p_scatter = scattering.dot(&Color::one()) / (3.0 * majorant);
+            let u = rng.gen::<f64>();
+            if u < p_absorb {
+                return MediumSample::Pass { weight: Color::zero(), emission };
+            } else if u < p_absorb + p_scatter {
+                weight *= scattering / (majorant * p_scatter);
+                return MediumSample::Scatter { distance: t, weight, emission };
+            } else {
+                let p_null = 1.0 - p_absorb - p_scatter;
+                if p_null <= 0.0 {
+                    return MediumSample::Pass { weight: Color::zero(), emission };
+                }
+                weight *= null / (majorant * p_null);
+            }
+        }
+    }
+
+    // 均質な媒質で散乱する距離をサンプリングする
+    // チャンネルを1つ選んでその消散係数で指数分布からサンプリングし、確率密度は全チャンネルの平均とする
+    // 散乱しない媒質では吸収による減衰だけを返す（Beer-Lambert の法則）
+    fn sample_homogeneous_distance(&self, random: (f64, f64), max_distance: f64) -> MediumSample {
+        if self.scattering == Color::zero() {
+            return MediumSample::Pass { weight: self.transmittance(max_distance), emission: Color::zero() };
+        }
+
+        let extinction = self.extinction();
+        let sigma = match (random.0 * 3.0) as usize {
+            0 => extinction.x,
+            1 => extinction.y,
+            _ => extinction.z,
+        };
+        let distance = if sigma > 0.0 { -(1.0 - random.1).ln() / sigma } else { config::INF };
+
+        // 透過率が 0 にアンダーフローした場合は寄与しない
+        if distance < max_distance {
+            let transmittance = self.transmittance(distance);
+            let pdf = (extinction * transmittance).dot(&Color::one()) / 3.0;
+            let weight = if pdf > 0.0 { transmittance * self.scattering / pdf } else { Color::zero() };
+            MediumSample::Scatter { distance, weight, emission: Color::zero() }
+        } else {
+            let transmittance = self.transmittance(max_distance);
+            let probability = transmittance.dot(&Color::one()) / 3.0;
+            let weight = if probability > 0.0 { transmittance / probability } else { Color::zero() };
+            MediumSample::Pass { weight, emission: Color::zero() }
+        }
+    }
+
+    // 進む向き direction から light の向きに散乱する位相関数の値
+    // 位相関数の値がそのままサンプリングの確率密度になる
+    pub fn phase(&self, direction: &Vector3, light: &Vector3) -> f64 {
+        let g = self.anisotropy;
+        let denominator = 1.0 + g * g - 2.0 * g * direction.dot(light);
+        (1.0 - g * g) / (4.0 * config::PI * denominator * denominator.sqrt())
+    }
+
+    // 位相関数に比例して散乱する向きをサンプリングする
+    pub fn sample_phase(&self, random: (f64, f64), direction: &Vector3) -> Vector3 {
+        let g = self.anisotropy;
+        let cos_theta = if g.abs() < 1e-3 {
+            1.0 - 2.0 * random.0
+        } else {
+            let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * random.0);
+            ((1.0 + g * g - s * s) / (2.0 * g)).clamp(-1.0, 1.0)
+        };
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = config::PI2 * random.1;
+
+        let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(direction);
+        (tangent * phi.cos() + binormal * phi.sin()) * sin_theta + *direction * cos_theta
+    }
+}
@@ -0,0 +1,268 @@
+use vector::{Vector3, Vector2};
+use color::{Color, color_to_luminance};
+use texture::Texture;
+use config;
+use math::{saturate, mix};
+use material::{get_tangent_space_basis_gram_schmidtd, importance_sample_diffuse, importance_sample_ggx_half,
+               roughness_to_alpha2, d_ggx, g_smith_joint,
+               rough_dielectric_bsdf, rough_dielectric_pdf, sample_rough_dielectric};
+
+// Disney の Principled BSDF
+// B. Burley, "Physically-Based Shading at Disney", SIGGRAPH 2012 Course
+// B. Burley, "Extending the Disney BRDF to a BSDF with Integrated Subsurface Scattering", SIGGRAPH 2015 Course
+// base color は Material::albedo を使い、roughness は Material::roughness を使う
+
+// sheen の色をどれだけ base color に寄せるか
+const SHEEN_TINT: f64 = 0.5;
+// clearcoat の GTR1 分布の alpha（clearcoat gloss = 1 に相当）
+const CLEARCOAT_ALPHA: f64 = 0.001;
+
+// Principled BSDF のパラメータのテクスチャ（値は x 成分を使う）
+#[derive(Debug)]
+pub struct PrincipledTextures {
+    pub metallic: Texture,
+    // 誘電体のスペキュラーの強さ。0.5 で F0 = 0.04（屈折率 1.5）になり、transmission の屈折率にも使う
+    pub specular: Texture,
+    pub specular_tint: Texture,
+    pub sheen: Texture,
+    pub clearcoat: Texture,
+    pub transmission: Texture,
+}
+
+impl PrincipledTextures {
+    pub fn new() -> PrincipledTextures {
+        PrincipledTextures {
+            metallic: Texture::black(),
+            specular: Texture::from_color(Color::from_one(0.5)),
+            specular_tint: Texture::black(),
+            sheen: Texture::black(),
+            clearcoat: Texture::black(),
+            transmission: Texture::black(),
+        }
+    }
+
+    pub fn sample(&self, uv: Vector2, base_color: Color) -> PrincipledParameters {
+        PrincipledParameters {
+            base_color,
+            metallic: saturate(self.metallic.sample(uv).x),
+            specular: self.specular.sample(uv).x.max(0.0),
+            specular_tint: saturate(self.specular_tint.sample(uv).x),
+            sheen: self.sheen.sample(uv).x.max(0.0),
+            clearcoat: self.clearcoat.sample(uv).x.max(0.0),
+            transmission: saturate(self.transmission.sample(uv).x),
+        }
+    }
+}
+
+// 交点で評価した Principled BSDF のパラメータ
+#[derive(Clone, Copy, Debug)]
+pub struct PrincipledParameters {
+    pub base_color: Color,
+    pub metallic: f64,
+    pub specular: f64,
+    pub specular_tint: f64,
+    pub sheen: f64,
+    pub clearcoat: f64,
+    pub transmission: f64,
+}
+
+impl Default for PrincipledParameters {
+    fn default() -> PrincipledParameters {
+        PrincipledParameters {
+            base_color: Color::one(),
+            metallic: 0.0,
+            specular: 0.5,
+            specular_tint: 0.0,
+            sheen: 0.0,
+            clearcoat: 0.0,
+            transmission: 0.0,
+        }
+    }
+}
+
+impl PrincipledParameters {
+    pub fn is_transmissive(&self) -> bool {
+        self.transmission_weight() > 0.0
+    }
+
+    pub fn bsdf(&self, roughness: f64, view: &Vector3, normal: &Vector3, light: &Vector3) -> Color {
+        let alpha2 = roughness_to_alpha2(roughness);
+        let mut bsdf = Color::zero();
+
+        // 反射の成分は view の側を向いた法線で評価する
+        let oriented_normal = if view.dot(normal) < 0.0 { -*normal } else { *normal };
+        let v_dot_n = view.dot(&oriented_normal);
+        let l_dot_n = light.dot(&oriented_normal);
+        if v_dot_n > 0.0 && l_dot_n > 0.0 {
+            let half = (*light + *view).normalize();
+            let h_dot_n = half.dot(&oriented_normal);
+            let l_dot_h = light.dot(&half);
+            let tint = self.tint();
+
+            // Diffuse: 粗さに応じて境界付近の明るさが変わる Burley の拡散反射と、布のような sheen
+            let diffuse_weight = self.diffuse_weight();
+            if diffuse_weight > 0.0 {
+                let fl = schlick_weight(l_dot_n);
+                let fv = schlick_weight(v_dot_n);
+                let fd90 = 0.5 + 2.0 * l_dot_h * l_dot_h * roughness;
+                let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv);
+                let sheen = mix(&Color::one(), &tint, SHEEN_TINT) * (self.sheen * schlick_weight(l_dot_h));
+                bsdf += (self.base_color * (fd / config::PI) + sheen) * diffuse_weight;
+            }
+
+            // Specular: 金属と誘電体の GGX の反射
+            let specular_weight = self.specular_weight();
+            if specular_weight > 0.0 {
+                let d = d_ggx(h_dot_n, alpha2);
+                let g = g_smith_joint(l_dot_n, v_dot_n, alpha2);
+                let f = mix(&self.specular_color(), &Color::one(), schlick_weight(l_dot_h));
+                bsdf += f * (d * g / (4.0 * l_dot_n * v_dot_n) * specular_weight);
+            }
+
+            // Clearcoat: 表面を覆う屈折率 1.5 の透明な層の反射
+            if self.clearcoat > 0.0 {
+                let d = gtr1(h_dot_n, CLEARCOAT_ALPHA);
+                let g = smith_g_ggx(l_dot_n, 0.25) * smith_g_ggx(v_dot_n, 0.25);
+                let f = 0.04 + 0.96 * schlick_weight(l_dot_h);
+                bsdf += Color::from_one(0.25 * self.clearcoat * d * g * f);
+            }
+        }
+
+        // Transmission: 粗い誘電体の反射と屈折。屈折した光は base color で色付けする
+        let transmission_weight = self.transmission_weight();
+        if transmission_weight > 0.0 {
+            let f = rough_dielectric_bsdf(view, normal, light, self.refractive_index(), alpha2) * transmission_weight;
+            let refracted = view.dot(normal) * light.dot(normal) < 0.0;
+            bsdf += if refracted { self.base_color * f } else { Color::from_one(f) };
+        }
+
+        bsdf
+    }
+
+    // sample で light の方向がサンプリングされる立体角測度の確率密度
+    pub fn pdf(&self, roughness: f64, view: &Vector3, normal: &Vector3, light: &Vector3) -> f64 {
+        let alpha2 = roughness_to_alpha2(roughness);
+        let (p_diffuse, p_specular, p_clearcoat, p_transmission) = self.lobe_probabilities();
+        let mut pdf = 0.0;
+
+        let oriented_normal = if view.dot(normal) < 0.0 { -*normal } else { *normal };
+        let l_dot_n = light.dot(&oriented_normal);
+        if view.dot(&oriented_normal) > 0.0 && l_dot_n > 0.0 {
+            let half = (*light + *view).normalize();
+            let h_dot_n = half.dot(&oriented_normal);
+            let v_dot_h = view.dot(&half).abs();
+
+            pdf += p_diffuse * l_dot_n / config::PI;
+            pdf += p_specular * d_ggx(h_dot_n, alpha2) * h_dot_n / (4.0 * v_dot_h);
+            pdf += p_clearcoat * gtr1(h_dot_n, CLEARCOAT_ALPHA) * h_dot_n / (4.0 * v_dot_h);
+        }
+
+        if p_transmission > 0.0 {
+            pdf += p_transmission * rough_dielectric_pdf(view, normal, light, self.refractive_index(), alpha2);
+        }
+
+        pdf
+    }
+
+    // random.0 で成分を1つ選んで方向をサンプリングする。サンプリングできない場合は None
+    // 選ばれなかった成分も含めた BSDF と確率密度で重み付けするので、反射率は bsdf * cos / pdf で求める
+    pub fn sample_direction(&self, roughness: f64, random: (f64, f64), view: &Vector3, normal: &Vector3) -> Option<Vector3> {
+        let alpha2 = roughness_to_alpha2(roughness);
+        let (p_diffuse, p_specular, p_clearcoat, _) = self.lobe_probabilities();
+        let oriented_normal = if view.dot(normal) < 0.0 { -*normal } else { *normal };
+
+        // 選んだ成分の区間の中で乱数を引き伸ばして使う
+        let remap = |u: f64, begin: f64, probability: f64| (((u - begin) / probability).max(0.0), random.1);
+        let u = random.0;
+        let light = if u < p_diffuse {
+            importance_sample_diffuse(remap(u, 0.0, p_diffuse), &oriented_normal)
+        } else if u < p_diffuse + p_specular {
+            let half = importance_sample_ggx_half(remap(u, p_diffuse, p_specular), &oriented_normal, alpha2);
+            (-*view).reflect(&half)
+        } else if u < p_diffuse + p_specular + p_clearcoat {
+            let half = importance_sample_gtr1_half(remap(u, p_diffuse + p_specular, p_clearcoat), &oriented_normal, CLEARCOAT_ALPHA);
+            (-*view).reflect(&half)
+        } else {
+            let begin = p_diffuse + p_specular + p_clearcoat;
+            let (u, v) = remap(u, begin, 1.0 - begin);
+            return sample_rough_dielectric((u.min(1.0 - f64::EPSILON), v), view, normal, self.refractive_index(), alpha2);
+        };
+
+        // 反射の成分で半球外が選ばれた場合
+        if light.dot(&oriented_normal) <= 0.0 {
+            None
+        } else {
+            Some(light)
+        }
+    }
+
+    fn diffuse_weight(&self) -> f64 {
+        (1.0 - self.metallic) * (1.0 - self.transmission)
+    }
+
+    fn specular_weight(&self) -> f64 {
+        1.0 - (1.0 - self.metallic) * self.transmission
+    }
+
+    fn transmission_weight(&self) -> f64 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    // diffuse, specular, clearcoat, transmission をサンプリングする確率
+    fn lobe_probabilities(&self) -> (f64, f64, f64, f64) {
+        let diffuse = self.diffuse_weight();
+        let specular = self.specular_weight();
+        let clearcoat = 0.25 * self.clearcoat.min(1.0);
+        let transmission = self.transmission_weight();
+        let total = diffuse + specular + clearcoat + transmission;
+        (diffuse / total, specular / total, clearcoat / total, transmission / total)
+    }
+
+    // base color の色味だけを取り出したもの
+    fn tint(&self) -> Color {
+        let luminance = color_to_luminance(&self.base_color);
+        if luminance > 0.0 { self.base_color / luminance } else { Color::one() }
+    }
+
+    // 正面から見たスペキュラーの反射率。金属では base color になる
+    fn specular_color(&self) -> Color {
+        let dielectric = mix(&Color::one(), &self.tint(), self.specular_tint) * (0.08 * self.specular);
+        mix(&dielectric, &self.base_color, self.metallic)
+    }
+
+    // F0 = 0.08 * specular となる屈折率
+    fn refractive_index(&self) -> f64 {
+        let sqrt_f0 = (0.08 * self.specular).min(0.99).sqrt();
+        ((1.0 + sqrt_f0) / (1.0 - sqrt_f0)).max(1.001)
+    }
+}
+
+fn schlick_weight(cos: f64) -> f64 {
+    (1.0 - saturate(cos)).powi(5)
+}
+
+// Generalized-Trowbridge-Reitz 分布（γ = 1）
+fn gtr1(h_dot_n: f64, alpha: f64) -> f64 {
+    let alpha2 = alpha * alpha;
+    let t = 1.0 + (alpha2 - 1.0) * h_dot_n * h_dot_n;
+    (alpha2 - 1.0) / (config::PI * alpha2.ln() * t)
+}
+
+// G1 / (2 * cos) の形の Smith のマスキング関数
+fn smith_g_ggx(x_dot_n: f64, alpha: f64) -> f64 {
+    let a = alpha * alpha;
+    let b = x_dot_n * x_dot_n;
+    (x_dot_n + (a + b - a * b).sqrt()).recip()
+}
+
+// GTR1 分布 * cos(half, normal) に比例したハーフベクトルのサンプリング
+fn importance_sample_gtr1_half(random: (f64, f64), normal: &Vector3, alpha: f64) -> Vector3 {
+    let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(normal);
+
+    let alpha2 = alpha * alpha;
+    let phi = config::PI2 * random.0;
+    let cos_theta = ((1.0 - alpha2.powf(1.0 - random.1)) / (1.0 - alpha2)).max(0.0).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    (tangent * phi.cos() + binormal * phi.sin()) * sin_theta + *normal * cos_theta
+}
@@ -3,80 +3,99 @@ extern crate rand;
 extern crate rayon;
 extern crate time;
 
+use std::str::FromStr;
 use time::Tm;
 use image::{ImageBuffer, Rgb};
 use self::rand::{Rng, SeedableRng, StdRng};
 use self::rayon::prelude::*;
 
 use config;
+use config::RenderSettings;
 use vector::{Vector3, Vector2};
-use scene::{SceneTrait, Intersectable};
+use scene::{SceneTrait, Intersection};
 use camera::{Camera, Ray};
 use color::{Color, color_to_rgb, linear_to_gamma};
-use material::PointMaterial;
 use tonemap;
 use filter;
+use checkpoint::Checkpoint;
+use light::LightSampler;
+use spectrum::{SpectrumToRgb, sample_wavelength, rgb_to_spectrum};
+use medium::{Medium, MediumSample};
+use material::SurfaceType;
 
 pub trait Renderer: Sync {
     fn max_sampling(&self) -> u32;
 
-    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, emissions: &Vec<&Box<Intersectable>>, normalized_coord: &Vector2, sampling: u32) -> Color;
+    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, lights: &LightSampler, normalized_coord: &Vector2, sampling: u32, settings: &RenderSettings) -> Color;
 
-    fn render(&mut self, scene: &SceneTrait, camera: &Camera, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> u32 {
+    // imgbuf にはトーンマッピング後の画像を、hdrbuf にはトーンマッピング前のピクセルごとの平均値を書き込む
+    fn render(&mut self, scene: &SceneTrait, camera: &Camera, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>, hdrbuf: &mut Vec<Color>) -> u32 {
         let resolution = Vector2::new(imgbuf.width() as f64, imgbuf.height() as f64);
         let num_of_pixel = imgbuf.width() * imgbuf.height();
-        let mut accumulation_buf = vec![Vector3::zero(); num_of_pixel as usize];
-        let emissions = scene.emissions();
+        let (resumed, mut accumulation_buf) = self.take_resume_state()
+            .unwrap_or_else(|| (0, vec![Vector3::zero(); num_of_pixel as usize]));
+        let lights = LightSampler::new(scene, &settings.light_sampling);
 
         // NOTICE: sampling is 1 origin
-        for sampling in 1..(self.max_sampling() + 1) {
+        let mut sampled = self.max_sampling();
+        for sampling in (resumed + 1)..(self.max_sampling() + 1) {
             accumulation_buf.par_iter_mut().enumerate().for_each(|(i, pixel)| {
                 let y = i as u32 / imgbuf.width();
                 let x = i as u32 - y * imgbuf.width();
                 let frag_coord = Vector2::new(x as f64, (imgbuf.height() - y) as f64);
-                *pixel += self.supersampling(scene, camera, &emissions, &frag_coord, &resolution, sampling);
+                *pixel += self.supersampling(scene, camera, &lights, &frag_coord, &resolution, sampling, settings);
             });
 
-            if self.report_progress(&accumulation_buf, sampling, imgbuf) {
-                return sampling;
+            if self.report_progress(&accumulation_buf, sampling, settings, imgbuf) {
+                sampled = sampling;
+                break;
             }
         }
 
-        self.max_sampling()
+        *hdrbuf = Self::average_buf(&accumulation_buf, sampled, settings);
+        sampled
     }
 
-    fn supersampling(&self, scene: &SceneTrait, camera: &Camera, emissions: &Vec<&Box<Intersectable>>, frag_coord: &Vector2, resolution: &Vector2, sampling: u32) -> Color {
+    // 途中から再開する場合は、再開前のサンプリング回数と累積バッファを返す
+    fn take_resume_state(&mut self) -> Option<(u32, Vec<Vector3>)> { None }
+
+    #[allow(clippy::too_many_arguments)]
+    fn supersampling(&self, scene: &SceneTrait, camera: &Camera, lights: &LightSampler, frag_coord: &Vector2, resolution: &Vector2, sampling: u32, settings: &RenderSettings) -> Color {
         let mut accumulation = Color::zero();
 
-        for sy in 0..config::SUPERSAMPLING {
-            for sx in 0..config::SUPERSAMPLING {
-                let offset = Vector2::new(sx as f64, sy as f64) / config::SUPERSAMPLING as f64 - 0.5;
+        for sy in 0..settings.supersampling {
+            for sx in 0..settings.supersampling {
+                let offset = Vector2::new(sx as f64, sy as f64) / settings.supersampling as f64 - 0.5;
                 let normalized_coord = ((*frag_coord + offset) * 2.0 - *resolution) / resolution.x.min(resolution.y);
-                accumulation += self.calc_pixel(scene, camera, &emissions, &normalized_coord, sampling);
+                accumulation += self.calc_pixel(scene, camera, lights, &normalized_coord, sampling, settings);
             }
         }
 
         accumulation
     }
 
-    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool;
+    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool;
+
+    // 累積バッファをサンプル数で割ってピクセルごとの平均値にする
+    fn average_buf(accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings) -> Vec<Color> {
+        let scale = ((sampling * settings.samples_per_pixel()) as f64).recip();
+        accumulation_buf.par_iter().map(|pixel| *pixel * scale).collect()
+    }
 
-    fn update_imgbuf(accumulation_buf: &Vec<Vector3>, sampling: u32, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) {
-        let scale = ((sampling * config::SUPERSAMPLING * config::SUPERSAMPLING) as f64).recip();
+    fn update_imgbuf(accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) {
         let width = imgbuf.width();
         let height = imgbuf.height();
 
-        let mut tmp: Vec<_> = accumulation_buf.par_iter().map(|pixel| {
-            let hdr = *pixel * scale;
-            let ldr = tonemap::execute(&hdr);
+        let mut tmp: Vec<_> = Self::average_buf(accumulation_buf, sampling, settings).par_iter().map(|hdr| {
+            let ldr = tonemap::execute(hdr, settings);
             let gamma = linear_to_gamma(ldr);
             gamma
         }).collect();
 
-        for _ in 0..config::BILATERAL_FILTER_ITERATION {
+        for _ in 0..settings.bilateral_filter_iteration {
             tmp = tmp.par_iter().enumerate().map(|i_p| {
                 let (index, pixel) = i_p;
-                filter::execute(&pixel, index, &tmp, width, height)
+                filter::execute(&pixel, index, &tmp, width, height, settings)
             }).collect();
         }
 
@@ -89,15 +108,53 @@ pub trait Renderer: Sync {
         }
     }
 
-    fn save_progress_image(path: &str, accumulation_buf: &Vec<Vector3>, sampling: u32, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) {
+    fn save_progress_image(path: &str, accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) {
         let begin = time::now();
-        Self::update_imgbuf(accumulation_buf, sampling, imgbuf);
+        Self::update_imgbuf(accumulation_buf, sampling, settings, imgbuf);
         let end = time::now();
         println!("update_imgbuf: {:.3} sec", (end - begin).num_milliseconds() as f64 * 0.001);
         let _ = image::ImageRgb8(imgbuf.clone()).save(path);
     }
 }
 
+// Multiple Importance Sampling の重み付けの方法
+#[derive(Clone, Debug)]
+pub enum MisHeuristic {
+    Balance,
+    Power,
+}
+
+impl MisHeuristic {
+    // pdf_a の手法でサンプリングした寄与に対する重み
+    // 確率密度が有限でない場合はデルタ分布とみなす
+    pub fn weight(&self, pdf_a: f64, pdf_b: f64) -> f64 {
+        if !pdf_a.is_finite() {
+            return 1.0;
+        }
+        if !pdf_b.is_finite() {
+            return 0.0;
+        }
+
+        let (a, b) = match *self {
+            MisHeuristic::Balance => (pdf_a, pdf_b),
+            MisHeuristic::Power => (pdf_a * pdf_a, pdf_b * pdf_b),
+        };
+        if a + b == 0.0 { 0.0 } else { a / (a + b) }
+    }
+}
+
+impl FromStr for MisHeuristic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MisHeuristic, String> {
+        match s {
+            "balance" => Ok(MisHeuristic::Balance),
+            "power" => Ok(MisHeuristic::Power),
+            _ => Err(format!("unknown MIS heuristic `{}`", s)),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub enum DebugRenderMode {
     Shading,
@@ -113,7 +170,7 @@ pub struct DebugRenderer {
 impl Renderer for DebugRenderer {
     fn max_sampling(&self) -> u32 { 1 }
 
-    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, _emissions: &Vec<&Box<Intersectable>>, normalized_coord: &Vector2, _: u32) -> Color {
+    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, _lights: &LightSampler, normalized_coord: &Vector2, _: u32, _settings: &RenderSettings) -> Color {
         let ray = camera.ray(&normalized_coord);
         let light_direction = Vector3::new(1.0, 2.0, -1.0).normalize();
         let (hit, intersection) = scene.intersect(&ray);
@@ -124,8 +181,7 @@ impl Renderer for DebugRenderer {
                         origin: intersection.position + intersection.normal * config::OFFSET,
                         direction: light_direction,
                     };
-                    let (shadow_hit, _) = scene.intersect(&shadow_ray);
-                    let shadow = if shadow_hit { 0.5 } else { 1.0 };
+                    let shadow = if scene.occluded(&shadow_ray, config::INF) { 0.5 } else { 1.0 };
                     let diffuse = intersection.normal.dot(&light_direction).max(0.0);
                     intersection.material.emission + intersection.material.albedo * diffuse * shadow
                 }
@@ -138,9 +194,9 @@ impl Renderer for DebugRenderer {
         }
     }
 
-    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool {
+    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool {
         // on finish
-        Self::update_imgbuf(accumulation_buf, sampling, imgbuf);
+        Self::update_imgbuf(accumulation_buf, sampling, settings, imgbuf);
         true
     }
 }
@@ -155,12 +211,20 @@ pub struct PathTracingRenderer {
     last_report_progress: Tm,
     last_report_image: Tm,
     report_image_counter: u32,
+
+    // for checkpoint
+    checkpoint_path: Option<String>,
+    checkpoint_hash: u64,
+    resume_checkpoint: Option<Checkpoint>,
+
+    // for spectral rendering
+    spectrum_to_rgb: SpectrumToRgb,
 }
 
 impl Renderer for PathTracingRenderer {
     fn max_sampling(&self) -> u32 { self.sampling }
 
-    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, emissions: &Vec<&Box<Intersectable>>, normalized_coord: &Vector2, sampling: u32) -> Color {
+    fn calc_pixel(&self, scene: &SceneTrait, camera: &Camera, lights: &LightSampler, normalized_coord: &Vector2, sampling: u32, settings: &RenderSettings) -> Color {
         // random generator
         let s = ((4.0 + normalized_coord.x) * 100870.0) as usize;
         let t = ((4.0 + normalized_coord.y) * 100304.0) as usize;
@@ -168,48 +232,146 @@ impl Renderer for PathTracingRenderer {
         let mut rng: StdRng = SeedableRng::from_seed(seed);// self::rand::thread_rng();
         let mut ray = camera.ray_with_dof(&normalized_coord, &mut rng);
 
+        // 分光レンダリングではパスごとに1つの波長を追跡し、色はすべてのチャンネルが同じ値のスペクトルの値とする
+        let wavelength = if settings.spectral_rendering { Some(sample_wavelength(rng.gen())) } else { None };
+
         let mut accumulation = Color::zero();
         let mut reflectance = Color::one();
 
-        for _ in 1..config::PATHTRACING_BOUNCE_LIMIT {
+        // 直前の反射で NEE を行った場合の、反射の起点と BSDF サンプリングの確率密度（MIS用）
+        let mut last_nee: Option<(Vector3, f64)> = None;
+
+        // レイが進んでいる関与媒質
+        // 物体が入れ子になっている場合は考慮せず、物体の内部では最後に入った物体の媒質を、外部ではシーン全体の媒質を使う
+        let scene_medium = scene.medium().map(|medium| match wavelength {
+            Some(lambda) => medium.at_wavelength(lambda),
+            None => medium.clone(),
+        });
+        let mut medium = scene_medium.clone();
+
+        for depth in 1..settings.pathtracing_bounce_limit {
             let random = rng.gen::<(f64, f64)>();
             let (hit, mut intersection) = scene.intersect(&ray);
-            let mut current_reflectance = 1.0;
+            let mut current_reflectance = Color::one();
+            if let Some(lambda) = wavelength {
+                intersection.material.set_wavelength(lambda);
+            }
 
-            if hit {
-                let view = &-ray.direction;
-                if let Some(result) = intersection.material.sample(random, &intersection.position, view, &intersection.normal) {
-                    if intersection.material.nee_available() {
+            // 関与媒質の中では、交点に届く前に散乱するかをサンプリングする
+            let mut scattered = false;
+            if let Some(ref m) = medium {
+                let max_distance = if hit { intersection.distance } else { config::INF };
+                match m.sample_distance(&ray, max_distance, &mut rng) {
+                    MediumSample::Scatter { distance, weight, emission } => {
+                        let position = ray.origin + ray.direction * distance;
+                        accumulation += reflectance * emission;
+                        reflectance *= weight;
                         accumulation += reflectance * PathTracingRenderer::next_event_estimation(
-                            random, &result.ray.origin, view, &intersection.normal,
-                            scene, &emissions, &intersection.material);
+                            random, &position, &ScatteringPoint::Medium { medium: m, direction: &ray.direction },
+                            scene, lights, &settings.mis_heuristic, wavelength, &scene_medium, &mut rng);
+
+                        let direction = m.sample_phase(random, &ray.direction);
+                        last_nee = Some((position, m.phase(&ray.direction, &direction)));
+                        ray = Ray { origin: position, direction };
+                        scattered = true;
                     }
+                    MediumSample::Pass { weight, emission } => {
+                        accumulation += reflectance * emission;
+                        reflectance *= weight;
+                        // 媒質の中で吸収された
+                        if reflectance == Color::zero() { break; }
+                    }
+                }
+            }
 
-                    ray = result.ray;
-                    current_reflectance = result.reflectance;
-                } else {
-                    // 半球外をサンプリングしたら計算を打ち切る
-                    break;
+            if !scattered {
+                // NEE の対象の光源に BSDF サンプリングで到達した場合は MIS で重み付けする
+                // 何とも交差しなかった場合は環境光に到達している
+                let nee = last_nee.take();
+                let emission_weight = match (nee, intersection.element_index) {
+                    (Some((origin, bsdf_pdf)), Some(index)) => {
+                        let light_pdf = scene.light_pdf(index, &origin, &intersection) * lights.probability(index, &origin);
+                        settings.mis_heuristic.weight(bsdf_pdf, light_pdf)
+                    }
+                    (Some((origin, bsdf_pdf)), None) => {
+                        let light_pdf = scene.environment().pdf(&ray.direction) * lights.environment_probability(&origin);
+                        settings.mis_heuristic.weight(bsdf_pdf, light_pdf)
+                    }
+                    _ => 1.0,
+                };
+
+                if hit {
+                    let view = &-ray.direction;
+                    if let Some(result) = intersection.material.sample(random, &intersection.position, view, &intersection.normal) {
+                        // 表側と裏側の関与媒質
+                        let (front_medium, back_medium) = if intersection.material.has_interior() {
+                            (scene_medium.clone(), intersection.material.medium.clone())
+                        } else {
+                            (medium.clone(), medium.clone())
+                        };
+
+                        if intersection.material.nee_available() {
+                            // 光源の選択とサンプリングは表側に少しずらした点から行う
+                            let origin = intersection.position + intersection.normal * config::OFFSET;
+                            let point = ScatteringPoint::Surface { intersection: &intersection, view, front_medium: &front_medium, back_medium: &back_medium };
+                            accumulation += reflectance * PathTracingRenderer::next_event_estimation(
+                                random, &origin, &point,
+                                scene, lights, &settings.mis_heuristic, wavelength, &scene_medium, &mut rng);
+                            let bsdf_pdf = intersection.material.pdf(view, &intersection.normal, &result.ray.direction);
+                            last_nee = Some((origin, bsdf_pdf));
+                        } else if let SurfaceType::Transparent = intersection.material.surface {
+                            // 媒質の境界を通り抜けるだけなので、直前の NEE の情報を引き継ぐ
+                            last_nee = nee;
+                        }
+
+                        // 表面の内側に向かうレイは物体の内部を進む
+                        medium = if result.ray.direction.dot(&intersection.normal) < 0.0 { back_medium } else { front_medium };
+
+                        ray = result.ray;
+                        current_reflectance = result.reflectance;
+                    } else {
+                        // 半球外をサンプリングしたら計算を打ち切る
+                        break;
+                    }
                 }
+
+                accumulation += reflectance * intersection.material.emission * emission_weight;
+                reflectance *= intersection.material.albedo * current_reflectance;
+
+                if !hit { break; }
             }
 
-            accumulation += reflectance * intersection.material.emission;
-            reflectance *= intersection.material.albedo * current_reflectance;
+            if reflectance == Vector3::zero() { break; }
 
-            if !hit || reflectance == Vector3::zero() { break; }
+            // ロシアンルーレット
+            // スループットの最大の成分を継続する確率とし、継続した経路はその確率で割って期待値を保つ
+            if depth >= settings.russian_roulette_depth {
+                let continue_probability = reflectance.x.max(reflectance.y).max(reflectance.z).min(1.0);
+                if rng.gen::<f64>() >= continue_probability {
+                    break;
+                }
+                reflectance = reflectance / continue_probability;
+            }
         }
 
-        accumulation
+        match wavelength {
+            Some(lambda) => self.spectrum_to_rgb.weight(lambda) * accumulation.x,
+            None => accumulation,
+        }
+    }
+
+    fn take_resume_state(&mut self) -> Option<(u32, Vec<Vector3>)> {
+        self.resume_checkpoint.take().map(|c| (c.sampling, c.accumulation_buf))
     }
 
-    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool {
+    fn report_progress(&mut self, accumulation_buf: &Vec<Vector3>, sampling: u32, settings: &RenderSettings, imgbuf: &mut ImageBuffer<Rgb<u8>, Vec<u8>>) -> bool {
         let now = time::now();
         let used = (now - self.begin).num_milliseconds() as f64 * 0.001;
         let used_percent = used / self.time_limit_sec as f64 * 100.0;
         let from_last_sampling_sec = (now - self.last_report_progress).num_milliseconds() as f64 * 0.001;
 
         println!("rendering: {}x{} sampled (last {:.3} sec). total: {:.3} sec ({:.2} %).",
-                 sampling, config::SUPERSAMPLING * config::SUPERSAMPLING,
+                 sampling, settings.samples_per_pixel(),
                  from_last_sampling_sec,
                  used, used_percent);
 
@@ -221,7 +383,8 @@ impl Renderer for PathTracingRenderer {
             println!("reached time limit");
             println!("output final image: {}", path);
             println!("remain: {:.3} sec.", self.time_limit_sec - used);
-            Self::save_progress_image(&path, accumulation_buf, sampling, imgbuf);
+            Self::save_progress_image(&path, accumulation_buf, sampling, settings, imgbuf);
+            self.save_checkpoint(accumulation_buf, sampling, imgbuf);
             return true;
         }
 
@@ -231,7 +394,8 @@ impl Renderer for PathTracingRenderer {
             println!("reached max sampling");
             println!("output final image: {}", path);
             println!("remain: {:.3} sec.", self.time_limit_sec - used);
-            Self::save_progress_image(&path, accumulation_buf, sampling, imgbuf);
+            Self::save_progress_image(&path, accumulation_buf, sampling, settings, imgbuf);
+            self.save_checkpoint(accumulation_buf, sampling, imgbuf);
             return true;
         }
 
@@ -241,7 +405,8 @@ impl Renderer for PathTracingRenderer {
             // save progress image
             let path = format!("{:>03}.png", self.report_image_counter);
             println!("output progress image: {}", path);
-            Self::save_progress_image(&path, accumulation_buf, sampling, imgbuf);
+            Self::save_progress_image(&path, accumulation_buf, sampling, settings, imgbuf);
+            self.save_checkpoint(accumulation_buf, sampling, imgbuf);
             self.report_image_counter += 1;
             self.last_report_image = now;
         }
@@ -263,35 +428,155 @@ impl PathTracingRenderer {
             last_report_progress: now,
             last_report_image: now,
             report_image_counter: 0,
+
+            checkpoint_path: None,
+            checkpoint_hash: 0,
+            resume_checkpoint: None,
+
+            spectrum_to_rgb: SpectrumToRgb::new(),
+        }
+    }
+
+    // 途中結果を出力するたびにチェックポイントを保存する
+    // hash はシーンと設定の照合用
+    pub fn enable_checkpoint(&mut self, path: &str, hash: u64) {
+        self.checkpoint_path = Some(path.to_string());
+        self.checkpoint_hash = hash;
+    }
+
+    // チェックポイントの続きからサンプリングする
+    pub fn resume(&mut self, checkpoint: Checkpoint) {
+        self.report_image_counter = checkpoint.report_image_counter;
+        self.resume_checkpoint = Some(checkpoint);
+    }
+
+    fn save_checkpoint(&self, accumulation_buf: &[Vector3], sampling: u32, imgbuf: &ImageBuffer<Rgb<u8>, Vec<u8>>) {
+        if let Some(ref path) = self.checkpoint_path {
+            let begin = time::now();
+            // 保存する時点の画像は出力済みなので、再開後はその次の番号から出力する
+            match Checkpoint::save(path, self.checkpoint_hash, imgbuf.width(), imgbuf.height(), sampling, self.report_image_counter + 1, accumulation_buf) {
+                Ok(_) => {
+                    let end = time::now();
+                    println!("save checkpoint: {} ({:.3} sec)", path, (end - begin).num_milliseconds() as f64 * 0.001);
+                }
+                Err(e) => eprintln!("{}: {}", path, e),
+            }
         }
     }
 
-    fn next_event_estimation(random: (f64, f64), position: &Vector3, view: &Vector3, normal: &Vector3,
-                             scene: &SceneTrait, emissions: &Vec<&Box<Intersectable>>, material: &PointMaterial) -> Vector3 {
+    #[allow(clippy::too_many_arguments)]
+    fn next_event_estimation(random: (f64, f64), position: &Vector3, point: &ScatteringPoint,
+                             scene: &SceneTrait, lights: &LightSampler, heuristic: &MisHeuristic,
+                             wavelength: Option<f64>, scene_medium: &Option<Medium>, rng: &mut StdRng) -> Vector3 {
         //return Vector3::zero();
 
         let mut accumulation = Vector3::zero();
 
-        for emission in emissions {
-            let surface = emission.sample_on_surface(random);
-            let shadow_vec = surface.position - *position;
-            let shadow_dir = shadow_vec.normalize();
-            let shadow_ray = Ray { origin: *position, direction: shadow_dir };
-            let (shadow_hit, shadow_intersection) = scene.intersect(&shadow_ray);
-
-            if shadow_hit && shadow_intersection.position.approximately(&surface.position) {
-                let dot_0 = normal.dot(&shadow_dir).abs();
-                let dot_l = surface.normal.dot(&shadow_dir).abs();
-                let distance_pow2 = shadow_vec.dot(&shadow_vec);
-                let g = (dot_0 * dot_l) / distance_pow2;
-                let pdf = surface.pdf;
-
-                accumulation += shadow_intersection.material.emission
-                    * material.bsdf(view, normal, &shadow_dir)
-                    * g / pdf;
+        lights.select(position, random.0, |light, probability, u| {
+            let sample = match light.sample(position, (u, random.1)) {
+                Some(sample) => sample,
+                None => return,
+            };
+
+            // 光源の方向に散乱する強さ（BSDF * cos か位相関数）と、その方向をサンプリングする確率密度
+            // シャドウレイの始点と、始点の関与媒質
+            let (scattering, scattering_pdf, origin, medium) = match *point {
+                ScatteringPoint::Surface { intersection, view, front_medium, back_medium } => {
+                    let normal = &intersection.normal;
+                    let material = &intersection.material;
+
+                    // 面の裏側にある点は、光を透過するマテリアルでなければ寄与しない
+                    // 光源自身の表面から同じ面上の点をサンプリングした場合もここで除かれる
+                    let dot_0 = normal.dot(&sample.direction);
+                    if dot_0 == 0.0 || (dot_0 < 0.0 && !material.transmissive()) {
+                        return;
+                    }
+
+                    // 裏側の光源へのシャドウレイは面の裏側から飛ばす
+                    let (origin, medium) = if dot_0 > 0.0 {
+                        (*position, front_medium.clone())
+                    } else {
+                        (intersection.position - *normal * config::OFFSET, back_medium.clone())
+                    };
+                    (material.bsdf(view, normal, &sample.direction) * material.albedo * dot_0.abs(),
+                     material.pdf(view, normal, &sample.direction), origin, medium)
+                }
+                ScatteringPoint::Medium { medium, direction } => {
+                    let phase = medium.phase(direction, &sample.direction);
+                    (Color::from_one(phase), phase, *position, Some(medium.clone()))
+                }
+            };
+
+            let shadow_ray = Ray { origin, direction: sample.direction };
+            if let Some(transmittance) = PathTracingRenderer::shadow_transmittance(scene, &shadow_ray, sample.distance, medium, scene_medium, wavelength, rng) {
+                // 光源を選ぶ確率も含めた確率密度
+                let light_pdf = sample.pdf * probability;
+                let weight = heuristic.weight(light_pdf, scattering_pdf);
+                // デルタ分布の光源は emission が放射照度なので、光源を選ぶ確率だけで割る
+                let pdf = if light_pdf.is_finite() { light_pdf } else { probability };
+
+                let emission = match wavelength {
+                    Some(lambda) => Color::from_one(rgb_to_spectrum(&sample.emission, lambda)),
+                    None => sample.emission,
+                };
+                accumulation += emission * scattering * transmittance * weight / pdf;
             }
-        }
+        });
 
-        accumulation * material.albedo
+        accumulation
     }
+
+    // シャドウレイが distance 進む間の透過率。遮られた場合は None
+    // transparent な面は通り抜け、面の内側と外側の関与媒質による減衰を掛け合わせる
+    #[allow(clippy::too_many_arguments)]
+    fn shadow_transmittance(scene: &SceneTrait, ray: &Ray, distance: f64, medium: Option<Medium>,
+                            scene_medium: &Option<Medium>, wavelength: Option<f64>, rng: &mut StdRng) -> Option<Color> {
+        let mut transmittance = Color::one();
+        let mut origin = ray.origin;
+        let mut remaining = distance;
+        let mut medium = medium;
+        // 境界で始点をずらしても、シャドウレイの終点は変えない
+        let end = ray.origin + ray.direction * distance;
+
+        loop {
+            let shadow_ray = Ray { origin, direction: ray.direction };
+            if !scene.occluded(&shadow_ray, remaining) {
+                if let Some(ref m) = medium {
+                    transmittance *= m.estimate_transmittance(&shadow_ray, remaining, rng);
+                }
+                return Some(transmittance);
+            }
+            if !scene.has_transparent_surfaces() {
+                return None;
+            }
+
+            let (_, mut intersection) = scene.intersect(&shadow_ray);
+            if let SurfaceType::Transparent = intersection.material.surface {} else {
+                return None;
+            }
+            if let Some(lambda) = wavelength {
+                intersection.material.set_wavelength(lambda);
+            }
+            if let Some(ref m) = medium {
+                transmittance *= m.estimate_transmittance(&shadow_ray, intersection.distance, rng);
+                if transmittance == Color::zero() {
+                    return None;
+                }
+            }
+
+            // 境界の反対側に移動して、その側の媒質で続ける
+            let entering = ray.direction.dot(&intersection.normal) < 0.0;
+            medium = if entering { intersection.material.medium } else { scene_medium.clone() };
+            origin = intersection.position + intersection.normal * if entering { -config::OFFSET } else { config::OFFSET };
+            remaining = (end - origin).dot(&ray.direction).max(0.0);
+        }
+    }
+}
+
+// NEE を行う散乱点
+enum ScatteringPoint<'a> {
+    // 物体の表面。front_medium と back_medium は法線の表側と裏側の関与媒質
+    Surface { intersection: &'a Intersection, view: &'a Vector3, front_medium: &'a Option<Medium>, back_medium: &'a Option<Medium> },
+    // 関与媒質の中の点。direction はレイの進む向き
+    Medium { medium: &'a Medium, direction: &'a Vector3 },
 }
@@ -1,24 +1,35 @@
 use config;
+use config::RenderSettings;
 use vector::{Vector3, Vector2};
-use material::{Material, PointMaterial, SurfaceType};
+use material::{Material, PointMaterial, SurfaceType, get_tangent_space_basis_gram_schmidtd};
 use camera::Ray;
-use texture::ImageTexture;
-use math::{equals_eps, modulo};
+use math::{equals_eps, modulo, component, set_component, Distribution1D};
 use color::Color;
-use bvh::{BvhNode, Aabb, intersect_polygon};
+use bvh::{Bvh, Aabb, intersect_polygon};
+use environment::Environment;
+use light::PunctualLight;
+use medium::Medium;
+use principled::PrincipledParameters;
 
 #[derive(Debug)]
 pub struct Intersection {
     pub position: Vector3,
     pub distance: f64,
     pub normal: Vector3,
+    // 補間する前の面の法線。光源の面積測度の確率密度の変換に使う
+    pub geometric_normal: Vector3,
     pub uv: Vector2,
     pub material: PointMaterial,
+
+    // 最も近い交差をした要素の Scene::elements 上のインデックス
+    pub element_index: Option<usize>,
 }
 
 pub struct Surface {
     pub position: Vector3,
-    pub normal: Vector3,
+    pub emission: Color,
+
+    // サンプリングの起点から見た立体角測度の確率密度
     pub pdf: f64,
 }
 
@@ -28,24 +39,66 @@ impl Intersection {
             position: Vector3::zero(),
             distance: config::INF,
             normal: Vector3::zero(),
+            geometric_normal: Vector3::zero(),
             uv: Vector2::zero(),
             material: PointMaterial {
                 surface: SurfaceType::Diffuse,
                 albedo: Color::one(),
                 emission: Color::zero(),
                 roughness: 0.2,
+                principled: PrincipledParameters::default(),
+                medium: None,
+                dispersion: None,
             },
+            element_index: None,
         }
     }
 }
 
+// 面積測度の確率密度を origin から見た立体角測度に変換する
+pub fn area_pdf_to_solid_angle_pdf(pdf: f64, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64 {
+    let v = *position - *origin;
+    let distance_pow2 = v.dot(&v);
+    let cos = normal.dot(&v).abs() / distance_pow2.sqrt();
+    if cos == 0.0 {
+        0.0
+    } else {
+        pdf * distance_pow2 / cos
+    }
+}
+
 pub trait Intersectable: Sync {
     fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool;
+
+    // 始点から max_distance 未満の距離で交差するかどうか
+    // 最も近い交点を求める必要がないので、交差が見つかった時点で打ち切ってよい
+    fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        let mut intersection = Intersection::empty();
+        intersection.distance = max_distance;
+        self.intersect(ray, &mut intersection)
+    }
+
     fn material(&self) -> &Material;
     fn aabb(&self) -> Aabb;
 
     fn nee_available(&self) -> bool;
-    fn sample_on_surface(&self, random: (f64, f64)) -> Surface;
+
+    // 表面積（光源の放射束の見積もりに使う）
+    fn surface_area(&self) -> f64;
+
+    // origin から見た表面上の点をサンプリングする
+    fn sample_on_surface(&self, origin: &Vector3, random: (f64, f64)) -> Surface;
+
+    // sample_on_surface で origin から表面上の position がサンプリングされる立体角測度の確率密度
+    fn sample_on_surface_pdf(&self, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64;
+
+    // NEE の対象となる光源かどうか
+    fn is_light(&self) -> bool {
+        self.nee_available() && self.material().emission.color != Color::zero()
+    }
+
+    // ポリゴンメッシュであれば、その形状を返す（BVH の比較モード用）
+    fn mesh(&self) -> Option<&Mesh> { None }
 }
 
 pub struct Sphere {
@@ -65,12 +118,8 @@ impl Intersectable for Sphere {
             intersection.position = ray.origin + ray.direction * t;
             intersection.distance = t;
             intersection.normal = (intersection.position - self.center).normalize();
-
-            intersection.uv.y = 1.0 - intersection.normal.y.acos() / config::PI;
-            intersection.uv.x = 0.5
-                - intersection.normal.z.signum()
-                * (intersection.normal.x / intersection.normal.xz().length()).acos()
-                / config::PI2;
+            intersection.geometric_normal = intersection.normal;
+            intersection.uv = sphere_uv(&intersection.normal);
             true
         } else {
             false
@@ -88,17 +137,71 @@ impl Intersectable for Sphere {
 
     fn nee_available(&self) -> bool { true }
 
-    // http://apollon.issp.u-tokyo.ac.jp/~watanabe/pdf/prob.pdf
-    fn sample_on_surface(&self, random: (f64, f64)) -> Surface {
-        let theta = config::PI2 * random.0;
-        let unit_z = 1.0 - 2.0 * random.1;
-        let a = (1.0 - unit_z * unit_z).sqrt();
+    fn surface_area(&self) -> f64 { 4.0 * config::PI * self.radius * self.radius }
+
+    // origin から見える範囲の円錐内で方向を一様にサンプリングする
+    // origin が球の内部にある場合は球面全体から一様にサンプリングする
+    // http://www.pbr-book.org/3ed-2018/Light_Transport_I_Surface_Reflection/Sampling_Light_Sources.html
+    fn sample_on_surface(&self, origin: &Vector3, random: (f64, f64)) -> Surface {
+        let to_center = self.center - *origin;
+        let distance_pow2 = to_center.dot(&to_center);
+        let radius_pow2 = self.radius * self.radius;
+
+        if distance_pow2 <= radius_pow2 {
+            let normal = sample_sphere_uniform(random);
+            let position = self.center + self.radius * normal;
+            let emission = self.material.emission.sample(sphere_uv(&normal));
+            let pdf = self.sample_on_surface_pdf(origin, &position, &normal);
+            return Surface { position, emission, pdf };
+        }
 
-        let normal = Vector3::new(a * theta.cos(), a * theta.sin(), unit_z);
-        let position = self.center + (self.radius + config::OFFSET) * normal;
-        let pdf = (4.0 * config::PI * self.radius * self.radius).recip();
-        Surface { position, normal, pdf }
+        let distance = distance_pow2.sqrt();
+        let cos_theta_max = (1.0 - radius_pow2 / distance_pow2).sqrt();
+        let cos_theta = 1.0 - random.0 * (1.0 - cos_theta_max);
+        let sin_theta_pow2 = 1.0 - cos_theta * cos_theta;
+        let phi = config::PI2 * random.1;
+
+        let w = to_center / distance;
+        let (tangent, binormal) = get_tangent_space_basis_gram_schmidtd(&w);
+        let direction = (tangent * phi.cos() + binormal * phi.sin()) * sin_theta_pow2.sqrt() + w * cos_theta;
+
+        // サンプリングした方向と球の手前側との交点
+        let t = distance * cos_theta - (radius_pow2 - distance_pow2 * sin_theta_pow2).max(0.0).sqrt();
+        let normal = (*origin + direction * t - self.center).normalize();
+        let position = self.center + self.radius * normal;
+        let emission = self.material.emission.sample(sphere_uv(&normal));
+        let pdf = (config::PI2 * (1.0 - cos_theta_max)).recip();
+        Surface { position, emission, pdf }
     }
+
+    fn sample_on_surface_pdf(&self, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64 {
+        let to_center = self.center - *origin;
+        let distance_pow2 = to_center.dot(&to_center);
+        let radius_pow2 = self.radius * self.radius;
+
+        if distance_pow2 <= radius_pow2 {
+            let pdf = (4.0 * config::PI * radius_pow2).recip();
+            area_pdf_to_solid_angle_pdf(pdf, origin, position, normal)
+        } else {
+            let cos_theta_max = (1.0 - radius_pow2 / distance_pow2).sqrt();
+            (config::PI2 * (1.0 - cos_theta_max)).recip()
+        }
+    }
+}
+
+fn sphere_uv(normal: &Vector3) -> Vector2 {
+    Vector2::new(
+        0.5 - normal.z.signum() * (normal.x / normal.xz().length()).acos() / config::PI2,
+        1.0 - normal.y.acos() / config::PI,
+    )
+}
+
+// http://apollon.issp.u-tokyo.ac.jp/~watanabe/pdf/prob.pdf
+fn sample_sphere_uniform(random: (f64, f64)) -> Vector3 {
+    let theta = config::PI2 * random.0;
+    let unit_z = 1.0 - 2.0 * random.1;
+    let a = (1.0 - unit_z * unit_z).sqrt();
+    Vector3::new(a * theta.cos(), a * theta.sin(), unit_z)
 }
 
 #[allow(dead_code)]
@@ -116,6 +219,7 @@ impl Intersectable for Plane {
         if t > 0.0 && t < intersection.distance {
             intersection.position = ray.origin + ray.direction * t;
             intersection.normal = self.normal;
+            intersection.geometric_normal = self.normal;
             intersection.distance = t;
 
             // normalがY軸なことを前提にUVを計算
@@ -138,7 +242,13 @@ impl Intersectable for Plane {
 
     fn nee_available(&self) -> bool { false }
 
-    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
+    fn surface_area(&self) -> f64 { config::INF }
+
+    fn sample_on_surface(&self, _origin: &Vector3, _random: (f64, f64)) -> Surface {
+        unimplemented!()
+    }
+
+    fn sample_on_surface_pdf(&self, _origin: &Vector3, _position: &Vector3, _normal: &Vector3) -> f64 {
         unimplemented!()
     }
 }
@@ -176,6 +286,7 @@ impl Intersectable for Cuboid {
                 intersection.normal = Vector3::new(0.0, 0.0, 1.0);
                 intersection.uv = uvw.xy();
             }
+            intersection.geometric_normal = intersection.normal;
             true
         } else {
             false
@@ -186,10 +297,100 @@ impl Intersectable for Cuboid {
 
     fn aabb(&self) -> Aabb { self.aabb.clone() }
 
-    fn nee_available(&self) -> bool { false }
+    fn nee_available(&self) -> bool { true }
 
-    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
-        unimplemented!()
+    fn surface_area(&self) -> f64 {
+        let size = self.aabb.max - self.aabb.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // origin から見える面の中から面積で重み付けして面を選び、その上で一様に点をサンプリングする
+    fn sample_on_surface(&self, origin: &Vector3, random: (f64, f64)) -> Surface {
+        let faces = self.visible_faces(origin);
+        let areas: Vec<_> = faces.iter().map(|face| face.area).collect();
+        let distribution = Distribution1D::new(&areas);
+        if distribution.total() == 0.0 {
+            return Surface { position: *origin, emission: Color::zero(), pdf: 0.0 };
+        }
+        let (face_index, u) = distribution.sample(random.0);
+        let face = &faces[face_index];
+
+        // 面上の 2 軸の位置を u, random.1 で決める
+        let size = self.aabb.max - self.aabb.min;
+        let (a1, a2) = ((face.axis + 1) % 3, (face.axis + 2) % 3);
+        let mut uvw = Vector3::zero();
+        set_component(&mut uvw, face.axis, if face.max_side { 1.0 } else { 0.0 });
+        set_component(&mut uvw, a1, u);
+        set_component(&mut uvw, a2, random.1);
+        let position = self.aabb.min + uvw * size;
+
+        let emission = self.material.emission.sample(Cuboid::face_uv(face.axis, &uvw));
+        let pdf = area_pdf_to_solid_angle_pdf(distribution.total().recip(), origin, &position, &face.normal());
+        Surface { position, emission, pdf }
+    }
+
+    fn sample_on_surface_pdf(&self, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64 {
+        let faces = self.visible_faces(origin);
+        let axis = if normal.x.abs() > normal.y.abs() && normal.x.abs() > normal.z.abs() {
+            0
+        } else if normal.y.abs() > normal.z.abs() {
+            1
+        } else {
+            2
+        };
+        let max_side = component(normal, axis).is_sign_positive();
+        if !faces.iter().any(|face| face.axis == axis && face.max_side == max_side) {
+            return 0.0;
+        }
+        let total_area: f64 = faces.iter().map(|face| face.area).sum();
+        area_pdf_to_solid_angle_pdf(total_area.recip(), origin, position, normal)
+    }
+}
+
+// Cuboid の 1 つの面
+struct CuboidFace {
+    axis: usize,
+    max_side: bool,
+    area: f64,
+}
+
+impl CuboidFace {
+    fn normal(&self) -> Vector3 {
+        let mut normal = Vector3::zero();
+        set_component(&mut normal, self.axis, if self.max_side { 1.0 } else { -1.0 });
+        normal
+    }
+}
+
+impl Cuboid {
+    // origin から見える面の一覧
+    // origin が内部にある場合は全ての面が見える
+    fn visible_faces(&self, origin: &Vector3) -> Vec<CuboidFace> {
+        let size = self.aabb.max - self.aabb.min;
+        let inside = (0..3).all(|axis| {
+            component(&self.aabb.min, axis) < component(origin, axis) && component(origin, axis) < component(&self.aabb.max, axis)
+        });
+
+        let mut faces = vec![];
+        for axis in 0..3 {
+            let area = component(&size, (axis + 1) % 3) * component(&size, (axis + 2) % 3);
+            if inside || component(origin, axis) < component(&self.aabb.min, axis) {
+                faces.push(CuboidFace { axis, max_side: false, area });
+            }
+            if inside || component(origin, axis) > component(&self.aabb.max, axis) {
+                faces.push(CuboidFace { axis, max_side: true, area });
+            }
+        }
+        faces
+    }
+
+    // intersect と同じ面ごとのテクスチャ座標
+    fn face_uv(axis: usize, uvw: &Vector3) -> Vector2 {
+        match axis {
+            0 => uvw.zy(),
+            1 => uvw.xiz(),
+            _ => uvw.xy(),
+        }
     }
 }
 
@@ -197,23 +398,38 @@ pub struct Face {
     pub v0: usize,
     pub v1: usize,
     pub v2: usize,
+
+    // Mesh::normals, Mesh::uvs 上の各頂点のインデックス（持たない場合は None）
+    pub normals: Option<[usize; 3]>,
+    pub uvs: Option<[usize; 3]>,
 }
 
 pub struct Mesh {
     pub vertexes: Vec<Vector3>,
+    pub normals: Vec<Vector3>,
+    pub uvs: Vec<Vector2>,
     pub faces: Vec<Face>,
     pub material: Material,
+
+    // 面積に比例してポリゴンを選ぶための分布（光源としてサンプリングする際に使う）
+    pub area_distribution: Distribution1D,
 }
 
 impl Intersectable for Mesh {
     fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
-        let mut any_hit = false;
-        for face in &self.faces {
+        let mut nearest_index = None;
+        for (index, face) in self.faces.iter().enumerate() {
             if intersect_polygon(&self.vertexes[face.v0], &self.vertexes[face.v1], &self.vertexes[face.v2], ray, intersection) {
-                any_hit = true;
+                nearest_index = Some(index);
             }
         }
-        any_hit
+
+        if let Some(index) = nearest_index {
+            self.interpolate(index, intersection);
+            true
+        } else {
+            false
+        }
     }
 
     fn material(&self) -> &Material { &self.material }
@@ -226,37 +442,148 @@ impl Intersectable for Mesh {
         }
     }
 
-    fn nee_available(&self) -> bool { false }
+    fn nee_available(&self) -> bool { self.area_distribution.total() > 0.0 }
 
-    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
-        unimplemented!()
+    fn surface_area(&self) -> f64 { self.area_distribution.total() }
+
+    // 面積で重み付けしてポリゴンを選び、その上で一様に点をサンプリングする
+    // http://www.pbr-book.org/3ed-2018/Monte_Carlo_Integration/2D_Sampling_with_Multidimensional_Transformations.html#SamplingaTriangle
+    fn sample_on_surface(&self, origin: &Vector3, random: (f64, f64)) -> Surface {
+        let (face_index, u) = self.area_distribution.sample(random.0);
+        let face = &self.faces[face_index];
+        let p0 = self.vertexes[face.v0];
+        let p1 = self.vertexes[face.v1];
+        let p2 = self.vertexes[face.v2];
+
+        // intersect_polygon と同じく、p1, p2 に対する重心座標
+        let s = u.sqrt();
+        let barycentric = Vector2::new(1.0 - s, random.1 * s);
+        let position = p0 * (1.0 - barycentric.x - barycentric.y) + p1 * barycentric.x + p2 * barycentric.y;
+        let normal = (p1 - p0).cross(&(p2 - p0)).normalize();
+
+        let mut intersection = Intersection::empty();
+        intersection.uv = barycentric;
+        self.interpolate(face_index, &mut intersection);
+        let emission = self.material.emission.sample(intersection.uv);
+        let pdf = self.sample_on_surface_pdf(origin, &position, &normal);
+        Surface { position, emission, pdf }
+    }
+
+    fn sample_on_surface_pdf(&self, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64 {
+        area_pdf_to_solid_angle_pdf(self.area_distribution.total().recip(), origin, position, normal)
+    }
+}
+
+impl Mesh {
+    pub fn new(vertexes: Vec<Vector3>, normals: Vec<Vector3>, uvs: Vec<Vector2>, faces: Vec<Face>, material: Material) -> Mesh {
+        let areas: Vec<_> = faces.iter().map(|face| {
+            let p0 = vertexes[face.v0];
+            0.5 * (vertexes[face.v1] - p0).cross(&(vertexes[face.v2] - p0)).length()
+        }).collect();
+        Mesh {
+            vertexes,
+            normals,
+            uvs,
+            faces,
+            material,
+            area_distribution: Distribution1D::new(&areas),
+        }
+    }
+
+    // 交差したポリゴンの頂点法線とテクスチャ座標を補間する
+    // intersect_polygon によって intersection.uv に重心座標が入っていることが前提
+    pub fn interpolate(&self, face_index: usize, intersection: &mut Intersection) {
+        let face = &self.faces[face_index];
+        let u = intersection.uv.x;
+        let v = intersection.uv.y;
+        let w = 1.0 - u - v;
+
+        // 表裏はポリゴンの巻き順で決まる幾何法線に合わせる
+        if let Some(n) = face.normals {
+            let normal = self.normals[n[0]] * w + self.normals[n[1]] * u + self.normals[n[2]] * v;
+            if normal.norm() > 0.0 {
+                let normal = normal.normalize();
+                intersection.normal = if normal.dot(&intersection.geometric_normal).is_sign_negative() { -normal } else { normal };
+            }
+        }
+
+        if let Some(t) = face.uvs {
+            intersection.uv = self.uvs[t[0]] * w + self.uvs[t[1]] * u + self.uvs[t[2]] * v;
+        }
+    }
+
+    // 頂点法線を、頂点を共有するポリゴンの法線をその頂点での角度で重み付けした平均として生成する
+    // 頂点法線のインデックスは頂点のインデックスと同じになる
+    pub fn generate_smooth_normals(&mut self) {
+        let mut normals = vec![Vector3::zero(); self.vertexes.len()];
+        for face in &self.faces {
+            let indexes = [face.v0, face.v1, face.v2];
+            let p0 = self.vertexes[face.v0];
+            let p1 = self.vertexes[face.v1];
+            let p2 = self.vertexes[face.v2];
+            let cross = (p1 - p0).cross(&(p2 - p0));
+            if cross.norm() == 0.0 {
+                continue;
+            }
+            let normal = cross.normalize();
+
+            for i in 0..3 {
+                let p = self.vertexes[indexes[i]];
+                let a = (self.vertexes[indexes[(i + 1) % 3]] - p).normalize();
+                let b = (self.vertexes[indexes[(i + 2) % 3]] - p).normalize();
+                let angle = a.dot(&b).clamp(-1.0, 1.0).acos();
+                normals[indexes[i]] += normal * angle;
+            }
+        }
+
+        self.normals = normals.iter().map(|n| if n.norm() > 0.0 { n.normalize() } else { *n }).collect();
+        for face in &mut self.faces {
+            face.normals = Some([face.v0, face.v1, face.v2]);
+        }
     }
 }
 
 pub struct BvhMesh {
     pub mesh: Mesh,
-    pub bvh: BvhNode,
+    pub bvh: Bvh,
 }
 
 impl Intersectable for BvhMesh {
     fn intersect(&self, ray: &Ray, intersection: &mut Intersection) -> bool {
-        self.bvh.intersect_for_mesh(&self.mesh, ray, intersection)
+        if let Some(index) = self.bvh.intersect_for_mesh(&self.mesh, ray, intersection) {
+            self.mesh.interpolate(index, intersection);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.bvh.occluded_for_mesh(&self.mesh, ray, max_distance)
     }
 
     fn material(&self) -> &Material { &self.mesh.material }
 
-    fn aabb(&self) -> Aabb { self.bvh.aabb.clone() }
+    fn aabb(&self) -> Aabb { self.bvh.aabb() }
 
-    fn nee_available(&self) -> bool { false }
+    fn nee_available(&self) -> bool { self.mesh.nee_available() }
 
-    fn sample_on_surface(&self, _random: (f64, f64)) -> Surface {
-        unimplemented!()
+    fn surface_area(&self) -> f64 { self.mesh.surface_area() }
+
+    fn mesh(&self) -> Option<&Mesh> { Some(&self.mesh) }
+
+    fn sample_on_surface(&self, origin: &Vector3, random: (f64, f64)) -> Surface {
+        self.mesh.sample_on_surface(origin, random)
+    }
+
+    fn sample_on_surface_pdf(&self, origin: &Vector3, position: &Vector3, normal: &Vector3) -> f64 {
+        self.mesh.sample_on_surface_pdf(origin, position, normal)
     }
 }
 
 impl BvhMesh {
-    pub fn from_mesh(mesh: Mesh) -> BvhMesh {
-        let bvh = BvhNode::build_from_mesh(&mesh);
+    pub fn from_mesh(mesh: Mesh, settings: &RenderSettings) -> BvhMesh {
+        let bvh = Bvh::build_from_mesh(&mesh, settings);
         //println!("bvh: {:?}", bvh);
         BvhMesh {
             bvh: bvh,
@@ -265,120 +592,120 @@ impl BvhMesh {
     }
 }
 
-pub struct Skybox {
-    pub px_texture: ImageTexture,
-    pub nx_texture: ImageTexture,
-    pub py_texture: ImageTexture,
-    pub ny_texture: ImageTexture,
-    pub pz_texture: ImageTexture,
-    pub nz_texture: ImageTexture,
-    pub intensity: Vector3,
-}
+pub trait SceneTrait: Sync {
+    fn intersect(&self, ray: &Ray) -> (bool, Intersection);
 
-impl Skybox {
-    pub fn new(px_path: &str, nx_path: &str, py_path: &str, ny_path: &str, pz_path: &str, nz_path: &str, intensity: &Vector3) -> Skybox {
-        Skybox {
-            px_texture: ImageTexture::new(px_path),
-            nx_texture: ImageTexture::new(nx_path),
-            py_texture: ImageTexture::new(py_path),
-            ny_texture: ImageTexture::new(ny_path),
-            pz_texture: ImageTexture::new(pz_path),
-            nz_texture: ImageTexture::new(nz_path),
-            intensity: *intensity,
-        }
-    }
+    // 始点から max_distance 未満の距離に遮蔽物があるかどうか（シャドウレイ用）
+    // 交点のマテリアルは評価しない
+    fn occluded(&self, ray: &Ray, max_distance: f64) -> bool;
 
-    pub fn one(px_path: &str, nx_path: &str, py_path: &str, ny_path: &str, pz_path: &str, nz_path: &str) -> Skybox {
-        Skybox::new(px_path, nx_path, py_path, ny_path, pz_path, nz_path, &Vector3::one())
-    }
+    // NEE の対象となる光源と、その Scene::elements 上のインデックス
+    fn emissions(&self) -> Vec<(usize, &Box<Intersectable>)>;
 
-    pub fn sample(&self, direction: &Vector3) -> Vector3 {
-        let abs_x = direction.x.abs();
-        let abs_y = direction.y.abs();
-        let abs_z = direction.z.abs();
+    // origin から element_index の要素上の交点を NEE でサンプリングする立体角測度の確率密度
+    // その光源が選ばれる確率（LightSampler::probability）は含まない
+    // NEE の対象外の要素であれば 0 を返す
+    fn light_pdf(&self, element_index: usize, origin: &Vector3, intersection: &Intersection) -> f64;
 
-        if abs_x > abs_y && abs_x > abs_z {
-            if direction.x.is_sign_positive() {
-                self.intensity * self.px_texture.sample_bilinear_0center(-direction.z / direction.x, direction.y / direction.x)
-            } else {
-                self.intensity * self.nx_texture.sample_bilinear_0center(-direction.z / direction.x, -direction.y / direction.x)
-            }
-        } else if abs_y > abs_x && abs_y > abs_z {
-            if direction.y.is_sign_positive() {
-                self.intensity * self.py_texture.sample_bilinear_0center(direction.x / direction.y, -direction.z / direction.y)
-            } else {
-                self.intensity * self.ny_texture.sample_bilinear_0center(-direction.x / direction.y, -direction.z / direction.y)
-            }
-        } else {
-            if direction.z.is_sign_positive() {
-                self.intensity * self.pz_texture.sample_bilinear_0center(direction.x / direction.z, direction.y / direction.z)
-            } else {
-                self.intensity * self.nz_texture.sample_bilinear_0center(direction.x / direction.z, -direction.y / direction.z)
-            }
-        }
-    }
-}
+    // 交差しなかったレイが到達する無限遠の環境光
+    fn environment(&self) -> &Environment;
 
-pub trait SceneTrait: Sync {
-    fn intersect(&self, ray: &Ray) -> (bool, Intersection);
-    fn emissions(&self) -> Vec<&Box<Intersectable>>;
+    // 形状を持たず、NEE でだけ扱う光源
+    fn punctual_lights(&self) -> &[PunctualLight];
+
+    // 全要素を含む AABB（環境光の放射束の見積もりに使う）
+    fn aabb(&self) -> Aabb;
+
+    // 物体の外側を満たす関与媒質
+    fn medium(&self) -> Option<&Medium>;
+
+    // 光をそのまま通す面（関与媒質の境界）があるかどうか
+    // 無ければシャドウレイは遮蔽の判定だけで済む
+    fn has_transparent_surfaces(&self) -> bool;
 }
 
 pub struct Scene {
     pub elements: Vec<Box<Intersectable>>,
-    pub skybox: Skybox,
+    pub environment: Box<Environment>,
+    pub lights: Vec<PunctualLight>,
+    pub medium: Option<Medium>,
 }
 
 impl SceneTrait for Scene {
     fn intersect(&self, ray: &Ray) -> (bool, Intersection) {
         let mut intersection = Intersection::empty();
-        let mut nearest: Option<&Box<Intersectable>> = None;
+        let mut nearest_index: Option<usize> = None;
 
-        for e in &self.elements {
+        for (index, e) in self.elements.iter().enumerate() {
             if e.intersect(&ray, &mut intersection) {
-                nearest = Some(&e);
+                nearest_index = Some(index);
             }
         }
 
-        if let Some(element) = nearest {
-            let material = element.material();
-            intersection.material.surface = material.surface.clone();
-            intersection.material.albedo = material.albedo.sample(intersection.uv);
-            intersection.material.emission = material.emission.sample(intersection.uv);
-            intersection.material.roughness = material.roughness.sample(intersection.uv).x;
+        if let Some(index) = nearest_index {
+            let material = self.elements[index].material();
+            intersection.element_index = Some(index);
+            intersection.material = material.sample(intersection.uv);
             (true, intersection)
         } else {
-            intersection.material.emission = self.skybox.sample(&ray.direction);
+            intersection.material.emission = self.environment.sample(&ray.direction);
             (false, intersection)
         }
     }
 
-    fn emissions(&self) -> Vec<&Box<Intersectable>> {
-        self.elements.iter().filter(|e| e.nee_available() && e.material().emission.color != Color::zero()).collect()
+    fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.elements.iter().any(|e| e.occluded(ray, max_distance))
     }
-}
 
-impl Scene {
-    pub fn add(&mut self, element: Box<Intersectable>) {
-        self.elements.push(element);
+    fn emissions(&self) -> Vec<(usize, &Box<Intersectable>)> {
+        self.elements.iter().enumerate().filter(|&(_, e)| e.is_light()).collect()
     }
 
-    pub fn add_with_check_collisions(&mut self, element: Box<Intersectable>) -> bool {
-        let aabb = element.aabb();
-        let no_collisions = self.elements.iter().all(|ref e| !e.aabb().intersect_aabb(&aabb));
-        if no_collisions {
-            self.elements.push(element);
-            true
+    fn light_pdf(&self, element_index: usize, origin: &Vector3, intersection: &Intersection) -> f64 {
+        let element = &self.elements[element_index];
+        if element.is_light() {
+            // NEE と同じく補間しない面の法線で立体角測度に変換する
+            element.sample_on_surface_pdf(origin, &intersection.position, &intersection.geometric_normal)
         } else {
-            //println!("add_with_check_collisions: collisions!: {:?}", aabb);
-            false
+            0.0
+        }
+    }
+
+    fn environment(&self) -> &Environment {
+        self.environment.as_ref()
+    }
+
+    fn punctual_lights(&self) -> &[PunctualLight] {
+        &self.lights
+    }
+
+    fn aabb(&self) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for element in &self.elements {
+            aabb.merge(&element.aabb());
         }
+        aabb
+    }
+
+    fn medium(&self) -> Option<&Medium> {
+        self.medium.as_ref()
+    }
+
+    fn has_transparent_surfaces(&self) -> bool {
+        self.elements.iter().any(|e| matches!(e.material().surface, SurfaceType::Transparent))
+    }
+}
+
+impl Scene {
+    pub fn add(&mut self, element: Box<Intersectable>) {
+        self.elements.push(element);
     }
 }
 
 pub struct BvhScene {
     pub scene: Scene,
-    pub bvh: BvhNode,
+    pub bvh: Bvh,
+    has_transparent_surfaces: bool,
 }
 
 impl SceneTrait for BvhScene {
@@ -389,28 +716,56 @@ impl SceneTrait for BvhScene {
         if let Some(index) = nearest_index {
             let element = &self.scene.elements[index];
             let material = element.material();
-            intersection.material.surface = material.surface.clone();
-            intersection.material.albedo = material.albedo.sample(intersection.uv);
-            intersection.material.emission = material.emission.sample(intersection.uv);
-            intersection.material.roughness = material.roughness.sample(intersection.uv).x;
+            intersection.element_index = Some(index);
+            intersection.material = material.sample(intersection.uv);
             (true, intersection)
         } else {
-            intersection.material.emission = self.scene.skybox.sample(&ray.direction);
+            intersection.material.emission = self.scene.environment.sample(&ray.direction);
             (false, intersection)
         }
     }
 
-    fn emissions(&self) -> Vec<&Box<Intersectable>> {
+    fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.bvh.occluded_for_scene(&self.scene, ray, max_distance)
+    }
+
+    fn emissions(&self) -> Vec<(usize, &Box<Intersectable>)> {
         self.scene.emissions()
     }
+
+    fn light_pdf(&self, element_index: usize, origin: &Vector3, intersection: &Intersection) -> f64 {
+        self.scene.light_pdf(element_index, origin, intersection)
+    }
+
+    fn environment(&self) -> &Environment {
+        self.scene.environment.as_ref()
+    }
+
+    fn punctual_lights(&self) -> &[PunctualLight] {
+        &self.scene.lights
+    }
+
+    fn aabb(&self) -> Aabb {
+        self.bvh.aabb()
+    }
+
+    fn medium(&self) -> Option<&Medium> {
+        self.scene.medium.as_ref()
+    }
+
+    fn has_transparent_surfaces(&self) -> bool {
+        self.has_transparent_surfaces
+    }
 }
 
 impl BvhScene {
-    pub fn from_scene(scene: Scene) -> BvhScene {
-        let bvh = BvhNode::build_from_scene(&scene);
+    pub fn from_scene(scene: Scene, settings: &RenderSettings) -> BvhScene {
+        let bvh = Bvh::build_from_scene(&scene, settings);
+        let has_transparent_surfaces = scene.has_transparent_surfaces();
         BvhScene {
             scene: scene,
             bvh: bvh,
+            has_transparent_surfaces,
         }
     }
 }
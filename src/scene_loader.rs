@@ -0,0 +1,995 @@
+use std::fs::File;
+use std::io::{BufReader, BufRead};
+use std::path::Path;
+use std::sync::Arc;
+
+use vector::Vector3;
+use matrix::Matrix44;
+use scene::{Scene, Sphere, Cuboid, BvhMesh};
+use environment::{Environment, Skybox, EquirectangularMap, PhysicalSky};
+use bvh::Aabb;
+use camera::{Camera, LensShape};
+use material::{Material, SurfaceType, CONDUCTOR_PRESETS};
+use texture::Texture;
+use color::{Color, hsv_to_rgb};
+use loader::{ObjLoader, LoadError, NormalMode, MtlSource};
+use config;
+use config::RenderSettings;
+use light::PunctualLight;
+use principled::PrincipledTextures;
+use spectrum::{Dispersion, DISPERSION_PRESETS};
+use medium::Medium;
+use volume::VoxelGrid;
+
+// シーン記述ファイルの読み込み
+//
+// # コメント
+// camera {
+//     eye 0.0 2.0 9.0
+//     target 0.0 1.0 0.0
+//     up 0.0 1.0 0.0
+//     fov 10.0
+//     lens circle
+//     aperture 0.2
+//     focus_distance 8.8
+// }
+//
+// skybox {
+//     cube textures/cube/LancellottiChapel
+//     intensity 1.0 1.0 1.0
+// }
+//
+// skybox {
+//     equirect textures/hdr/sky.hdr
+//     intensity 1.0
+//     rotate 90deg
+// }
+//
+// skybox {
+//     sky preetham
+//     sun_direction 0.5 0.6 -0.4
+//     turbidity 3.0
+//     ground_albedo 0.3
+//     sun_intensity 1.0
+// }
+//
+// medium {
+//     scattering 0.02
+//     anisotropy 0.3
+// }
+//
+// sphere {
+//     center 0.0 0.6 0.0
+//     radius 0.6
+//     surface ggx 0.8
+//     albedo hsv 0.2 1.0 1.0
+//     roughness 0.05
+// }
+//
+// cuboid {
+//     min -5.0 -1.0 -5.0
+//     max 5.0 0.0 5.0
+//     surface diffuse
+//     albedo textures/2d/checkered_diagonal_10_0.5_1.0_512.png
+// }
+//
+// mesh {
+//     obj models/dia/dia.obj
+//     translate 3.1 0.0 0.8
+//     rotate_y -0.5
+//     rotate_x 40.35deg
+//     surface refraction 2.42
+// }
+//
+// volume {
+//     min -1.0 0.0 -1.0
+//     max 1.0 2.0 1.0
+//     density smoke.txt
+//     density_scale 4.0
+//     scattering 0.9
+//     absorption 0.1
+// }
+//
+// point_light {
+//     position 0.0 3.0 2.0
+//     intensity 10.0
+// }
+//
+// spot_light {
+//     position 0.0 4.0 0.0
+//     target 0.0 0.0 0.0
+//     intensity 50.0 45.0 40.0
+//     angle 30deg
+//     falloff 5deg
+// }
+//
+// directional_light {
+//     direction -0.5 -1.0 0.3
+//     irradiance 2.0
+// }
+//
+// mesh の変形は記述した順に右から乗算する（Rust で Matrix44 を掛ける順序と同じ）
+// mesh の normals は頂点法線の扱いを指定する（デフォルトは file）
+//   file     OBJ の頂点法線（vn）を補間し、無ければ面の法線を使う
+//   smooth   OBJ の頂点法線を補間し、無ければ角度で重み付けした頂点法線を生成する
+//   flat     OBJ の頂点法線を無視して面の法線を使う
+// mesh の mtl を指定すると MTL ファイルのマテリアルを usemtl ごとに使う（usemtl の無いポリゴンには mesh のマテリアルを使う）
+//   mtl            OBJ の mtllib で指定された MTL ファイルを使う
+//   mtl FILE       指定した MTL ファイルを使う
+// mesh の group を指定すると、OBJ の o / g がその名前のポリゴンだけを読み込む（複数指定可）
+// skybox は次のうちどれか1つを指定する
+//   cube DIR          6 面の JPEG のディレクトリ
+//   equirect FILE     緯度経度形式の .hdr / .exr / .pfm。画像の中央が -z 方向になり、rotate で y 軸まわりに回転できる
+//   sky preetham      Preetham の空のモデルと太陽。sun_direction は必須で、
+//                     turbidity（1.7〜10、デフォルト 3）・ground_albedo（デフォルト 0.3）・sun_intensity（デフォルト 1）を指定できる
+// point_light・spot_light・directional_light は形状を持たず、NEE でだけ扱う光源
+//   intensity は放射強度、irradiance は光に垂直な面での放射照度
+//   spot_light の向きは target か direction で指定し、angle（デフォルト 30deg）は光の広がる半頂角、
+//   falloff（デフォルト 5deg）はその内側で減衰する幅
+//   directional_light の direction は光が進む向き
+// surface refraction・ggx_refraction・principled・transparent では、物体の内部を満たす関与媒質を指定できる
+// surface refraction・ggx_refraction は境界で色を付けないので albedo は指定できず、色は absorption_color で付ける
+// surface transparent は屈折せずに光をそのまま通す面で、閉じた形状を霧や煙の境界にする
+// medium ブロックには物体の外側（シーン全体）を満たす関与媒質を同じキーで指定する
+// 物体の入れ子は考慮せず、カメラは物体の外側にあるものとする
+//   absorption V / R G B                 単位長さあたりの吸収係数
+//   absorption_color V / R G B DISTANCE  距離 DISTANCE を進んだ白色光がその色になる吸収係数
+//   scattering V / R G B                 単位長さあたりの散乱係数（デフォルト 0 で、吸収だけの場合は Beer-Lambert の法則で減衰する）
+//   anisotropy G                         Henyey-Greenstein の位相関数の g（-1〜1、正で前方散乱、デフォルト 0）
+// volume は min と max の直方体の内部を、density のグリッドの密度を係数に掛けた不均質な関与媒質で満たす
+// 係数は medium と同じキーで指定し、delta tracking と ratio tracking で扱う（グリッドの形式は volume.rs を参照）
+//   density_scale V       密度に掛ける値（デフォルト 1）
+//   emission V / R G B    放射輝度。吸収係数に比例して放射するので absorption が必要
+//   emission_grid FILE    放射輝度に掛けるグリッド（炎の温度分布など）。省略すると密度のある所すべてで放射する
+// surface refraction・ggx_refraction の dispersion は、分光レンダリングでの屈折率の波長依存性（係数の波長の単位は μm）
+//   dispersion diamond / bk7 / fused_silica   代表的な誘電体
+//   dispersion cauchy A B                     n = A + B / λ^2
+//   dispersion sellmeier B1 B2 B3 C1 C2 C3    n^2 = 1 + Σ Bi λ^2 / (λ^2 - Ci)
+// surface conductor は金属の名前（Au, Ag, Cu, Al）か、複素屈折率の eta と k をそれぞれ RGB で指定する
+//   surface conductor Au
+//   surface conductor 0.2 0.92 1.1 3.9 2.45 2.14
+// surface principled は Disney の Principled BSDF で、albedo を base color、roughness を粗さとして使い、
+// 次のパラメータをテクスチャで指定できる（値は 0〜1、specular 以外のデフォルトは 0）
+//   metallic         金属らしさ
+//   specular         誘電体の反射の強さ（デフォルト 0.5 で屈折率 1.5 相当）。transmission の屈折率にもなる
+//   specular_tint    誘電体の反射を base color で色付けする割合
+//   sheen            布のような境界付近の反射
+//   clearcoat        表面を覆う透明な層の反射
+//   transmission     ガラスのような透過
+// 角度はラジアンで、末尾に deg を付けると度数として扱う
+// テクスチャの記述:
+//   V / R G B        単色
+//   hsv H S V        HSV で指定した単色
+//   PATH [R G B]     画像（色を乗算する）
+pub struct SceneLoader {
+    path: String,
+    settings: RenderSettings,
+}
+
+struct Statement {
+    line: usize,
+    key: String,
+    args: Vec<String>,
+}
+
+struct Block {
+    line: usize,
+    kind: String,
+    statements: Vec<Statement>,
+}
+
+impl SceneLoader {
+    // メッシュの BVH の構築に settings を使う
+    pub fn load(path: &str, settings: &RenderSettings) -> Result<(Camera, Scene), LoadError> {
+        let loader = SceneLoader { path: path.to_string(), settings: settings.clone() };
+        let f = File::open(path).map_err(|e| LoadError::new(path, 0, e.to_string()))?;
+        let blocks = loader.parse_blocks(BufReader::new(f))?;
+        loader.build(&blocks)
+    }
+
+    fn error<T>(&self, line: usize, message: String) -> Result<T, LoadError> {
+        Err(LoadError::new(&self.path, line, message))
+    }
+
+    fn parse_blocks<R: BufRead>(&self, reader: R) -> Result<Vec<Block>, LoadError> {
+        let mut blocks = vec![];
+        let mut current: Option<Block> = None;
+
+        for (i, line) in reader.lines().enumerate() {
+            let line_number = i + 1;
+            let l = line.map_err(|e| LoadError::new(&self.path, line_number, e.to_string()))?;
+            let content = match l.find('#') {
+                Some(pos) => &l[..pos],
+                None => &l[..],
+            };
+            let tokens: Vec<&str> = content.split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+
+            if let Some(mut block) = current.take() {
+                if tokens == ["}"] {
+                    blocks.push(block);
+                } else if tokens.contains(&"{") || tokens.contains(&"}") {
+                    return self.error(line_number, format!("unexpected brace in `{}` block", block.kind));
+                } else {
+                    block.statements.push(Statement {
+                        line: line_number,
+                        key: tokens[0].to_string(),
+                        args: tokens[1..].iter().map(|t| t.to_string()).collect(),
+                    });
+                    current = Some(block);
+                }
+            } else if tokens.len() == 2 && tokens[1] == "{" {
+                current = Some(Block {
+                    line: line_number,
+                    kind: tokens[0].to_string(),
+                    statements: vec![],
+                });
+            } else {
+                return self.error(line_number, format!("expected `<block> {{`, found `{}`", tokens.join(" ")));
+            }
+        }
+
+        if let Some(block) = current {
+            return self.error(block.line, format!("unclosed `{}` block", block.kind));
+        }
+
+        Ok(blocks)
+    }
+
+    fn build(&self, blocks: &[Block]) -> Result<(Camera, Scene), LoadError> {
+        let camera = match self.find_unique(blocks, "camera")? {
+            Some(block) => self.build_camera(block)?,
+            None => return self.error(0, "missing `camera` block".to_string()),
+        };
+        let environment = match self.find_unique(blocks, "skybox")? {
+            Some(block) => self.build_environment(block)?,
+            None => return self.error(0, "missing `skybox` block".to_string()),
+        };
+
+        let medium = match self.find_unique(blocks, "medium")? {
+            Some(block) => Some(self.build_medium(block)?),
+            None => None,
+        };
+
+        let mut scene = Scene {
+            elements: vec![],
+            environment,
+            lights: vec![],
+            medium,
+        };
+
+        for block in blocks {
+            match block.kind.as_str() {
+                "camera" | "skybox" | "medium" => {}
+                "sphere" => scene.add(Box::new(self.build_sphere(block)?)),
+                "cuboid" => scene.add(Box::new(self.build_cuboid(block)?)),
+                "volume" => scene.add(Box::new(self.build_volume(block)?)),
+                "mesh" => for mesh in self.build_mesh(block)? {
+                    scene.add(Box::new(mesh));
+                },
+                "point_light" => scene.lights.push(self.build_point_light(block)?),
+                "spot_light" => scene.lights.push(self.build_spot_light(block)?),
+                "directional_light" => scene.lights.push(self.build_directional_light(block)?),
+                kind => return self.error(block.line, format!("unknown block `{}`", kind)),
+            }
+        }
+
+        Ok((camera, scene))
+    }
+
+    fn find_unique<'a>(&self, blocks: &'a [Block], kind: &str) -> Result<Option<&'a Block>, LoadError> {
+        let mut found = None;
+        for block in blocks.iter().filter(|b| b.kind == kind) {
+            if found.is_some() {
+                return self.error(block.line, format!("duplicate `{}` block", kind));
+            }
+            found = Some(block);
+        }
+        Ok(found)
+    }
+
+    fn build_camera(&self, block: &Block) -> Result<Camera, LoadError> {
+        let mut eye = None;
+        let mut target = None;
+        let mut up = Vector3::new(0.0, 1.0, 0.0);
+        let mut fov = None;
+        let mut lens_shape = LensShape::Circle;
+        let mut aperture = 0.0;
+        let mut focus_distance = None;
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "eye" => eye = Some(self.parse_vector3(s)?),
+                "target" => target = Some(self.parse_vector3(s)?),
+                "up" => up = self.parse_vector3(s)?,
+                "fov" => fov = Some(self.parse_single(s)?),
+                "lens" => {
+                    lens_shape = match self.single_arg(s)? {
+                        "circle" => LensShape::Circle,
+                        "square" => LensShape::Square,
+                        other => return self.error(s.line, format!("unknown lens shape `{}`", other)),
+                    }
+                }
+                "aperture" => aperture = self.parse_single(s)?,
+                "focus_distance" => focus_distance = Some(self.parse_single(s)?),
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        Ok(Camera::new(
+            self.require(eye, "eye", block)?,
+            self.require(target, "target", block)?,
+            up.normalize(),
+            self.require(fov, "fov", block)?,
+            lens_shape,
+            aperture,
+            self.require(focus_distance, "focus_distance", block)?,
+        ))
+    }
+
+    fn build_environment(&self, block: &Block) -> Result<Box<Environment>, LoadError> {
+        let mut source: Option<&Statement> = None;
+        // cube / equirect / sky のうち、特定の種類でだけ使えるキーと、その種類
+        let mut specific_keys = vec![];
+        let mut intensity = Vector3::one();
+        let mut rotation = 0.0;
+        let mut sun_direction = None;
+        let mut turbidity = 3.0;
+        let mut ground_albedo = Color::from_one(0.3);
+        let mut sun_intensity = 1.0;
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "cube" | "equirect" | "sky" => {
+                    if let Some(first) = source {
+                        return self.error(s.line, format!("`{}` and `{}` cannot be used together", first.key, s.key));
+                    }
+                    source = Some(s);
+                }
+                "intensity" => intensity = self.parse_color(s, &s.args)?,
+                "rotate" => {
+                    rotation = self.parse_angle(s)?;
+                    specific_keys.push((s, "equirect"));
+                }
+                "sun_direction" => {
+                    let direction = self.parse_vector3(s)?;
+                    if direction.norm() == 0.0 {
+                        return self.error(s.line, "`sun_direction` must not be zero".to_string());
+                    }
+                    sun_direction = Some(direction);
+                    specific_keys.push((s, "sky"));
+                }
+                "turbidity" => {
+                    turbidity = self.parse_single(s)?;
+                    if !(1.7..=10.0).contains(&turbidity) {
+                        return self.error(s.line, format!("`turbidity` must be between 1.7 and 10, found {}", turbidity));
+                    }
+                    specific_keys.push((s, "sky"));
+                }
+                "ground_albedo" => {
+                    ground_albedo = self.parse_color(s, &s.args)?;
+                    specific_keys.push((s, "sky"));
+                }
+                "sun_intensity" => {
+                    sun_intensity = self.parse_single(s)?;
+                    specific_keys.push((s, "sky"));
+                }
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        let source = match source {
+            Some(s) => s,
+            None => return self.error(block.line, "`skybox` block requires `cube`, `equirect` or `sky`".to_string()),
+        };
+        for (s, kind) in specific_keys {
+            if source.key != kind {
+                return self.error(s.line, format!("`{}` is only supported with `{}`", s.key, kind));
+            }
+        }
+
+        match source.key.as_str() {
+            "cube" => {
+                let dir = self.single_arg(source)?;
+                for face in &["posx", "negx", "posy", "negy", "posz", "negz"] {
+                    self.check_file(source, &format!("{}/{}.jpg", dir, face))?;
+                }
+                Ok(Box::new(Skybox::new(
+                    &format!("{}/posx.jpg", dir),
+                    &format!("{}/negx.jpg", dir),
+                    &format!("{}/posy.jpg", dir),
+                    &format!("{}/negy.jpg", dir),
+                    &format!("{}/posz.jpg", dir),
+                    &format!("{}/negz.jpg", dir),
+                    &intensity,
+                )))
+            }
+            "equirect" => {
+                let path = self.single_arg(source)?;
+                self.check_file(source, path)?;
+                match EquirectangularMap::load(path, &intensity, rotation) {
+                    Ok(map) => Ok(Box::new(map)),
+                    Err(e) => self.error(source.line, format!("{}: {}", path, e)),
+                }
+            }
+            _ => {
+                match self.single_arg(source)? {
+                    "preetham" => {}
+                    model => return self.error(source.line, format!("unknown sky model `{}` (preetham)", model)),
+                }
+                let sun_direction = self.require(sun_direction, "sun_direction", block)?;
+                Ok(Box::new(PhysicalSky::new(&sun_direction, turbidity, &ground_albedo, &intensity, sun_intensity)))
+            }
+        }
+    }
+
+    fn build_sphere(&self, block: &Block) -> Result<Sphere, LoadError> {
+        let mut center = None;
+        let mut radius = None;
+        let mut material = default_material();
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "center" => center = Some(self.parse_vector3(s)?),
+                "radius" => radius = Some(self.parse_single(s)?),
+                _ => if !self.parse_material(s, &mut material)? {
+                    return self.unknown_key(s, block);
+                },
+            }
+        }
+
+        self.check_material(block, &material)?;
+        Ok(Sphere {
+            center: self.require(center, "center", block)?,
+            radius: self.require(radius, "radius", block)?,
+            material,
+        })
+    }
+
+    fn build_cuboid(&self, block: &Block) -> Result<Cuboid, LoadError> {
+        let mut min = None;
+        let mut max = None;
+        let mut material = default_material();
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "min" => min = Some(self.parse_vector3(s)?),
+                "max" => max = Some(self.parse_vector3(s)?),
+                _ => if !self.parse_material(s, &mut material)? {
+                    return self.unknown_key(s, block);
+                },
+            }
+        }
+
+        self.check_material(block, &material)?;
+        Ok(Cuboid {
+            aabb: Aabb {
+                min: self.require(min, "min", block)?,
+                max: self.require(max, "max", block)?,
+            },
+            material,
+        })
+    }
+
+    // 密度のグリッドを持つ関与媒質を、transparent な直方体の内部として扱う
+    fn build_volume(&self, block: &Block) -> Result<Cuboid, LoadError> {
+        let mut min = None;
+        let mut max = None;
+        let mut density = None;
+        let mut density_scale = 1.0;
+        let mut emission_grid = None;
+        let mut medium = Medium::new();
+        let mut max_line = block.line;
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "min" => min = Some(self.parse_vector3(s)?),
+                "max" => {
+                    max = Some(self.parse_vector3(s)?);
+                    max_line = s.line;
+                }
+                "density" => {
+                    let path = self.single_arg(s)?;
+                    self.check_file(s, path)?;
+                    density = Some(path.to_string());
+                }
+                "density_scale" => {
+                    density_scale = self.parse_single(s)?;
+                    if density_scale < 0.0 {
+                        return self.error(s.line, "`density_scale` must not be negative".to_string());
+                    }
+                }
+                "emission" => medium.emission = self.parse_coefficient(s)?,
+                "emission_grid" => {
+                    let path = self.single_arg(s)?;
+                    self.check_file(s, path)?;
+                    emission_grid = Some(path.to_string());
+                }
+                "absorption" | "absorption_color" | "scattering" | "anisotropy" => self.parse_medium(s, &mut medium)?,
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        if medium.emission != Color::zero() && medium.absorption == Color::zero() {
+            return self.error(block.line, "`emission` of `volume` block requires `absorption`".to_string());
+        }
+
+        let aabb = Aabb {
+            min: self.require(min, "min", block)?,
+            max: self.require(max, "max", block)?,
+        };
+        // グリッドの座標は範囲の大きさで割って求めるので、厚みの無い範囲は扱えない
+        let size = aabb.max - aabb.min;
+        if !(size.x > 0.0 && size.y > 0.0 && size.z > 0.0) {
+            return self.error(max_line, "`max` must be greater than `min` on every axis".to_string());
+        }
+        let density = self.require(density, "density", block)?;
+        medium.density = Some(Arc::new(VoxelGrid::load(&density, aabb.clone(), density_scale)?));
+        if let Some(path) = emission_grid {
+            medium.emission_grid = Some(Arc::new(VoxelGrid::load(&path, aabb.clone(), 1.0)?));
+        }
+
+        let mut material = default_material();
+        material.surface = SurfaceType::Transparent;
+        material.medium = Some(medium);
+        Ok(Cuboid { aabb, material })
+    }
+
+    fn build_point_light(&self, block: &Block) -> Result<PunctualLight, LoadError> {
+        let mut position = None;
+        let mut intensity = None;
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "position" => position = Some(self.parse_vector3(s)?),
+                "intensity" => intensity = Some(self.parse_color(s, &s.args)?),
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        Ok(PunctualLight::Point {
+            position: self.require(position, "position", block)?,
+            intensity: self.require(intensity, "intensity", block)?,
+        })
+    }
+
+    fn build_spot_light(&self, block: &Block) -> Result<PunctualLight, LoadError> {
+        let mut position = None;
+        let mut aim: Option<(&Statement, Vector3)> = None;
+        let mut intensity = None;
+        let mut angle = 30.0f64.to_radians();
+        let mut falloff = 5.0f64.to_radians();
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "position" => position = Some(self.parse_vector3(s)?),
+                "target" | "direction" => {
+                    if let Some((first, _)) = aim {
+                        return self.error(s.line, format!("`{}` and `{}` cannot be used together", first.key, s.key));
+                    }
+                    aim = Some((s, self.parse_vector3(s)?));
+                }
+                "intensity" => intensity = Some(self.parse_color(s, &s.args)?),
+                "angle" => {
+                    angle = self.parse_angle(s)?;
+                    if angle <= 0.0 || angle > config::PI {
+                        return self.error(s.line, "`angle` must be between 0 and 180deg".to_string());
+                    }
+                }
+                "falloff" => {
+                    falloff = self.parse_angle(s)?;
+                    if falloff < 0.0 {
+                        return self.error(s.line, "`falloff` must not be negative".to_string());
+                    }
+                }
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        let position = self.require(position, "position", block)?;
+        let direction = match aim {
+            Some((s, v)) => {
+                let direction = if s.key == "target" { v - position } else { v };
+                if direction.norm() == 0.0 {
+                    let message = if s.key == "target" { "`target` must differ from `position`" } else { "`direction` must not be zero" };
+                    return self.error(s.line, message.to_string());
+                }
+                direction.normalize()
+            }
+            None => return self.error(block.line, "`spot_light` block requires `target` or `direction`".to_string()),
+        };
+
+        Ok(PunctualLight::Spot {
+            position,
+            direction,
+            intensity: self.require(intensity, "intensity", block)?,
+            cos_inner: (angle - falloff).max(0.0).cos(),
+            cos_outer: angle.cos(),
+        })
+    }
+
+    fn build_directional_light(&self, block: &Block) -> Result<PunctualLight, LoadError> {
+        let mut direction = None;
+        let mut irradiance = None;
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "direction" => {
+                    let v = self.parse_vector3(s)?;
+                    if v.norm() == 0.0 {
+                        return self.error(s.line, "`direction` must not be zero".to_string());
+                    }
+                    direction = Some(v.normalize());
+                }
+                "irradiance" => irradiance = Some(self.parse_color(s, &s.args)?),
+                _ => return self.unknown_key(s, block),
+            }
+        }
+
+        Ok(PunctualLight::Directional {
+            direction: self.require(direction, "direction", block)?,
+            irradiance: self.require(irradiance, "irradiance", block)?,
+        })
+    }
+
+    fn build_mesh(&self, block: &Block) -> Result<Vec<BvhMesh>, LoadError> {
+        let mut obj = None;
+        let mut matrix = Matrix44::identity();
+        let mut normal_mode = NormalMode::File;
+        let mut groups = vec![];
+        let mut mtl = MtlSource::None;
+        let mut material = default_material();
+
+        for s in &block.statements {
+            match s.key.as_str() {
+                "obj" => {
+                    let path = self.single_arg(s)?;
+                    self.check_file(s, path)?;
+                    obj = Some(path.to_string());
+                }
+                "translate" => {
+                    let t = self.parse_vector3(s)?;
+                    matrix = matrix * Matrix44::translate(t.x, t.y, t.z);
+                }
+                "scale" => {
+                    let v = self.parse_color(s, &s.args)?;
+                    matrix = matrix * Matrix44::scale(v.x, v.y, v.z);
+                }
+                "rotate_x" => matrix = matrix * Matrix44::rotate_x(self.parse_angle(s)?),
+                "rotate_y" => matrix = matrix * Matrix44::rotate_y(self.parse_angle(s)?),
+                "rotate_z" => matrix = matrix * Matrix44::rotate_z(self.parse_angle(s)?),
+                "normals" => {
+                    normal_mode = match self.single_arg(s)?.parse() {
+                        Ok(mode) => mode,
+                        Err(message) => return self.error(s.line, message),
+                    };
+                }
+                "mtl" => {
+                    mtl = match s.args.len() {
+                        0 => MtlSource::Mtllib,
+                        _ => {
+                            let path = self.single_arg(s)?;
+                            self.check_file(s, path)?;
+                            MtlSource::Path(path.to_string())
+                        }
+                    };
+                }
+                "group" => {
+                    if s.args.is_empty() {
+                        return self.error(s.line, "`group` requires at least 1 argument".to_string());
+                    }
+                    groups.extend(s.args.iter().cloned());
+                }
+                _ => if !self.parse_material(s, &mut material)? {
+                    return self.unknown_key(s, block);
+                },
+            }
+        }
+
+        let path = self.require(obj, "obj", block)?;
+        self.check_material(block, &material)?;
+        let meshes = ObjLoader::load(&path, matrix, material, &normal_mode, &groups, &mtl)?;
+        Ok(meshes.into_iter().map(|mesh| BvhMesh::from_mesh(mesh, &self.settings)).collect())
+    }
+
+    // マテリアルに関するキーであれば material に反映して true を返す
+    fn parse_material(&self, s: &Statement, material: &mut Material) -> Result<bool, LoadError> {
+        match s.key.as_str() {
+            "surface" => material.surface = self.parse_surface(s)?,
+            "albedo" => material.albedo = self.parse_texture(s)?,
+            "emission" => material.emission = self.parse_texture(s)?,
+            "roughness" => material.roughness = self.parse_texture(s)?,
+            "metallic" => material.principled.metallic = self.parse_texture(s)?,
+            "specular" => material.principled.specular = self.parse_texture(s)?,
+            "specular_tint" => material.principled.specular_tint = self.parse_texture(s)?,
+            "sheen" => material.principled.sheen = self.parse_texture(s)?,
+            "clearcoat" => material.principled.clearcoat = self.parse_texture(s)?,
+            "transmission" => material.principled.transmission = self.parse_texture(s)?,
+            "absorption" | "absorption_color" | "scattering" | "anisotropy" => {
+                let mut medium = material.medium.take().unwrap_or_else(Medium::new);
+                self.parse_medium(s, &mut medium)?;
+                material.medium = Some(medium);
+            }
+            "dispersion" => material.dispersion = Some(self.parse_dispersion(s)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    // principled のパラメータや吸収が、それを使わない surface で指定されていないか確認する
+    // 誘電体の色は albedo ではなく吸収で付ける
+    fn check_material(&self, block: &Block, material: &Material) -> Result<(), LoadError> {
+        let (principled, refraction, transparent) = match material.surface {
+            SurfaceType::Principled => (true, false, false),
+            SurfaceType::Refraction { refractive_index: _ } | SurfaceType::GGXRefraction { refractive_index: _ } => (false, true, false),
+            SurfaceType::Transparent => (false, false, true),
+            _ => (false, false, false),
+        };
+        let principled_keys = ["metallic", "specular", "specular_tint", "sheen", "clearcoat", "transmission"];
+        let medium_keys = ["absorption", "absorption_color", "scattering", "anisotropy"];
+        for s in &block.statements {
+            if !principled && principled_keys.contains(&s.key.as_str()) {
+                return self.error(s.line, format!("`{}` is only supported with `surface principled`", s.key));
+            }
+            if !principled && !refraction && !transparent && medium_keys.contains(&s.key.as_str()) {
+                return self.error(s.line, format!("`{}` is only supported with `surface refraction`, `ggx_refraction`, `principled` or `transparent`", s.key));
+            }
+            if refraction && s.key == "albedo" {
+                return self.error(s.line, "`albedo` is not supported with `surface refraction` or `ggx_refraction` (use `absorption_color`)".to_string());
+            }
+            if !refraction && s.key == "dispersion" {
+                return self.error(s.line, "`dispersion` is only supported with `surface refraction` or `ggx_refraction`".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    // 誘電体の名前か、Cauchy または Sellmeier の式の係数
+    fn parse_dispersion(&self, s: &Statement) -> Result<Dispersion, LoadError> {
+        if s.args.is_empty() {
+            return self.error(s.line, "`dispersion` requires a model".to_string());
+        }
+        let params = &s.args[1..];
+        let values = params.iter().map(|v| self.parse_f64(s.line, v)).collect::<Result<Vec<_>, _>>()?;
+        match s.args[0].as_str() {
+            "cauchy" => {
+                if values.len() != 2 {
+                    return self.error(s.line, "`dispersion cauchy` takes 2 parameters (A, B)".to_string());
+                }
+                Ok(Dispersion::Cauchy { a: values[0], b: values[1] })
+            }
+            "sellmeier" => {
+                if values.len() != 6 {
+                    return self.error(s.line, "`dispersion sellmeier` takes 6 parameters (B1 B2 B3, C1 C2 C3)".to_string());
+                }
+                Ok(Dispersion::Sellmeier {
+                    b: [values[0], values[1], values[2]],
+                    c: [values[3], values[4], values[5]],
+                })
+            }
+            name => {
+                let names: Vec<_> = DISPERSION_PRESETS.iter().map(|preset| preset.0).collect();
+                match DISPERSION_PRESETS.iter().find(|preset| preset.0 == name) {
+                    Some(&(_, b, c)) if params.is_empty() => Ok(Dispersion::Sellmeier { b, c }),
+                    Some(_) => self.error(s.line, format!("`dispersion {}` takes no parameters", name)),
+                    None => self.error(s.line, format!("unknown dispersion `{}` (cauchy, sellmeier, {})", name, names.join(", "))),
+                }
+            }
+        }
+    }
+
+    fn build_medium(&self, block: &Block) -> Result<Medium, LoadError> {
+        let mut medium = Medium::new();
+        for s in &block.statements {
+            match s.key.as_str() {
+                "absorption" | "absorption_color" | "scattering" | "anisotropy" => self.parse_medium(s, &mut medium)?,
+                _ => return self.unknown_key(s, block),
+            }
+        }
+        Ok(medium)
+    }
+
+    // 関与媒質のキーを medium に反映する
+    fn parse_medium(&self, s: &Statement, medium: &mut Medium) -> Result<(), LoadError> {
+        match s.key.as_str() {
+            "absorption" => medium.absorption = self.parse_coefficient(s)?,
+            "absorption_color" => medium.absorption = self.parse_absorption_color(s)?,
+            "scattering" => medium.scattering = self.parse_coefficient(s)?,
+            "anisotropy" => {
+                let g = self.parse_single(s)?;
+                if g <= -1.0 || g >= 1.0 {
+                    return self.error(s.line, "`anisotropy` must be between -1 and 1".to_string());
+                }
+                medium.anisotropy = g;
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    // 単位長さあたりの係数
+    fn parse_coefficient(&self, s: &Statement) -> Result<Color, LoadError> {
+        let coefficient = self.parse_color(s, &s.args)?;
+        if coefficient.x < 0.0 || coefficient.y < 0.0 || coefficient.z < 0.0 {
+            return self.error(s.line, format!("`{}` must not be negative", s.key));
+        }
+        Ok(coefficient)
+    }
+
+    // 距離 distance を進んだ光が color になる吸収係数
+    fn parse_absorption_color(&self, s: &Statement) -> Result<Color, LoadError> {
+        if s.args.len() != 2 && s.args.len() != 4 {
+            return self.error(s.line, "`absorption_color` requires a color and a distance".to_string());
+        }
+        let (color_args, distance) = s.args.split_at(s.args.len() - 1);
+        let color = self.parse_color(s, color_args)?;
+        let distance = self.parse_f64(s.line, &distance[0])?;
+        if distance <= 0.0 {
+            return self.error(s.line, "`absorption_color` distance must be positive".to_string());
+        }
+        match Medium::absorption_from_color(&color, distance) {
+            Some(absorption) => Ok(absorption),
+            None => self.error(s.line, "`absorption_color` color must be in (0, 1]".to_string()),
+        }
+    }
+
+    fn parse_surface(&self, s: &Statement) -> Result<SurfaceType, LoadError> {
+        if s.args.is_empty() {
+            return self.error(s.line, "`surface` requires a type".to_string());
+        }
+
+        let params = &s.args[1..];
+        let surface = match s.args[0].as_str() {
+            "diffuse" => SurfaceType::Diffuse,
+            "specular" => SurfaceType::Specular,
+            "refraction" => SurfaceType::Refraction { refractive_index: self.parse_surface_param(s, params)? },
+            "ggx" => SurfaceType::GGX { f0: self.parse_surface_param(s, params)? },
+            "ggx_refraction" => SurfaceType::GGXRefraction { refractive_index: self.parse_surface_param(s, params)? },
+            "conductor" => self.parse_conductor(s, params)?,
+            "principled" => SurfaceType::Principled,
+            "transparent" => SurfaceType::Transparent,
+            other => return self.error(s.line, format!("unknown surface type `{}`", other)),
+        };
+
+        match surface {
+            SurfaceType::Diffuse | SurfaceType::Specular | SurfaceType::Principled | SurfaceType::Transparent if !params.is_empty() => {
+                self.error(s.line, format!("surface type `{}` takes no parameters", s.args[0]))
+            }
+            _ => Ok(surface),
+        }
+    }
+
+    // 金属の名前か、eta と k の RGB の 6 つの値
+    fn parse_conductor(&self, s: &Statement, params: &[String]) -> Result<SurfaceType, LoadError> {
+        if params.len() == 1 {
+            let names: Vec<_> = CONDUCTOR_PRESETS.iter().map(|preset| preset.0).collect();
+            return match CONDUCTOR_PRESETS.iter().find(|preset| preset.0 == params[0]) {
+                Some(&(_, eta, k)) => Ok(SurfaceType::Conductor {
+                    eta: Color::new(eta[0], eta[1], eta[2]),
+                    k: Color::new(k[0], k[1], k[2]),
+                }),
+                None => self.error(s.line, format!("unknown conductor `{}` ({})", params[0], names.join(", "))),
+            };
+        }
+        if params.len() != 6 {
+            return self.error(s.line, "surface type `conductor` takes a metal name or 6 parameters (eta RGB, k RGB)".to_string());
+        }
+        Ok(SurfaceType::Conductor {
+            eta: self.parse_vector3_args(s, &params[..3])?,
+            k: self.parse_vector3_args(s, &params[3..])?,
+        })
+    }
+
+    fn parse_surface_param(&self, s: &Statement, params: &[String]) -> Result<f64, LoadError> {
+        if params.len() != 1 {
+            return self.error(s.line, format!("surface type `{}` takes 1 parameter", s.args[0]));
+        }
+        self.parse_f64(s.line, &params[0])
+    }
+
+    fn parse_texture(&self, s: &Statement) -> Result<Texture, LoadError> {
+        if s.args.is_empty() {
+            return self.error(s.line, format!("`{}` requires a color or a texture path", s.key));
+        }
+
+        let first = &s.args[0];
+        if first.parse::<f64>().is_ok() {
+            Ok(Texture::from_color(self.parse_color(s, &s.args)?))
+        } else if first == "hsv" {
+            let hsv = self.parse_vector3_args(s, &s.args[1..])?;
+            Ok(Texture::from_color(hsv_to_rgb(hsv)))
+        } else {
+            self.check_file(s, first)?;
+            if s.args.len() == 1 {
+                Ok(Texture::from_path(first))
+            } else {
+                Ok(Texture::new(first, self.parse_color(s, &s.args[1..])?))
+            }
+        }
+    }
+
+    // 1つの値なら全成分に同じ値を、3つの値ならそれぞれの成分とする
+    fn parse_color(&self, s: &Statement, args: &[String]) -> Result<Color, LoadError> {
+        if args.len() == 1 {
+            Ok(Color::from_one(self.parse_f64(s.line, &args[0])?))
+        } else {
+            self.parse_vector3_args(s, args)
+        }
+    }
+
+    fn parse_vector3(&self, s: &Statement) -> Result<Vector3, LoadError> {
+        self.parse_vector3_args(s, &s.args)
+    }
+
+    fn parse_vector3_args(&self, s: &Statement, args: &[String]) -> Result<Vector3, LoadError> {
+        if args.len() != 3 {
+            return self.error(s.line, format!("`{}` requires 3 numbers, found {}", s.key, args.len()));
+        }
+        Ok(Vector3::new(
+            self.parse_f64(s.line, &args[0])?,
+            self.parse_f64(s.line, &args[1])?,
+            self.parse_f64(s.line, &args[2])?,
+        ))
+    }
+
+    fn parse_single(&self, s: &Statement) -> Result<f64, LoadError> {
+        let arg = self.single_arg(s)?;
+        self.parse_f64(s.line, arg)
+    }
+
+    fn parse_angle(&self, s: &Statement) -> Result<f64, LoadError> {
+        let arg = self.single_arg(s)?;
+        if let Some(degree) = arg.strip_suffix("deg") {
+            Ok(self.parse_f64(s.line, degree)?.to_radians())
+        } else {
+            self.parse_f64(s.line, arg)
+        }
+    }
+
+    fn parse_f64(&self, line: usize, token: &str) -> Result<f64, LoadError> {
+        match token.parse::<f64>() {
+            Ok(v) => Ok(v),
+            Err(_) => self.error(line, format!("invalid number `{}`", token)),
+        }
+    }
+
+    fn single_arg<'a>(&self, s: &'a Statement) -> Result<&'a str, LoadError> {
+        if s.args.len() != 1 {
+            return self.error(s.line, format!("`{}` requires 1 argument, found {}", s.key, s.args.len()));
+        }
+        Ok(&s.args[0])
+    }
+
+    fn check_file(&self, s: &Statement, path: &str) -> Result<(), LoadError> {
+        if Path::new(path).is_file() {
+            Ok(())
+        } else {
+            self.error(s.line, format!("file not found `{}`", path))
+        }
+    }
+
+    fn require<T>(&self, value: Option<T>, key: &str, block: &Block) -> Result<T, LoadError> {
+        match value {
+            Some(v) => Ok(v),
+            None => self.error(block.line, format!("`{}` block requires `{}`", block.kind, key)),
+        }
+    }
+
+    fn unknown_key<T>(&self, s: &Statement, block: &Block) -> Result<T, LoadError> {
+        self.error(s.line, format!("unknown key `{}` in `{}` block", s.key, block.kind))
+    }
+}
+
+fn default_material() -> Material {
+    Material {
+        surface: SurfaceType::Diffuse,
+        albedo: Texture::white(),
+        emission: Texture::black(),
+        roughness: Texture::black(),
+        principled: PrincipledTextures::new(),
+        medium: None,
+        dispersion: None,
+    }
+}
@@ -0,0 +1,141 @@
+use color::Color;
+use vector::Vector3;
+
+// 分光レンダリングで扱う波長の範囲 [nm]
+// Smits の RGB からスペクトルへの変換の定義域に合わせる
+pub const LAMBDA_MIN: f64 = 380.0;
+pub const LAMBDA_MAX: f64 = 720.0;
+
+// 波長を一様にサンプリングする。確率密度は 1 / (LAMBDA_MAX - LAMBDA_MIN)
+pub fn sample_wavelength(u: f64) -> f64 {
+    LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * u
+}
+
+// B. Smits, "An RGB to Spectrum Conversion for Reflectances", 1999
+// 380nm〜720nm を 10 分割した区間ごとの値
+const SMITS_WHITE: [f64; 10] = [1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000];
+const SMITS_CYAN: [f64; 10] = [0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000];
+const SMITS_MAGENTA: [f64; 10] = [1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959];
+const SMITS_YELLOW: [f64; 10] = [0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840];
+const SMITS_RED: [f64; 10] = [0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149];
+const SMITS_GREEN: [f64; 10] = [0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025];
+const SMITS_BLUE: [f64; 10] = [1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496];
+
+// RGB の色をスペクトルに変換して、波長 lambda での値を求める
+// 反射率だけでなく放射輝度や吸収係数などの正の値にも使う
+pub fn rgb_to_spectrum(color: &Color, lambda: f64) -> f64 {
+    let bin = ((lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN) * 10.0).clamp(0.0, 9.0) as usize;
+    let (r, g, b) = (color.x, color.y, color.z);
+
+    // 最小の成分を白で、残りを 2 次色と原色で表す
+    if r <= g && r <= b {
+        r * SMITS_WHITE[bin] + if g <= b {
+            (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+        } else {
+            (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+        }
+    } else if g <= r && g <= b {
+        g * SMITS_WHITE[bin] + if r <= b {
+            (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+        } else {
+            (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+        }
+    } else {
+        b * SMITS_WHITE[bin] + if r <= g {
+            (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+        } else {
+            (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+        }
+    }
+}
+
+// RGB のチャンネルごとの値を 650nm, 550nm, 450nm の値とみなして線形補間する
+// 金属の複素屈折率のように、波長ごとの測定値を RGB に割り当てた値に使う
+pub fn interpolate_rgb_samples(color: &Color, lambda: f64) -> f64 {
+    if lambda <= 450.0 {
+        color.z
+    } else if lambda <= 550.0 {
+        color.z + (color.y - color.z) * (lambda - 450.0) / 100.0
+    } else if lambda <= 650.0 {
+        color.y + (color.x - color.y) * (lambda - 550.0) / 100.0
+    } else {
+        color.x
+    }
+}
+
+// CIE 1931 等色関数の近似
+// C. Wyman, P.-P. Sloan, P. Shirley, "Simple Analytic Approximations to the CIE XYZ Color Matching Functions", JCGT 2013
+fn cie_xyz(lambda: f64) -> Vector3 {
+    let g = |mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (lambda - mu) / if lambda < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    Vector3::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+// XYZ から線形 sRGB への変換
+fn xyz_to_rgb(xyz: &Vector3) -> Color {
+    Color::new(
+        3.2404542 * xyz.x - 1.5371385 * xyz.y - 0.4985314 * xyz.z,
+        -0.9692660 * xyz.x + 1.8760108 * xyz.y + 0.0415560 * xyz.z,
+        0.0556434 * xyz.x - 0.2040259 * xyz.y + 1.0572252 * xyz.z,
+    )
+}
+
+// サンプリングした波長の放射輝度を RGB に戻す
+// 値が一定のスペクトルが RGB の (1, 1, 1) になるようにチャンネルごとに正規化する
+pub struct SpectrumToRgb {
+    white: Color,
+}
+
+impl SpectrumToRgb {
+    pub fn new() -> SpectrumToRgb {
+        let steps = (LAMBDA_MAX - LAMBDA_MIN) as usize;
+        let step = (LAMBDA_MAX - LAMBDA_MIN) / steps as f64;
+        let mut xyz = Vector3::zero();
+        for i in 0..steps {
+            xyz += cie_xyz(LAMBDA_MIN + (i as f64 + 0.5) * step) * step;
+        }
+        SpectrumToRgb { white: xyz_to_rgb(&xyz) }
+    }
+
+    // 波長 lambda を sample_wavelength でサンプリングしたときの、放射輝度 1 あたりの RGB への寄与
+    pub fn weight(&self, lambda: f64) -> Color {
+        xyz_to_rgb(&cie_xyz(lambda)) * (LAMBDA_MAX - LAMBDA_MIN) / self.white
+    }
+}
+
+// 屈折率の波長依存性（分散）。係数の波長の単位は μm
+#[derive(Clone, Copy, Debug)]
+pub enum Dispersion {
+    // n = a + b / λ^2
+    Cauchy { a: f64, b: f64 },
+    // n^2 = 1 + Σ b_i λ^2 / (λ^2 - c_i)
+    Sellmeier { b: [f64; 3], c: [f64; 3] },
+}
+
+// 代表的な誘電体の Sellmeier の係数
+pub const DISPERSION_PRESETS: [(&str, [f64; 3], [f64; 3]); 3] = [
+    ("diamond", [0.3306, 4.3356, 0.0], [0.030625, 0.011236, 0.0]),
+    ("bk7", [1.03961212, 0.231792344, 1.01046945], [0.00600069867, 0.0200179144, 103.560653]),
+    ("fused_silica", [0.6961663, 0.4079426, 0.8974794], [0.00467914826, 0.0135120631, 97.9340025]),
+];
+
+impl Dispersion {
+    // 波長 lambda [nm] での屈折率
+    pub fn refractive_index(&self, lambda: f64) -> f64 {
+        let l = lambda * 1e-3;
+        let l2 = l * l;
+        match *self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f64>();
+                n2.max(1.0).sqrt()
+            }
+        }
+    }
+}
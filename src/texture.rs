@@ -56,6 +56,15 @@ impl ImageTexture {
         gamma_to_linear(gamma)
     }
 
+    // 全ピクセルのリニアな色の平均
+    pub fn average(&self) -> Vector3 {
+        let mut sum = Vector3::zero();
+        for (_, _, pixel) in self.image.pixels() {
+            sum += gamma_to_linear(rgba_to_color(pixel));
+        }
+        sum / (self.image.width() * self.image.height()) as f64
+    }
+
     fn sample_nearest_screen(&self, x: u32, y: u32) -> Vector3 {
         let x = clamp_u32(x,0, self.image.width() - 1);
         let y = clamp_u32(self.image.height() - y - 1, 0, self.image.height() - 1);
@@ -105,6 +114,15 @@ impl Texture {
         Texture::from_color(Color::zero())
     }
 
+    // テクスチャ全体の平均の色
+    pub fn average(&self) -> Color {
+        if let Some(ref tex) = self.image_texture {
+            tex.average() * self.color
+        } else {
+            self.color
+        }
+    }
+
     pub fn sample(&self, uv: Vector2) -> Color {
         if let Some(ref tex) = self.image_texture {
             tex.sample_bilinear(uv.x, uv.y) * self.color
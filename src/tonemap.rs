@@ -1,17 +1,30 @@
+use std::str::FromStr;
 use vector::Vector3;
-use config;
+use config::RenderSettings;
 use color;
 
-#[allow(dead_code)]
+#[derive(Clone, Debug)]
 pub enum ToneMappingMode {
     None,
     Reinhard,
 }
 
-pub fn execute(color: &Vector3) -> Vector3 {
-    match config::TONE_MAPPING_MODE {
+impl FromStr for ToneMappingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ToneMappingMode, String> {
+        match s {
+            "none" => Ok(ToneMappingMode::None),
+            "reinhard" => Ok(ToneMappingMode::Reinhard),
+            _ => Err(format!("unknown tone mapping mode `{}`", s)),
+        }
+    }
+}
+
+pub fn execute(color: &Vector3, settings: &RenderSettings) -> Vector3 {
+    match settings.tone_mapping_mode {
         ToneMappingMode::None => none(color),
-        ToneMappingMode::Reinhard => reinhard(color, config::TONE_MAPPING_EXPOSURE, config::TONE_MAPPING_WHITE_POINT)
+        ToneMappingMode::Reinhard => reinhard(color, settings.tone_mapping_exposure, settings.tone_mapping_white_point)
     }
 }
 
@@ -97,6 +97,10 @@ impl Vector3 {
     pub fn powf(&self, v: f64) -> Vector3 {
         Vector3::new(self.x.powf(v), self.y.powf(v), self.z.powf(v))
     }
+
+    pub fn exp(&self) -> Vector3 {
+        Vector3::new(self.x.exp(), self.y.exp(), self.z.exp())
+    }
 }
 
 impl Add for Vector3 {
@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io::{BufReader, BufRead};
+
+use vector::Vector3;
+use bvh::Aabb;
+use loader::LoadError;
+
+// 直方体の範囲に値を並べた密な 3 次元グリッド（煙や雲の密度など）
+//
+// ファイルはテキスト形式で、# 以降はコメント
+//   NX NY NZ
+//   V V V ...
+// 値は x が最も速く変わり、次に y、z の順に NX * NY * NZ 個並べる（改行は自由）
+// 値はボクセルの中心の値とし、その間は線形補間する
+#[derive(Debug)]
+pub struct VoxelGrid {
+    pub aabb: Aabb,
+    resolution: [usize; 3],
+    values: Vec<f64>,
+    max_value: f64,
+}
+
+impl VoxelGrid {
+    pub fn load(path: &str, aabb: Aabb, scale: f64) -> Result<VoxelGrid, LoadError> {
+        let f = File::open(path).map_err(|e| LoadError::new(path, 0, e.to_string()))?;
+        let mut header = vec![];
+        let mut values = vec![];
+
+        for (i, line) in BufReader::new(f).lines().enumerate() {
+            let line_number = i + 1;
+            let l = line.map_err(|e| LoadError::new(path, line_number, e.to_string()))?;
+            let content = match l.find('#') {
+                Some(pos) => &l[..pos],
+                None => &l[..],
+            };
+
+            for token in content.split_whitespace() {
+                // 最初の 3 つの値が解像度
+                if header.len() < 3 {
+                    match token.parse::<usize>() {
+                        Ok(n) if n > 0 => header.push(n),
+                        _ => return Err(LoadError::new(path, line_number, format!("invalid resolution `{}`", token))),
+                    }
+                    continue;
+                }
+
+                match token.parse::<f64>() {
+                    Ok(v) if v >= 0.0 && v.is_finite() => values.push(v * scale),
+                    _ => return Err(LoadError::new(path, line_number, format!("invalid voxel value `{}`", token))),
+                }
+            }
+        }
+
+        if header.len() < 3 {
+            return Err(LoadError::new(path, 0, "missing resolution `NX NY NZ`".to_string()));
+        }
+        let resolution = [header[0], header[1], header[2]];
+        let count = resolution[0] * resolution[1] * resolution[2];
+        if values.len() != count {
+            return Err(LoadError::new(path, 0, format!("expected {} voxel values, found {}", count, values.len())));
+        }
+
+        let max_value = values.iter().cloned().fold(0.0, f64::max);
+        Ok(VoxelGrid { aabb, resolution, values, max_value })
+    }
+
+    // グリッド全体での最大値（delta tracking の majorant に使う）
+    pub fn max_value(&self) -> f64 {
+        self.max_value
+    }
+
+    // position での値を三重線形補間で求める。範囲外は 0
+    pub fn lookup(&self, position: &Vector3) -> f64 {
+        let min = &self.aabb.min;
+        let max = &self.aabb.max;
+        if position.x < min.x || position.y < min.y || position.z < min.z ||
+            position.x > max.x || position.y > max.y || position.z > max.z {
+            return 0.0;
+        }
+
+        let local = (*position - *min) / (*max - *min);
+        let (x0, x1, tx) = self.cell(local.x, 0);
+        let (y0, y1, ty) = self.cell(local.y, 1);
+        let (z0, z1, tz) = self.cell(local.z, 2);
+
+        let lerp = |a: f64, b: f64, t: f64| a + (b - a) * t;
+        let c00 = lerp(self.value(x0, y0, z0), self.value(x1, y0, z0), tx);
+        let c10 = lerp(self.value(x0, y1, z0), self.value(x1, y1, z0), tx);
+        let c01 = lerp(self.value(x0, y0, z1), self.value(x1, y0, z1), tx);
+        let c11 = lerp(self.value(x0, y1, z1), self.value(x1, y1, z1), tx);
+        lerp(lerp(c00, c10, ty), lerp(c01, c11, ty), tz)
+    }
+
+    // 0〜1 の座標 u を挟む 2 つのボクセルの添字と補間の重み
+    fn cell(&self, u: f64, axis: usize) -> (usize, usize, f64) {
+        let n = self.resolution[axis];
+        let x = (u * n as f64 - 0.5).max(0.0).min((n - 1) as f64);
+        let i = (x as usize).min(n - 1);
+        (i, (i + 1).min(n - 1), x - i as f64)
+    }
+
+    fn value(&self, x: usize, y: usize, z: usize) -> f64 {
+        self.values[(z * self.resolution[1] + y) * self.resolution[0] + x]
+    }
+}